@@ -317,6 +317,13 @@ export async function POST(request: NextRequest) {
           rustProcess.on('close', (code) => {
             if (code === 0) {
               resolve(null);
+            } else if (code === 2) {
+              // Partial success: some images were skipped but the markdown was written
+              controller.enqueue(encoder.encode(`data: ${JSON.stringify({
+                status: 'warning',
+                message: 'Some images produced no text and were skipped'
+              })}\n\n`));
+              resolve(null);
             } else {
               reject(new Error(`Process exited with code ${code}`));
             }
@@ -103,7 +103,15 @@ export async function POST(request: NextRequest) {
 
     // Execute Rust OCR processor
     console.log('Executing:', command);
-    await execAsync(command);
+    try {
+      await execAsync(command);
+    } catch (error) {
+      // Exit code 2 means some images were skipped but the markdown was still written
+      if ((error as { code?: number }).code !== 2) {
+        throw error;
+      }
+      console.warn('OCR completed with skipped images');
+    }
 
     // Convert markdown to PDF using Rust
     const coordinatesFlag = useCoordinates ? ' --use-coordinates' : '';
@@ -1,11 +1,10 @@
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose, Engine as _};
-use clap::{Parser, Subcommand};
-use lopdf::{Document, Object};
-use pdf_extract::extract_text;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 use printpdf::{IndirectFontRef, Line, Mm, PdfLayerReference, Point};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
@@ -14,15 +13,112 @@ use walkdir::WalkDir;
 #[command(name = "ocr_processor")]
 #[command(about = "OCR processor for images and PDFs", long_about = None)]
 struct Cli {
+    /// Config file with default option values
+    /// [default: ~/.config/ocr_processor/config.toml]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    encoding: OutputEncoding,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Defaults loaded from the config file. Precedence is CLI > config file > built-in defaults.
+///
+/// ```toml
+/// model = "deepseek-ocr"
+/// api_url = "http://127.0.0.1:11434/v1/chat/completions"
+/// timeout = 300
+/// temperature = 0.0
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    model: Option<String>,
+    custom_prompt: Option<String>,
+    api_url: Option<String>,
+    timeout: Option<u64>,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    grounding_token: Option<String>,
+}
+
+impl ConfigFile {
+    fn default_path() -> Option<PathBuf> {
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+        Some(PathBuf::from(home).join(".config").join("ocr_processor").join("config.toml"))
+    }
+
+    /// Load the file given by `--config`, or the default location if it exists.
+    fn load(explicit: Option<&Path>) -> Result<Self> {
+        let path = match explicit {
+            Some(path) => path.to_path_buf(),
+            None => match Self::default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file: {}", path.display()))
+    }
+
+    /// Fill in the configured values for the arguments of `command` that were not
+    /// given on the command line (or through the environment).
+    fn apply(self, command: &mut Commands, matches: &clap::ArgMatches) {
+        use clap::parser::ValueSource;
+        let from_cli = |id: &str| matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable));
+        let (model, custom_prompt, options) = command.configurable();
+        if let (Some(model), Some(value)) = (model, self.model.filter(|_| !from_cli("model"))) {
+            *model = value;
+        }
+        if let (Some(custom_prompt), Some(value)) = (custom_prompt, self.custom_prompt.filter(|_| !from_cli("custom_prompt"))) {
+            *custom_prompt = Some(value);
+        }
+        let Some(options) = options else {
+            return;
+        };
+        if let Some(api_url) = self.api_url.filter(|_| !from_cli("api_url")) {
+            options.api_url = Some(api_url);
+        }
+        if let Some(timeout) = self.timeout.filter(|_| !from_cli("timeout")) {
+            options.timeout = Some(timeout);
+        }
+        if let Some(temperature) = self.temperature.filter(|_| !from_cli("temperature")) {
+            options.temperature = Some(temperature);
+        }
+        if let Some(top_p) = self.top_p.filter(|_| !from_cli("top_p")) {
+            options.top_p = Some(top_p);
+        }
+        if let Some(max_tokens) = self.max_tokens.filter(|_| !from_cli("max_tokens")) {
+            options.max_tokens = max_tokens;
+        }
+        if let Some(token) = self.grounding_token.filter(|_| !from_cli("grounding_token")) {
+            options.grounding_token = token;
+        }
+    }
+}
+
+/// Parse the command line, then fill in config-file values for the options that
+/// were not given explicitly, so that explicit flags still win.
+fn parse_cli() -> Result<Cli> {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let config = ConfigFile::load(cli.config.as_deref())?;
+    if let Some((_, sub_matches)) = matches.subcommand() {
+        config.apply(&mut cli.command, sub_matches);
+    }
+    Ok(cli)
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Process a single image file
     ProcessImage {
-        /// Path to the image file
+        /// Path to the image file, or an http(s) URL to download it from
         #[arg(short, long)]
         input: PathBuf,
 
@@ -30,7 +126,20 @@ enum Commands {
         #[arg(short, long)]
         output: Option<PathBuf>,
 
-        /// OCR model to use
+        /// Append to the output file as a new page instead of overwriting it
+        #[arg(long, requires = "output")]
+        append: bool,
+
+        /// Start the markdown with YAML front matter (title, source, date, model, language)
+        /// for static site generators; not repeated when appending to an existing file
+        #[arg(long)]
+        front_matter: bool,
+
+        /// Also render the result to this PDF (coordinate layout with --use-coordinates)
+        #[arg(long)]
+        pdf_output: Option<PathBuf>,
+
+        /// OCR model to use; a comma-separated list is tried in order until one succeeds
         #[arg(short, long, default_value = "deepseek-ocr")]
         model: String,
 
@@ -45,24 +154,55 @@ enum Commands {
         /// Disable grounding mode for NexaAI models (use free OCR instead of structured document OCR)
         #[arg(long)]
         disable_grounding_mode: bool,
+
+        #[command(flatten)]
+        ocr_options: Box<OcrOptions>,
+
+        #[command(flatten)]
+        pdf_options: Box<PdfOptions>,
     },
 
     /// Process multiple images in a directory
     ProcessDir {
-        /// Directory containing images
-        #[arg(short, long)]
-        input: PathBuf,
+        /// Directory containing images, a glob pattern such as 'scans/page-*.png', or a .zip of images
+        #[arg(short, long, required_unless_present = "file_list", conflicts_with = "file_list")]
+        input: Option<PathBuf>,
+
+        /// Text file listing image paths one per line, processed in that order ('#' starts a comment)
+        #[arg(long)]
+        file_list: Option<PathBuf>,
 
         /// Output markdown file path
-        #[arg(short, long)]
-        output: PathBuf,
+        #[arg(short, long, required_unless_present = "output_dir", conflicts_with = "output_dir")]
+        output: Option<PathBuf>,
+
+        /// Write one markdown file per image into this directory (scan1.png -> scan1.md;
+        /// images sharing a stem keep their extension: scan1.png.md, scan1.jpg.md)
+        #[arg(long, conflicts_with = "join_images")]
+        output_dir: Option<PathBuf>,
+
+        /// Also render the combined result to this PDF (coordinate layout with --use-coordinates)
+        #[arg(long, conflicts_with = "output_dir")]
+        pdf_output: Option<PathBuf>,
 
-        /// OCR model to use
+        /// Draw each page into the PDF as soon as its OCR finishes instead of converting
+        /// the whole batch at the end; the file is written when the batch completes.
+        /// Not available with layouts that span pages (--index-page, --two-up)
+        #[arg(long, requires = "pdf_output", conflicts_with_all = ["join_images", "dedup_running", "index_page", "two_up"])]
+        stream_pdf: bool,
+
+        /// Start each markdown file with YAML front matter (title, source, date, model,
+        /// language) for static site generators
+        #[arg(long)]
+        front_matter: bool,
+
+        /// OCR model to use; a comma-separated list is tried in order until one succeeds
         #[arg(short, long, default_value = "deepseek-ocr")]
         model: String,
 
         /// Join all images into one before OCR (experimental)
-        #[arg(long)]
+        // The joined image comes back as one page: no running lines to compare, no per-page report
+        #[arg(long, conflicts_with_all = ["split_spreads", "dedup_running", "report"])]
         join_images: bool,
 
         /// Custom prompt for Ollama models (optional)
@@ -76,6 +216,18 @@ enum Commands {
         /// Use coordinates in OCR output
         #[arg(long)]
         use_coordinates: bool,
+
+        #[command(flatten)]
+        batch_options: Box<BatchOptions>,
+
+        #[command(flatten)]
+        join_options: JoinOptions,
+
+        #[command(flatten)]
+        ocr_options: Box<OcrOptions>,
+
+        #[command(flatten)]
+        pdf_options: Box<PdfOptions>,
     },
     /// Extract images from PDF and process
     ProcessPdf {
@@ -90,9 +242,22 @@ enum Commands {
         /// Temporary directory for extracted images
         #[arg(short, long, default_value = "temp_images")]
         temp_dir: PathBuf,
-        /// Use native rust extraction (fallback when pdftoppm is not available)
+        /// Use native rust extraction (fallback when pdftoppm is not available or renders no pages)
         #[arg(long)]
         use_native: bool,
+
+        /// Image format pdftoppm writes the pages in; jpeg is much smaller for photographic scans
+        #[arg(long, value_enum, default_value_t = TempFormat::Png)]
+        temp_format: TempFormat,
+
+        /// Resolution pdftoppm renders the pages at; page sizes are recorded in the output,
+        /// so pixel boxes still map onto the right place in coordinate PDFs
+        #[arg(long, default_value_t = PDF_EXTRACT_DPI, value_parser = clap::value_parser!(u32).range(1..))]
+        dpi: u32,
+
+        /// OCR only every Nth page for a quick overview; page markers keep the real page numbers
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+        sample: u64,
     },
     /// Convert markdown to PDF
     MarkdownToPdf {
@@ -107,6 +272,29 @@ enum Commands {
         /// Use coordinate-based formatting (preserves original layout)
         #[arg(long)]
         use_coordinates: bool,
+
+        #[command(flatten)]
+        pdf_options: Box<PdfOptions>,
+    },
+    /// Convert markdown to an editable Word document (.docx)
+    MarkdownToDocx {
+        /// Input markdown file
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output DOCX file
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Convert markdown to an EPUB e-book, one chapter per h1/h2 heading (or per page)
+    MarkdownToEpub {
+        /// Input markdown file
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output EPUB file
+        #[arg(short, long)]
+        output: PathBuf,
     },
     /// Process markdown (clean and display)
     ProcessMarkdown {
@@ -121,6 +309,43 @@ enum Commands {
         /// Remove OCR coordinates and internal markers for clean output
         #[arg(long)]
         clean: bool,
+
+        /// Deprecated: <|det|> coordinates are kept by default; accepted with a warning
+        #[arg(long, hide = true, conflicts_with = "clean")]
+        keep_coordinates: bool,
+
+        /// How much model annotation to strip: none, light (control tokens, think blocks,
+        /// page markers), standard (light + <|det|> coordinates, keeps <|ref|> labels) or
+        /// aggressive (every OCR tag, same as --clean)
+        #[arg(long, value_enum, conflicts_with_all = ["clean", "keep_coordinates"])]
+        clean_level: Option<CleanLevel>,
+
+        /// Rejoin words hyphenated across line breaks (infor-/mation -> information)
+        #[arg(long)]
+        dehyphenate: bool,
+
+        /// Join wrapped lines into paragraphs (breaks only at blank lines, headers and list items)
+        #[arg(long)]
+        reflow: bool,
+
+        /// Extract only the tables (HTML or pipe tables) as CSV; with --output, several
+        /// tables are written as numbered files (report_1.csv, report_2.csv, ...)
+        #[arg(long, conflicts_with_all = ["clean", "keep_coordinates", "clean_level", "dehyphenate", "reflow"])]
+        only_tables: bool,
+
+        /// Output format; csv and tsv export only the tables, like --only-tables, and
+        /// blocks-json the coordinate blocks for custom renderers.
+        /// Inferred from the --output extension when omitted (markdown on stdout)
+        #[arg(long, value_enum)]
+        format: Option<MarkdownFormat>,
+
+        /// Drop table columns that are empty in every row (text and pdf output;
+        /// default on, pass "=false" to keep them)
+        #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+        trim_whitespace_columns: Option<bool>,
+
+        #[command(flatten)]
+        running: RunningLineOptions,
     },
     /// Split and reorder PDF pages
     SplitPdf {
@@ -137,446 +362,3825 @@ enum Commands {
         #[arg(short, long)]
         pages: String,
     },
+    /// Compare the text of two OCR outputs
+    DiffMarkdown {
+        /// Original markdown file
+        old: PathBuf,
+
+        /// Updated markdown file
+        new: PathBuf,
+
+        /// Compare word by word instead of line by line
+        #[arg(long)]
+        words: bool,
+    },
+    /// Save a contact sheet: every page as a thumbnail in one grid image
+    Contacts {
+        /// Directory of images, glob pattern, .zip of images or a PDF
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output PNG file
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Thumbnails per row
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+        columns: u32,
+
+        /// Longest side of each thumbnail in pixels
+        #[arg(long, default_value_t = 200, value_parser = clap::value_parser!(u32).range(16..))]
+        thumb_size: u32,
+
+        /// Temporary directory for pages rendered from a PDF
+        #[arg(short, long, default_value = "temp_images")]
+        temp_dir: PathBuf,
+    },
+    /// Smoke-test the OCR backend on a bundled sample image
+    Selftest {
+        /// OCR model to use; a comma-separated list is tried in order until one succeeds
+        #[arg(short, long, default_value = "deepseek-ocr")]
+        model: String,
+
+        /// Disable grounding mode for NexaAI models (use free OCR instead of structured document OCR)
+        #[arg(long)]
+        disable_grounding_mode: bool,
+
+        #[command(flatten)]
+        ocr_options: Box<OcrOptions>,
+    },
+
+    /// Run an HTTP server exposing `POST /ocr` (raw image body -> cleaned markdown)
+    Serve {
+        /// Address to bind to
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// Port to listen on
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+
+        /// OCR model to use; a comma-separated list is tried in order until one succeeds
+        #[arg(short, long, default_value = "deepseek-ocr")]
+        model: String,
+
+        /// Custom prompt for Ollama models (optional)
+        #[arg(long)]
+        custom_prompt: Option<String>,
+
+        /// Use coordinates in OCR output
+        #[arg(long)]
+        use_coordinates: bool,
+
+        /// Disable grounding mode for NexaAI models (use free OCR instead of structured document OCR)
+        #[arg(long)]
+        disable_grounding_mode: bool,
+
+        #[command(flatten)]
+        ocr_options: Box<OcrOptions>,
+    },
 }
 
-#[derive(Serialize)]
-struct OcrRequest {
-    model: String,
-    messages: Vec<Message>,
-    max_tokens: u32,
-    stream: bool,
+impl Commands {
+    /// The model, custom prompt and request options of the subcommand, where it has
+    /// them, for the config file to fill in.
+    fn configurable(&mut self) -> (Option<&mut String>, Option<&mut Option<String>>, Option<&mut OcrOptions>) {
+        match self {
+            Commands::ProcessImage { model, custom_prompt, ocr_options, .. }
+            | Commands::ProcessDir { model, custom_prompt, ocr_options, .. }
+            | Commands::Serve { model, custom_prompt, ocr_options, .. } => (Some(model), Some(custom_prompt), Some(ocr_options)),
+            Commands::Selftest { model, ocr_options, .. } => (Some(model), None, Some(ocr_options)),
+            _ => (None, None, None),
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct Message {
-    role: String,
-    content: Vec<Content>,
+/// Graduated cleaning for `process-markdown --clean-level`; each level includes the previous one.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum CleanLevel {
+    /// Leave the text untouched
+    None,
+    /// Control tokens (<|grounding|>, <|OCR|>), think blocks, page/image markers, extra blank lines
+    Light,
+    /// Also <|det|> coordinate tags; <|ref|> labels survive
+    Standard,
+    /// Also <|ref|> blocks and any remaining <|...|> tag
+    Aggressive,
 }
 
-#[derive(Serialize)]
-#[serde(tag = "type")]
-enum Content {
-    #[serde(rename = "text")]
-    Text { text: String },
-    #[serde(rename = "image_url")]
-    ImageUrl { image_url: ImageUrl },
+/// Output format of `process-markdown`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum MarkdownFormat {
+    Markdown,
+    /// Plain text with tables drawn as ASCII grids (only via --format; .txt stays markdown)
+    Text,
+    Csv,
+    Tsv,
+    Pdf,
+    Docx,
+    Epub,
+    /// The cleaned text plus every <|det|> box with its geometry, as JSON
+    BlocksJson,
 }
 
-#[derive(Serialize)]
-struct ImageUrl {
-    url: String,
+impl MarkdownFormat {
+    /// Format implied by an output file extension.
+    fn from_extension(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+        Ok(match extension.as_str() {
+            "md" | "markdown" | "txt" => MarkdownFormat::Markdown,
+            "csv" => MarkdownFormat::Csv,
+            "tsv" => MarkdownFormat::Tsv,
+            "pdf" => MarkdownFormat::Pdf,
+            "docx" => MarkdownFormat::Docx,
+            "epub" => MarkdownFormat::Epub,
+            "json" => MarkdownFormat::BlocksJson,
+            _ => anyhow::bail!(
+                "Cannot infer the output format from {}; pass --format (markdown, text, csv, tsv, pdf, docx, epub, blocks-json)",
+                path.display()
+            ),
+        })
+    }
+
+    /// Explicit --format wins; otherwise the output extension decides.
+    fn resolve(format: Option<Self>, output: Option<&Path>) -> Result<Self> {
+        match (format, output) {
+            (Some(format), _) => Ok(format),
+            (None, Some(path)) => Self::from_extension(path),
+            (None, None) => Ok(MarkdownFormat::Markdown),
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct OcrResponse {
-    choices: Vec<Choice>,
+/// Page image format for `process-pdf` extraction.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum TempFormat {
+    Png,
+    Jpeg,
+    Tiff,
 }
 
-#[derive(Deserialize)]
-struct Choice {
-    message: ResponseMessage,
+impl TempFormat {
+    fn pdftoppm_flag(self) -> &'static str {
+        match self {
+            TempFormat::Png => "-png",
+            TempFormat::Jpeg => "-jpeg",
+            TempFormat::Tiff => "-tiff",
+        }
+    }
+
+    /// Extension pdftoppm gives the files it writes.
+    fn extension(self) -> &'static str {
+        match self {
+            TempFormat::Png => "png",
+            TempFormat::Jpeg => "jpg",
+            TempFormat::Tiff => "tif",
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct ResponseMessage {
-    content: String,
+/// How `--no-wrap` handles lines wider than the page.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum NoWrap {
+    Clip,
+    Shrink,
 }
 
-const NEXA_API_URL: &str = "http://127.0.0.1:18181/v1/chat/completions";
-const OLLAMA_API_URL: &str = "http://127.0.0.1:11434/v1/chat/completions";
+/// What `--min-quality` does with images below the quality thresholds.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum QualityAction {
+    /// Report the image and OCR it anyway
+    Warn,
+    /// Report the image and leave it out (empty page)
+    Skip,
+}
 
-// Determine which API to use based on model name
-fn get_api_url(model: &str) -> &'static str {
-    // Check if it's an Ollama model (doesn't contain "NexaAI" or "GGUF")
-    if model.contains("NexaAI") || model.contains("GGUF") {
-        NEXA_API_URL
-    } else {
-        OLLAMA_API_URL
+/// Units of the `<|det|>` boxes in coordinate mode (`--box-units`).
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum BoxUnits {
+    /// Pixels when a box reaches past the 0-999 grid, grid units otherwise
+    #[default]
+    Auto,
+    /// DeepSeek-OCR's 0-999 grid, whatever the image size
+    Grid,
+    /// Pixels of the source image
+    Pixels,
+    /// Fractions of the image size, 0-1 (some fine-tuned models)
+    Fraction,
+}
+
+/// Line ending used for written markdown files.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+/// How markdown files are encoded on disk. Applies to every command that writes markdown.
+#[derive(Args, Clone, Copy, Debug, Default)]
+struct OutputEncoding {
+    /// Start written markdown files with a UTF-8 byte order mark
+    #[arg(long, global = true)]
+    bom: bool,
+
+    /// Line endings for written markdown files
+    #[arg(long, value_enum, default_value_t = LineEnding::Lf, global = true)]
+    line_endings: LineEnding,
+}
+
+impl OutputEncoding {
+    /// Convert LF-normalized text to the requested line endings. Never adds a BOM.
+    fn convert_line_endings(&self, text: &str) -> String {
+        let text = text.replace("\r\n", "\n");
+        match self.line_endings {
+            LineEnding::Lf => text,
+            LineEnding::Crlf => text.replace('\n', "\r\n"),
+        }
+    }
+
+    /// Write `text` as a complete markdown file.
+    fn write(&self, path: &Path, text: &str) -> Result<()> {
+        // Drop a BOM carried over from the input so it is never doubled
+        let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+        let mut bytes = Vec::with_capacity(text.len() + 3);
+        if self.bom {
+            bytes.extend_from_slice("\u{feff}".as_bytes());
+        }
+        bytes.extend_from_slice(self.convert_line_endings(text).as_bytes());
+        fs::write(path, bytes).with_context(|| format!("Failed to write {}", path.display()))
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// Request and post-processing options shared by the OCR commands.
+#[derive(Args, Clone, Debug)]
+struct OcrOptions {
+    /// Maximum number of tokens the model may generate per request
+    #[arg(long, default_value_t = DEFAULT_MAX_TOKENS)]
+    max_tokens: u32,
 
-    match &cli.command {
-        Commands::ProcessImage { input, output, model, custom_prompt, use_coordinates, disable_grounding_mode } => {
-            println!("DEBUG: ProcessImage called. disable_grounding_mode={}", disable_grounding_mode);
-            let use_grounding_mode = !disable_grounding_mode;
-            let markdown = process_image(input, model, custom_prompt.as_deref(), *use_coordinates, use_grounding_mode).await?;
+    /// Sampling temperature (0 gives repeatable extraction)
+    #[arg(long)]
+    temperature: Option<f32>,
+
+    /// Nucleus sampling cutoff
+    #[arg(long)]
+    top_p: Option<f32>,
+
+    /// OpenAI-compatible chat completions URL (default: chosen from the model name)
+    #[arg(long)]
+    api_url: Option<String>,
+
+    /// Request timeout in seconds
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Never download anything: URL inputs are rejected
+    #[arg(long)]
+    offline: bool,
+
+    /// API key for hosted endpoints, sent as "Authorization: Bearer <key>"
+    #[arg(long)]
+    api_key: Option<String>,
+
+    /// Extra HTTP header for OCR requests as "Name: Value" (repeatable)
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// Largest base64 image payload to send; bigger images are downscaled and
+    /// recompressed as JPEG until they fit
+    #[arg(long)]
+    max_image_bytes: Option<usize>,
+
+    /// How often to retry a request rejected with HTTP 429 (rate limited)
+    #[arg(long, default_value_t = DEFAULT_RATE_LIMIT_RETRIES)]
+    rate_limit_retries: u32,
+
+    /// Send at most this many OCR requests per minute (tiles, retries, fallback models and
+    /// --refine passes included), waiting as needed, to stay under the rate limits of
+    /// shared or metered backends
+    #[arg(long, value_name = "REQUESTS_PER_MINUTE", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+
+    /// Send the OCR result back for a second pass that corrects obvious recognition
+    /// mistakes (one extra request per image; API models only)
+    #[arg(long)]
+    refine: bool,
+
+    /// Fail on API responses without a completion (empty or missing choices) instead
+    /// of treating them as an empty page
+    #[arg(long)]
+    strict_json: bool,
+
+    /// Rejoin words hyphenated across line breaks (infor-/mation -> information)
+    #[arg(long)]
+    dehyphenate: bool,
+
+    /// Fix single-character OCR errors (e.g. "rn" read for "m") against this word list,
+    /// one word per line such as /usr/share/dict/words; only unambiguous corrections of
+    /// lowercase words are applied
+    #[arg(long, value_name = "DICTIONARY")]
+    spellfix: Option<PathBuf>,
+
+    /// Extract the fields described by this JSON schema file instead of markdown; the
+    /// reply is validated against the schema and retried when it doesn't match
+    #[arg(long, value_name = "FILE", value_parser = parse_json_schema, conflicts_with_all = ["refine", "tile_height"])]
+    schema: Option<serde_json::Value>,
+
+    /// OCR only this pixel rectangle of the image, given as "x,y,width,height"
+    #[arg(long, value_name = "X,Y,W,H", value_parser = parse_crop_region)]
+    crop_region: Option<[u32; 4]>,
+
+    /// Trim uniform scan borders from the image before sending it
+    #[arg(long)]
+    auto_crop: bool,
+
+    /// Border color to trim as hex (e.g. "#ffffff"); sampled from the corners if omitted
+    #[arg(long, value_parser = parse_hex_color, requires = "auto_crop")]
+    crop_background: Option<[u8; 3]>,
+
+    /// Maximum per-channel difference from the border color still treated as border
+    #[arg(long, default_value_t = DEFAULT_CROP_TOLERANCE, requires = "auto_crop")]
+    crop_tolerance: u8,
+
+    /// Convert the image to black and white with a local (Sauvola) threshold; helps faint scans
+    #[arg(long)]
+    binarize: bool,
+
+    /// Convert the image to grayscale, then to black and white with Floyd-Steinberg
+    /// dithering (smaller fax-style images); an alternative to --binarize, which
+    /// thresholds instead, so only one of them can be used
+    #[arg(long, conflicts_with = "binarize")]
+    dither: bool,
+
+    /// Retry a poor OCR result with the image rotated 90/180/270 degrees and keep the
+    /// best one (costs up to three extra requests per image)
+    #[arg(long)]
+    auto_orient_ocr: bool,
+
+    /// Clockwise rotation applied before OCR (set internally by --auto-orient-ocr)
+    #[arg(skip)]
+    rotation: u16,
+
+    /// Split images taller than this many pixels into horizontal tiles that are OCR'd
+    /// one by one and joined in order
+    #[arg(long)]
+    tile_height: Option<u32>,
+
+    /// Pixels shared by consecutive tiles so no line is cut in half; text repeated in
+    /// the overlap is dropped when the tiles are joined
+    #[arg(long, default_value_t = DEFAULT_TILE_OVERLAP, requires = "tile_height")]
+    tile_overlap: u32,
+
+    /// Rows (top, height) of the tile being OCR'd (set internally by --tile-height)
+    #[arg(skip)]
+    tile: Option<(u32, u32)>,
+
+    /// Frame of an animated GIF or page of a multi-page TIFF being OCR'd (set internally)
+    #[arg(skip)]
+    frame: Option<usize>,
+
+    /// OCR the left and right halves of a landscape image (two facing pages of a book
+    /// scan) as separate pages, split at the gutter found between them
+    #[arg(long)]
+    split_spreads: bool,
+
+    /// Split spreads at this percentage of the width instead of detecting the gutter
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent, requires = "split_spreads")]
+    spread_split: Option<f32>,
+
+    /// Columns (left, width) of the spread half being read
+    #[arg(skip)]
+    spread_half: Option<(u32, u32)>,
+
+    /// Check the resolution, sharpness and contrast of what is OCR'd (each frame, spread
+    /// half, crop region or tile) before sending it and report images unlikely to OCR
+    /// well; "skip" also leaves them out instead of sending them
+    #[arg(long, value_enum)]
+    min_quality: Option<QualityAction>,
+
+    /// Shortest side in pixels below which --min-quality calls an image too small
+    #[arg(long, value_name = "PIXELS", default_value_t = DEFAULT_MIN_RESOLUTION, requires = "min_quality")]
+    min_resolution: u32,
+
+    /// Laplacian variance below which --min-quality calls an image blurry
+    #[arg(long, default_value_t = DEFAULT_MIN_SHARPNESS, requires = "min_quality")]
+    min_sharpness: f64,
+
+    /// Spread between dark and light gray levels (0-255) below which --min-quality
+    /// calls an image low-contrast
+    #[arg(long, default_value_t = DEFAULT_MIN_CONTRAST, requires = "min_quality")]
+    min_contrast: u8,
+
+    /// Grounding token inserted into structured-OCR prompts; pass "" for models that don't use one
+    #[arg(long, default_value = DEFAULT_GROUNDING_TOKEN)]
+    grounding_token: String,
+
+    /// Shared client reused across requests (set by long-running commands like `serve`)
+    #[arg(skip)]
+    client: Option<reqwest::Client>,
+
+    /// When the next request may go out under `--rate-limit`; shared by clones of the options
+    #[arg(skip)]
+    next_request: std::sync::Arc<std::sync::Mutex<Option<std::time::Instant>>>,
+}
 
-            if let Some(output_path) = output {
-                fs::write(output_path, &markdown)?;
-                println!("✓ Markdown saved to: {}", output_path.display());
-            } else {
-                println!("{}", markdown);
+impl Default for OcrOptions {
+    fn default() -> Self {
+        Self {
+            max_tokens: DEFAULT_MAX_TOKENS,
+            temperature: None,
+            top_p: None,
+            api_url: None,
+            timeout: None,
+            offline: false,
+            api_key: None,
+            headers: Vec::new(),
+            max_image_bytes: None,
+            rate_limit_retries: DEFAULT_RATE_LIMIT_RETRIES,
+            rate_limit: None,
+            refine: false,
+            strict_json: false,
+            dehyphenate: false,
+            spellfix: None,
+            schema: None,
+            crop_region: None,
+            auto_crop: false,
+            crop_background: None,
+            crop_tolerance: DEFAULT_CROP_TOLERANCE,
+            binarize: false,
+            dither: false,
+            auto_orient_ocr: false,
+            rotation: 0,
+            tile_height: None,
+            tile_overlap: DEFAULT_TILE_OVERLAP,
+            tile: None,
+            frame: None,
+            split_spreads: false,
+            spread_split: None,
+            spread_half: None,
+            min_quality: None,
+            min_resolution: DEFAULT_MIN_RESOLUTION,
+            min_sharpness: DEFAULT_MIN_SHARPNESS,
+            min_contrast: DEFAULT_MIN_CONTRAST,
+            grounding_token: DEFAULT_GROUNDING_TOKEN.to_string(),
+            client: None,
+            next_request: Default::default(),
+        }
+    }
+}
+
+impl OcrOptions {
+    fn api_url<'a>(&'a self, model: &str) -> &'a str {
+        self.api_url.as_deref().unwrap_or_else(|| get_api_url(model))
+    }
+
+    fn needs_preprocessing(&self) -> bool {
+        self.crop_region.is_some() || self.auto_crop || self.binarize || self.dither || self.rotation != 0 || self.tile.is_some() || self.frame.is_some() || self.spread_half.is_some()
+    }
+
+    /// Optional text passes applied to the cleaned OCR result.
+    fn postprocess(&self, markdown: String) -> Result<String> {
+        let mut markdown = if self.dehyphenate {
+            dehyphenate(&markdown)
+        } else {
+            markdown
+        };
+        if let Some(path) = &self.spellfix {
+            let (fixed, corrections) = spellfix(&markdown, load_spell_dictionary(path)?);
+            if corrections > 0 {
+                println!("📖 Spellfix: corrected {} word(s)", corrections);
             }
+            markdown = fixed;
         }
-        Commands::ProcessDir { input, output, model, join_images, custom_prompt, disable_grounding_mode, use_coordinates } => {
-            let use_grounding_mode = !disable_grounding_mode;
-            let markdown = if *join_images {
-                process_directory_joined(input, model, custom_prompt.as_deref(), use_grounding_mode, *use_coordinates).await?
-            } else {
-                process_directory(input, model, custom_prompt.as_deref(), use_grounding_mode, *use_coordinates).await?
-            };
-            fs::write(output, &markdown)?;
-            println!("✓ Markdown saved to: {}", output.display());
+        Ok(markdown)
+    }
+
+    fn http_client(&self) -> Result<reqwest::Client, OcrError> {
+        if let Some(client) = &self.client {
+            return Ok(client.clone());
         }
-        Commands::ProcessPdf {
-            input,
-            output,
-            temp_dir,
-            use_native,
-        } => {
-            let markdown = process_pdf(input, temp_dir, *use_native).await?;
-            fs::write(output, &markdown)?;
-            println!("✓ Markdown saved to: {}", output.display());
+        let mut builder = reqwest::Client::builder();
+        if let Some(seconds) = self.timeout {
+            builder = builder.timeout(std::time::Duration::from_secs(seconds));
         }
-        Commands::MarkdownToPdf {
-            input,
-            output,
-            use_coordinates,
-        } => {
-            println!(
-                "👉 markdown-to-pdf: input={} output={} use_coordinates={}",
-                input.display(),
-                output.display(),
-                use_coordinates
-            );
-            let markdown = fs::read_to_string(input)?;
-            convert_markdown_to_pdf(&markdown, output, *use_coordinates)?;
-            println!("✓ PDF saved to: {}", output.display());
+        builder.build().map_err(OcrError::Client)
+    }
+
+    /// Wait for this request's slot under `--rate-limit`. Slots are reserved under the
+    /// lock and slept on outside it, so concurrent requests queue up one interval apart.
+    async fn wait_for_rate_limit(&self) {
+        let Some(per_minute) = self.rate_limit else {
+            return;
+        };
+        let interval = std::time::Duration::from_secs_f64(60.0 / per_minute as f64);
+        let now = std::time::Instant::now();
+        let slot = {
+            let mut next_request = self.next_request.lock().unwrap_or_else(|e| e.into_inner());
+            let slot = next_request.map_or(now, |next| next.max(now));
+            *next_request = Some(slot + interval);
+            slot
+        };
+        let wait = slot - now;
+        if !wait.is_zero() {
+            println!("⏱ Rate limit: waiting {:.1}s before the next request", wait.as_secs_f64());
+            tokio::time::sleep(wait).await;
         }
-        Commands::ProcessMarkdown { input, output, clean } => {
-            let markdown = fs::read_to_string(input)?;
-            let processed = if *clean {
-                clean_markdown_for_plain(&markdown)
-            } else {
-                markdown
-            };
-            
-            if let Some(output_path) = output {
-                fs::write(output_path, &processed)?;
-                println!("✓ Processed markdown saved to: {}", output_path.display());
-            } else {
-                println!("{}", processed);
+    }
+
+    /// POST an OCR request, waiting and retrying while the server answers 429.
+    /// The wait comes from `Retry-After` (in seconds) when present, otherwise it doubles per attempt.
+    async fn send_ocr_request(&self, api_url: &str, request: &OcrRequest) -> Result<reqwest::Response, OcrError> {
+        let client = self.http_client()?;
+        let mut attempt = 0;
+        loop {
+            self.wait_for_rate_limit().await;
+            let mut builder = client.post(api_url).json(request);
+            if let Some(key) = &self.api_key {
+                builder = builder.bearer_auth(key);
             }
-        }
-        Commands::SplitPdf { input, output, pages } => {
-            println!("Splitting PDF: {} -> {}", input.display(), output.display());
-            println!("Page order: {}", pages);
-            
-            split_pdf(input, output, pages)?;
-            println!("✓ PDF split successfully: {}", output.display());
+            for (name, value) in &self.headers {
+                builder = builder.header(name, value);
+            }
+            let response = builder.send().await.map_err(|source| OcrError::Network {
+                url: api_url.to_string(),
+                source,
+            })?;
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS || attempt >= self.rate_limit_retries {
+                return Ok(response);
+            }
+            attempt += 1;
+            let wait = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(1 << attempt.min(6))
+                .min(MAX_RETRY_AFTER_SECS);
+            println!("⏳ Rate limited (429), retrying in {}s (attempt {}/{})", wait, attempt, self.rate_limit_retries);
+            tokio::time::sleep(std::time::Duration::from_secs(wait)).await;
         }
     }
 
-    Ok(())
+    /// Read a successful OCR response and return the completion text. Parse failures
+    /// quote the start of the body so a misconfigured backend's reply is visible.
+    async fn read_ocr_response(&self, response: reqwest::Response) -> Result<String, OcrError> {
+        let url = response.url().to_string();
+        let body = response.text().await.map_err(|source| OcrError::Network { url, source })?;
+        let snippet = || {
+            let mut snippet: String = body.chars().take(RESPONSE_SNIPPET_CHARS).collect();
+            if body.chars().count() > RESPONSE_SNIPPET_CHARS {
+                snippet.push('…');
+            }
+            snippet
+        };
+        let ocr_response: OcrResponse = serde_json::from_str(&body).map_err(|e| OcrError::Parse {
+            message: e.to_string(),
+            snippet: snippet(),
+        })?;
+        if let Some(usage) = &ocr_response.usage {
+            let tokens = usage.total_tokens.unwrap_or(usage.prompt_tokens + usage.completion_tokens);
+            TOKENS_USED.fetch_add(tokens, std::sync::atomic::Ordering::Relaxed);
+        }
+        match ocr_response.choices.into_iter().next() {
+            Some(choice) => Ok(choice.message.content),
+            None if self.strict_json => Err(OcrError::EmptyResponse { snippet: snippet() }),
+            None => Ok(String::new()),
+        }
+    }
 }
 
-fn split_pdf(input: &Path, output: &Path, pages_str: &str) -> Result<()> {
-    use std::process::Command;
-    
-    // Parse page numbers
-    let page_numbers: Vec<u32> = pages_str
-        .split(',')
-        .map(|s| s.trim())
+/// Failures of the OCR pipeline that callers may want to tell apart. `ocr_image`,
+/// `process_image` and `process_directory` return them directly; anything else on the
+/// way (files, context added by helpers) is carried as `Other`, see `OcrError::root`.
+#[derive(Debug, thiserror::Error)]
+pub enum OcrError {
+    #[error("failed to build HTTP client: {0}")]
+    Client(#[source] reqwest::Error),
+
+    /// Connection, timeout or transfer failure
+    #[error("request to {url} failed: {source}")]
+    Network {
+        url: String,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    /// The server answered with a non-success status
+    #[error("OCR API error: {status} - {body}")]
+    Api { status: reqwest::StatusCode, body: String },
+
+    /// The body is not a chat-completions response
+    #[error("unexpected OCR response ({message}): {snippet}")]
+    Parse { message: String, snippet: String },
+
+    /// A well-formed response without a completion (`--strict-json`)
+    #[error("OCR response has no choices: {snippet}")]
+    EmptyResponse { snippet: String },
+
+    #[error("invalid image: {0}")]
+    InvalidImage(#[from] image::ImageError),
+
+    /// `--crop-region` reaches past the image edges
+    #[error("crop region {x},{y},{w},{h} lies outside the {width}x{height} image")]
+    RegionOutOfBounds { x: u32, y: u32, w: u32, h: u32, width: u32, height: u32 },
+
+    #[error("refusing to download {url} because --offline is set")]
+    Offline { url: String },
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl OcrError {
+    /// The pipeline failure behind any context added on the way up, or `self` when
+    /// there is none.
+    pub fn root(&self) -> &OcrError {
+        match self {
+            OcrError::Other(error) => error
+                .chain()
+                .filter_map(|e| e.downcast_ref::<OcrError>())
+                .map(OcrError::root)
+                .find(|e| !matches!(e, OcrError::Other(_)))
+                .unwrap_or(self),
+            _ => self,
+        }
+    }
+}
+
+/// Pass a successful response through; turn any other status into `OcrError::Api`.
+async fn ensure_success(response: reqwest::Response) -> Result<reqwest::Response, OcrError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(OcrError::Api { status, body })
+}
+
+const DEFAULT_MAX_TOKENS: u32 = 16384;
+const DEFAULT_RATE_LIMIT_RETRIES: u32 = 5;
+const MAX_RETRY_AFTER_SECS: u64 = 120;
+const RESPONSE_SNIPPET_CHARS: usize = 500;
+const DEFAULT_CROP_TOLERANCE: u8 = 40;
+const DEFAULT_TILE_OVERLAP: u32 = 64;
+const DEFAULT_MIN_RESOLUTION: u32 = 600;
+const DEFAULT_MIN_SHARPNESS: f64 = 100.0;
+const DEFAULT_MIN_CONTRAST: u8 = 80;
+const DEFAULT_GROUNDING_TOKEN: &str = "<|grounding|>";
+
+/// Parse a `--header` value of the form "Name: Value".
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    let (name, header_value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected \"Name: Value\", got {:?}", value))?;
+    let name = name.trim();
+    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name {:?}", name))?;
+    let header_value = header_value.trim();
+    reqwest::header::HeaderValue::from_str(header_value)
+        .map_err(|_| format!("invalid value for header {:?}", name))?;
+    Ok((name.to_string(), header_value.to_string()))
+}
+
+fn parse_crop_region(value: &str) -> Result<[u32; 4], String> {
+    let parts: Vec<u32> = value
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("expected x,y,width,height in pixels, got '{}'", value))?;
+    match <[u32; 4]>::try_from(parts) {
+        Ok([_, _, w, h]) if w == 0 || h == 0 => Err("crop region width and height must be positive".to_string()),
+        Ok(region) => Ok(region),
+        Err(_) => Err(format!("expected x,y,width,height in pixels, got '{}'", value)),
+    }
+}
+
+fn parse_hex_color(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.trim().trim_start_matches('#');
+    let hex = if hex.len() == 3 {
+        hex.chars().flat_map(|c| [c, c]).collect::<String>()
+    } else {
+        hex.to_string()
+    };
+    if hex.len() != 6 {
+        return Err(format!("expected a hex color like #ffffff, got '{}'", value));
+    }
+    let channel = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16)
+            .map_err(|_| format!("expected a hex color like #ffffff, got '{}'", value))
+    };
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Options controlling how a directory batch handles individual pages.
+#[derive(Args, Clone, Debug)]
+struct BatchOptions {
+    /// Keep going when an image fails, leaving a placeholder in the output
+    #[arg(long)]
+    continue_on_error: bool,
+
+    /// Drop pages with (almost) no text, together with their page break
+    #[arg(long)]
+    skip_blank: bool,
+
+    /// Pages with fewer cleaned characters than this count as blank
+    #[arg(long, default_value_t = DEFAULT_BLANK_THRESHOLD, requires = "skip_blank")]
+    blank_threshold: usize,
+
+    /// POST a JSON summary of the batch to this URL when it finishes (e.g. a chat webhook)
+    #[arg(long)]
+    notify_url: Option<String>,
+
+    /// OCR only every Nth image (1st, N+1th, ...) for a quick overview; page markers
+    /// keep the real page numbers
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    sample: u64,
+
+    #[command(flatten)]
+    running: RunningLineOptions,
+
+    /// Detect each page's language locally (no API calls), print it and record it in
+    /// the combined output as a ---IMAGE_LANGUAGE:xx--- marker
+    #[arg(long)]
+    detect_language: bool,
+
+    /// Language code to record for pages --detect-language can't tell (too little text,
+    /// a tie) when no earlier page of the batch had a language either
+    #[arg(long, value_name = "CODE", requires = "detect_language")]
+    language: Option<String>,
+
+    /// OCR this percentage of the pages (picked at random) a second time and report
+    /// pages whose two results differ noticeably, a sign the model is unsure of them
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    verify_sample: Option<f32>,
+
+    /// Where --verify-sample writes its report
+    #[arg(long, default_value = "verify_report.md", requires = "verify_sample")]
+    verify_report: PathBuf,
+
+    /// Write a JSON run report to this file when the batch finishes: page counts by
+    /// outcome, tokens used (as reported by the backend), elapsed time and per-page timings
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            continue_on_error: false,
+            skip_blank: false,
+            blank_threshold: DEFAULT_BLANK_THRESHOLD,
+            notify_url: None,
+            sample: 1,
+            running: RunningLineOptions::default(),
+            detect_language: false,
+            language: None,
+            verify_sample: None,
+            verify_report: PathBuf::from("verify_report.md"),
+            report: None,
+        }
+    }
+}
+
+const DEFAULT_BLANK_THRESHOLD: usize = 10;
+
+/// Removal of running headers and footers repeated on every page.
+#[derive(Args, Clone, Debug, Default)]
+struct RunningLineOptions {
+    /// Remove short lines repeated at the top or bottom of at least this many pages
+    /// (running headers, footers, page furniture)
+    #[arg(long, num_args = 0..=1, default_missing_value = "3", value_name = "PAGES", value_parser = clap::value_parser!(u64).range(2..))]
+    dedup_running: Option<u64>,
+
+    /// Keep the first occurrence of each removed running line
+    #[arg(long, requires = "dedup_running")]
+    keep_first_running: bool,
+}
+
+/// Lines this far from the top or bottom of a page can be running headers/footers.
+const RUNNING_EDGE_LINES: usize = 3;
+const MAX_RUNNING_LINE_CHARS: usize = 80;
+
+/// Image encoding for the `--join-images` canvas.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum JoinFormat {
+    Png,
+    Jpeg,
+}
+
+/// Encoding options for the combined image sent with `--join-images`.
+#[derive(Args, Clone, Debug)]
+struct JoinOptions {
+    /// Image format of the joined canvas; JPEG gives much smaller requests
+    #[arg(long, value_enum, default_value_t = JoinFormat::Png, requires = "join_images")]
+    join_format: JoinFormat,
+
+    /// JPEG quality (1-100) for the joined canvas
+    #[arg(long, default_value_t = DEFAULT_JOIN_QUALITY, value_parser = clap::value_parser!(u8).range(1..=100), requires = "join_images")]
+    join_quality: u8,
+
+    /// Downscale the joined canvas so its longest side is at most this many pixels
+    #[arg(long, requires = "join_images")]
+    join_max_side: Option<u32>,
+
+    /// Scale each image by this factor (0-1) before it is pasted onto the joined
+    /// canvas, keeping the canvas within the model's useful resolution; 1.0 keeps
+    /// full resolution
+    #[arg(long, value_name = "FACTOR", default_value_t = 1.0, value_parser = parse_join_scale, requires = "join_images")]
+    join_scale: f32,
+}
+
+fn parse_join_scale(value: &str) -> Result<f32, String> {
+    let factor: f32 = value.parse().map_err(|_| format!("expected a number, got {:?}", value))?;
+    if !(factor > 0.0 && factor <= 1.0) {
+        return Err(format!("expected a factor above 0 and at most 1, got {}", factor));
+    }
+    Ok(factor)
+}
+
+const DEFAULT_JOIN_QUALITY: u8 = 85;
+const DEFAULT_COLUMN_THRESHOLD: f32 = 95.0;
+const DEFAULT_MIN_FONT_SIZE: f32 = 6.0;
+const DEFAULT_MAX_FONT_SIZE: f32 = 10.0;
+
+/// Layout options for markdown-to-PDF rendering.
+#[derive(Args, Clone, Debug, Default)]
+struct PdfOptions {
+    /// Table column widths as ratios, e.g. "1,3,1" ("10,30,10" is the same layout).
+    /// Empty entries ("1,,3") are sized from their content and the given columns
+    /// split the rest of the table by ratio
+    #[arg(long)]
+    table_columns: Option<ColumnWidths>,
+
+    /// Draw this text diagonally across every page, e.g. "DRAFT"
+    #[arg(long)]
+    watermark: Option<String>,
+
+    /// Page background color as hex, e.g. "#fdf6e3"
+    #[arg(long, value_parser = parse_hex_color)]
+    background: Option<[u8; 3]>,
+
+    /// TrueType font to embed instead of Helvetica; needed for non-Latin scripts such as Arabic or Hebrew
+    #[arg(long)]
+    font: Option<PathBuf>,
+
+    /// Right-to-left layout: right-align text and apply bidi reordering (plain text mode)
+    #[arg(long)]
+    rtl: bool,
+
+    /// Render each table row as "header: value" lines instead of a grid, which reads
+    /// better on narrow pages (plain text mode)
+    #[arg(long)]
+    flatten_tables: bool,
+
+    /// Keep every line as written in a monospace font instead of word-wrapping (code,
+    /// ASCII art): "clip" lets long lines run off the page, "shrink" scales the font so
+    /// the longest line fits (plain text mode)
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "clip", value_name = "MODE")]
+    no_wrap: Option<NoWrap>,
+
+    /// Column boundaries in mm from the left page edge; pass several (e.g. "70,140")
+    /// for three or more columns. Default: one split at 95mm (coordinate mode)
+    #[arg(long = "column-threshold", value_name = "MM", value_delimiter = ',')]
+    column_thresholds: Vec<f32>,
+
+    /// Smallest body font size in points; raise it for legibility at the cost of
+    /// positioning fidelity (coordinate mode, default 6)
+    #[arg(long)]
+    min_font_size: Option<f32>,
+
+    /// Largest body font size in points (coordinate mode, default 10)
+    #[arg(long)]
+    max_font_size: Option<f32>,
+
+    /// Start a new page when the vertical gap between consecutive OCR blocks exceeds
+    /// this many grid units (0-999 per image height, pixel boxes are rescaled first);
+    /// splits stitched scans into their pages (coordinate mode)
+    #[arg(long)]
+    page_break_gap: Option<f32>,
+
+    /// Merge OCR boxes of similar text height that sit within this many grid units
+    /// (0-999 per image side, pixel boxes are rescaled first) of each other into one
+    /// block, so fragmented paragraphs flow as one (coordinate mode, default 20)
+    #[arg(long, num_args = 0..=1, default_missing_value = "20", value_name = "GAP")]
+    combine_adjacent_blocks: Option<f32>,
+
+    /// Drop table columns that are empty in every row, usually misdetected cell
+    /// boundaries (default on; pass "=false" to keep them)
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    trim_whitespace_columns: Option<bool>,
+
+    /// Print two pages side by side on each landscape sheet to save paper (plain text mode)
+    #[arg(long)]
+    two_up: bool,
+
+    /// Extra vertical space in mm after each paragraph and heading; the default depends
+    /// on the text size (5mm for body text) (plain text mode)
+    #[arg(long, value_name = "MM")]
+    paragraph_spacing: Option<f32>,
+
+    /// Color text by the model's <|conf|> confidence: red below 0.5, orange below 0.8,
+    /// black otherwise or without a score (coordinate mode)
+    #[arg(long)]
+    color_by_confidence: bool,
+
+    /// Join the lines under one OCR box into a wrapped paragraph (default); pass "=false"
+    /// to keep each line as written, for poetry or addresses (coordinate mode)
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    merge_lines_within_block: Option<bool>,
+
+    /// Vertical CJK layout: characters run top to bottom in columns that advance right
+    /// to left; needs a CJK --font (plain text mode)
+    #[arg(long, requires = "font", conflicts_with_all = ["rtl", "two_up", "no_wrap"])]
+    vertical: bool,
+
+    /// Label each page with the file name of its source image in the top margin; in
+    /// plain text mode each source image then starts a page of its own
+    #[arg(long)]
+    annotate_sources: bool,
+
+    /// Letterhead drawn as a full-page background on every page: an image (PNG, JPEG)
+    /// or the first page of a PDF (needs pdftoppm); combine with --content-margins to
+    /// keep text off its graphics
+    #[arg(long, value_name = "PDF_OR_IMAGE", value_parser = parse_template)]
+    template: Option<Letterhead>,
+
+    /// Space in mm kept free around the text as "top,right,bottom,left", or one value
+    /// for all sides (plain text mode, default 17,5,20,5; 15 all round with --vertical)
+    #[arg(long, value_name = "MM", value_parser = parse_margins)]
+    content_margins: Option<[f32; 4]>,
+
+    /// Treat single newlines as line breaks: consecutive lines of a block are set at
+    /// normal line spacing (still wrapped when too long) instead of each being spaced
+    /// out as its own paragraph, for addresses, poetry and signatures (plain text mode)
+    #[arg(long, visible_alias = "keep-original-line-breaks")]
+    preserve_breaks: bool,
+
+    /// Start the PDF with an index listing each source page's first heading (or first
+    /// line) and the page it begins on (plain text mode)
+    #[arg(long, conflicts_with = "vertical")]
+    index_page: bool,
+
+    /// Units of the <|det|> boxes: "pixels" boxes are mapped through each image's
+    /// recorded size, so the layout doesn't depend on the scan or extraction DPI
+    /// (coordinate mode)
+    #[arg(long, value_enum, default_value_t = BoxUnits::Auto)]
+    box_units: BoxUnits,
+
+    /// After saving the PDF, also render each of its pages to a PNG in this directory
+    /// (e.g. web previews); needs pdftoppm from poppler-utils
+    #[arg(long, value_name = "DIR")]
+    page_images: Option<PathBuf>,
+
+    /// Resolution of the --page-images PNGs
+    #[arg(long, default_value_t = DEFAULT_PAGE_IMAGE_DPI, requires = "page_images")]
+    page_image_dpi: u32,
+}
+
+const DEFAULT_PAGE_IMAGE_DPI: u32 = 96;
+/// Resolution `process-pdf` renders pages at unless `--dpi` says otherwise.
+const PDF_EXTRACT_DPI: u32 = 300;
+
+impl PdfOptions {
+    /// Plain-mode content margins in mm: top, right, bottom, left.
+    fn plain_margins(&self) -> [f32; 4] {
+        self.content_margins.unwrap_or(DEFAULT_PLAIN_MARGINS)
+    }
+}
+
+/// Parse `--content-margins`: four comma-separated millimetre values, or one for all sides.
+fn parse_margins(value: &str) -> Result<[f32; 4], String> {
+    let parts: Vec<f32> = value
+        .split(',')
+        .map(|part| part.trim().parse::<f32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("expected top,right,bottom,left in mm, got '{}'", value))?;
+    if parts.iter().any(|&mm| mm < 0.0) {
+        return Err("margins must not be negative".to_string());
+    }
+    match *parts.as_slice() {
+        [all] => Ok([all; 4]),
+        [top, right, bottom, left] => Ok([top, right, bottom, left]),
+        _ => Err(format!("expected top,right,bottom,left in mm, got '{}'", value)),
+    }
+}
+
+/// Fonts of a rendered PDF, loaded once per document.
+#[derive(Clone)]
+struct PdfFonts {
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+    /// Captions: builtin Helvetica has an oblique cut, an embedded `--font` is used as is
+    italic: IndirectFontRef,
+    /// `--no-wrap` and space-aligned tables, drawn in Courier unless `--font` is given
+    mono: IndirectFontRef,
+}
+
+/// Fonts for a new PDF: the `--font` file for all of them when given, otherwise the
+/// builtin Helvetica family and Courier.
+fn load_pdf_fonts(doc: &printpdf::PdfDocumentReference, options: &PdfOptions) -> Result<PdfFonts> {
+    use printpdf::BuiltinFont;
+
+    if let Some(path) = &options.font {
+        let font_file = |p: &Path| fs::File::open(p).with_context(|| format!("Failed to open font {}", p.display()));
+        let font = doc
+            .add_external_font(font_file(path)?)
+            .with_context(|| format!("Failed to load font {}", path.display()))?;
+        // No separate faces: embed the file once and use it for all of them
+        return Ok(PdfFonts { regular: font.clone(), bold: font.clone(), italic: font.clone(), mono: font });
+    }
+    Ok(PdfFonts {
+        regular: doc.add_builtin_font(BuiltinFont::Helvetica)?,
+        bold: doc.add_builtin_font(BuiltinFont::HelveticaBold)?,
+        italic: doc.add_builtin_font(BuiltinFont::HelveticaOblique)?,
+        mono: doc.add_builtin_font(BuiltinFont::Courier)?,
+    })
+}
+
+/// The PDF the converters draw into. The printpdf document is created along with its
+/// first page, so that page gets its own size; `--stream-pdf` keeps one open for the
+/// whole batch and every page shares its fonts.
+struct PdfTarget {
+    title: String,
+    open: std::cell::OnceCell<(printpdf::PdfDocumentReference, PdfFonts)>,
+}
+
+impl PdfTarget {
+    fn new(title: &str) -> Self {
+        Self { title: title.to_string(), open: std::cell::OnceCell::new() }
+    }
+
+    /// First page of a rendering: the page the document is created with, or a new
+    /// page when it is already open.
+    fn open_page(&self, size: PageSize, options: &PdfOptions) -> Result<PdfLayerReference> {
+        if self.open.get().is_some() {
+            return Ok(self.add_page(size));
+        }
+        let (doc, page, layer) = printpdf::PdfDocument::new(&self.title, Mm(size.0), Mm(size.1), "Layer 1");
+        let fonts = load_pdf_fonts(&doc, options)?;
+        let layer = doc.get_page(page).get_layer(layer);
+        let _ = self.open.set((doc, fonts));
+        Ok(layer)
+    }
+
+    /// Another page of the open document.
+    fn add_page(&self, size: PageSize) -> PdfLayerReference {
+        let (doc, _) = self.open.get().expect("open_page starts every rendering");
+        let (page, layer) = doc.add_page(Mm(size.0), Mm(size.1), "Layer 1");
+        doc.get_page(page).get_layer(layer)
+    }
+
+    fn fonts(&self) -> &PdfFonts {
+        &self.open.get().expect("open_page starts every rendering").1
+    }
+
+    fn save(self, output_path: &Path, options: &PdfOptions) -> Result<()> {
+        let (doc, _) = self
+            .open
+            .into_inner()
+            .with_context(|| format!("No pages to write to {}", output_path.display()))?;
+        save_pdf(doc, output_path, options)
+    }
+}
+
+/// Reorder a logical-order line into visual (left-to-right drawing) order for a
+/// right-to-left paragraph.
+fn bidi_visual_line(line: &str) -> String {
+    use unicode_bidi::{BidiInfo, Level};
+
+    let info = BidiInfo::new(line, Some(Level::rtl()));
+    match info.paragraphs.first() {
+        Some(para) => info.reorder_line(para, para.range.clone()).into_owned(),
+        None => line.to_string(),
+    }
+}
+
+/// Per-column width overrides parsed from `--table-columns`.
+#[derive(Clone, Debug, Default)]
+struct ColumnWidths(Vec<Option<f32>>);
+
+impl std::str::FromStr for ColumnWidths {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let widths = s
+            .split(',')
+            .map(|part| {
+                let part = part.trim().trim_end_matches('%');
+                if part.is_empty() {
+                    return Ok(None);
+                }
+                match part.parse::<f32>() {
+                    Ok(value) if value > 0.0 => Ok(Some(value)),
+                    _ => Err(format!("invalid column width '{}'", part)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ColumnWidths(widths))
+    }
+}
+
+impl ColumnWidths {
+    /// Split `available` width between `content_widths.len()` columns. The given
+    /// values are ratios; columns without one share a part of the table matching
+    /// their share of the content, and the given columns split the rest by ratio.
+    fn distribute(&self, content_widths: &[usize], available: f32) -> Vec<f32> {
+        let num_cols = content_widths.len();
+        let overrides: Vec<Option<f32>> = (0..num_cols)
+            .map(|i| self.0.get(i).copied().flatten())
+            .collect();
+        let specified: f32 = overrides.iter().flatten().sum();
+        let auto_count = overrides.iter().filter(|o| o.is_none()).count();
+
+        let total_chars: usize = content_widths.iter().sum();
+        let auto_chars: usize = content_widths
+            .iter()
+            .zip(&overrides)
+            .filter(|(_, o)| o.is_none())
+            .map(|(w, _)| *w)
+            .sum();
+        let auto_share = match (specified > 0.0, auto_count > 0) {
+            (true, false) => 0.0,
+            (false, _) => 1.0,
+            // Keep both groups readable: the auto-sized columns get a fifth to four fifths
+            (true, true) if total_chars > 0 => (auto_chars as f32 / total_chars as f32).clamp(0.2, 0.8),
+            (true, true) => auto_count as f32 / num_cols as f32,
+        };
+        let fixed = available * (1.0 - auto_share);
+        let remaining = available * auto_share;
+
+        content_widths
+            .iter()
+            .zip(&overrides)
+            .map(|(chars, o)| match o {
+                Some(value) => value / specified * fixed,
+                None if auto_chars > 0 => *chars as f32 / auto_chars as f32 * remaining,
+                None => remaining / auto_count as f32,
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct OcrRequest {
+    model: String,
+    messages: Vec<Message>,
+    max_tokens: u32,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    /// Structured output constraint for `--schema` (OpenAI-style `json_schema`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: String,
+    content: Vec<Content>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum Content {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(rename = "image_url")]
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize)]
+struct ImageUrl {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct OcrResponse {
+    choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+/// Token counts reported by OpenAI-compatible backends; local servers often omit them.
+#[derive(Deserialize)]
+struct Usage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+    total_tokens: Option<u64>,
+}
+
+/// Tokens reported by every OCR response so far, for the batch `--report`.
+static TOKENS_USED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+fn tokens_used() -> u64 {
+    TOKENS_USED.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ResponseMessage,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: String,
+}
+
+/// Exit status when a batch completed but some images were skipped or failed.
+/// Full success exits 0 and fatal errors exit 1.
+const EXIT_PARTIAL_FAILURE: i32 = 2;
+
+const NEXA_API_URL: &str = "http://127.0.0.1:18181/v1/chat/completions";
+const OLLAMA_API_URL: &str = "http://127.0.0.1:11434/v1/chat/completions";
+
+// Determine which API to use based on model name
+fn get_api_url(model: &str) -> &'static str {
+    // Check if it's an Ollama model (doesn't contain "NexaAI" or "GGUF")
+    if model.contains("NexaAI") || model.contains("GGUF") {
+        NEXA_API_URL
+    } else {
+        OLLAMA_API_URL
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = parse_cli()?;
+    // Images that were skipped or failed in a batch that otherwise completed
+    let mut incomplete_pages = 0usize;
+
+    match &cli.command {
+        Commands::ProcessImage { input, output, append, front_matter, pdf_output, model, custom_prompt, use_coordinates, disable_grounding_mode, ocr_options, pdf_options } => {
+            println!("DEBUG: ProcessImage called. disable_grounding_mode={}", disable_grounding_mode);
+            let use_grounding_mode = !disable_grounding_mode;
+            let (mut markdown, answered) = process_image(input, model, custom_prompt.as_deref(), *use_coordinates, use_grounding_mode, ocr_options).await?;
+            // An appended page joins a file that already has its header
+            let appending_to_existing = *append && output.as_ref().is_some_and(|p| fs::metadata(p).is_ok_and(|m| m.len() > 0));
+            if *front_matter && !appending_to_existing {
+                let language = detect_language(&clean_markdown_for_plain(&markdown));
+                markdown = format!("{}{}", build_front_matter(input, &answered, language), markdown);
+            }
+
+            if let Some(output_path) = output {
+                if *append {
+                    append_page(output_path, &markdown, &cli.encoding)?;
+                    println!("✓ Markdown appended to: {}", output_path.display());
+                } else {
+                    cli.encoding.write(output_path, &markdown)?;
+                    println!("✓ Markdown saved to: {}", output_path.display());
+                }
+            } else {
+                println!("{}", markdown);
+            }
+
+            if let Some(pdf_path) = pdf_output {
+                // After --append the PDF covers the whole file, like the markdown does
+                let pdf_markdown = match output {
+                    Some(output_path) if *append => fs::read_to_string(output_path)?,
+                    _ => markdown,
+                };
+                convert_markdown_to_pdf(&pdf_markdown, pdf_path, *use_coordinates, pdf_options)?;
+                println!("✓ PDF saved to: {}", pdf_path.display());
+            }
+        }
+        Commands::ProcessDir { input, file_list, output, output_dir, pdf_output, stream_pdf, front_matter, model, join_images, custom_prompt, disable_grounding_mode, use_coordinates, batch_options, join_options, ocr_options, pdf_options } => {
+            let use_grounding_mode = !disable_grounding_mode;
+            let started = std::time::Instant::now();
+            let image_files = match (file_list, input) {
+                (Some(list), _) => read_file_list(list)?,
+                // clap guarantees --input whenever --file-list is absent
+                (None, input) => collect_image_files(input.as_ref().context("Either --input or --file-list is required")?)?,
+            };
+            let (files_processed, failures, destination) = if let Some(dir) = output_dir {
+                let mut batch = process_directory(&image_files, model, custom_prompt.as_deref(), use_grounding_mode, *use_coordinates, batch_options, ocr_options, None).await?;
+                if *front_matter {
+                    for page in &mut batch.pages {
+                        let language = page
+                            .language
+                            .clone()
+                            .or_else(|| detect_language(&clean_markdown_for_plain(&page.markdown)).map(str::to_string))
+                            .or_else(|| batch_options.language.clone());
+                        let answered = page.model.as_deref().unwrap_or(model);
+                        page.markdown = format!("{}{}", build_front_matter(&page.source, answered, language.as_deref()), page.markdown);
+                    }
+                }
+                write_pages_to_dir(&batch.pages, dir, &cli.encoding)?;
+                incomplete_pages = batch.incomplete_count();
+                (batch.pages.len() - batch.failed.len(), batch.failed.len(), dir)
+            } else {
+                // clap guarantees --output whenever --output-dir is absent
+                let output = output.as_ref().context("Either --output or --output-dir is required")?;
+                let (markdown, files_processed, failures, answered) = if *join_images {
+                    let image_files: Vec<PathBuf> = image_files.into_iter().step_by(batch_options.sample as usize).collect();
+                    let files_processed = image_files.len();
+                    // The joined image is sent once, so only the first model of a chain is used
+                    let model = model_chain(model).first().copied().unwrap_or(model);
+                    let markdown = process_directory_joined(image_files, model, custom_prompt.as_deref(), use_grounding_mode, *use_coordinates, join_options, ocr_options).await?;
+                    (markdown, files_processed, 0, model.to_string())
+                } else {
+                    // Same title as the front matter gives the PDF when it is written in one go
+                    let title = file_list
+                        .as_ref()
+                        .or(input.as_ref())
+                        .filter(|_| *front_matter)
+                        .and_then(|source| source.file_stem())
+                        .and_then(|n| n.to_str())
+                        .unwrap_or("OCR Document");
+                    let mut stream = (pdf_output.is_some() && *stream_pdf).then(|| PdfStream::new(title, *use_coordinates, pdf_options));
+                    let batch = process_directory(&image_files, model, custom_prompt.as_deref(), use_grounding_mode, *use_coordinates, batch_options, ocr_options, stream.as_mut()).await?;
+                    incomplete_pages = batch.incomplete_count();
+                    if let (Some(stream), Some(pdf_path)) = (stream, pdf_output) {
+                        stream.finish(pdf_path)?;
+                        println!("✓ PDF saved to: {}", pdf_path.display());
+                    }
+                    (combine_pages(&batch.pages), batch.pages.len() - batch.failed.len(), batch.failed.len(), answering_models(&batch.pages))
+                };
+                let markdown = if *front_matter {
+                    // The batch as a whole comes from the directory, glob or file list
+                    let source = file_list.as_ref().or(input.as_ref()).context("Either --input or --file-list is required")?;
+                    let language = detect_language(&clean_markdown_for_plain(&markdown)).or(batch_options.language.as_deref());
+                    format!("{}{}", build_front_matter(source, &answered, language), markdown)
+                } else {
+                    markdown
+                };
+                cli.encoding.write(output, &markdown)?;
+                println!("✓ Markdown saved to: {}", output.display());
+                if let Some(pdf_path) = pdf_output.as_ref().filter(|_| !*stream_pdf) {
+                    convert_markdown_to_pdf(&markdown, pdf_path, *use_coordinates, pdf_options)?;
+                    println!("✓ PDF saved to: {}", pdf_path.display());
+                }
+                (files_processed, failures, output)
+            };
+
+            if let Some(url) = &batch_options.notify_url {
+                let summary = BatchSummary {
+                    files_processed,
+                    failures,
+                    incomplete: incomplete_pages,
+                    elapsed_seconds: started.elapsed().as_secs_f64(),
+                    output: destination.display().to_string(),
+                };
+                notify_batch_complete(url, &summary, ocr_options).await;
+            }
+        }
+        Commands::ProcessPdf {
+            input,
+            output,
+            temp_dir,
+            use_native,
+            temp_format,
+            dpi,
+            sample,
+        } => {
+            let (markdown, skipped) = process_pdf(input, temp_dir, *use_native, *temp_format, *dpi, *sample).await?;
+            incomplete_pages = skipped;
+            cli.encoding.write(output, &markdown)?;
+            println!("✓ Markdown saved to: {}", output.display());
+        }
+        Commands::MarkdownToPdf {
+            input,
+            output,
+            use_coordinates,
+            pdf_options,
+        } => {
+            println!(
+                "👉 markdown-to-pdf: input={} output={} use_coordinates={}",
+                input.display(),
+                output.display(),
+                use_coordinates
+            );
+            let markdown = fs::read_to_string(input)?;
+            convert_markdown_to_pdf(&markdown, output, *use_coordinates, pdf_options)?;
+            println!("✓ PDF saved to: {}", output.display());
+        }
+        Commands::MarkdownToDocx { input, output } => {
+            let markdown = fs::read_to_string(input)?;
+            convert_markdown_to_docx(&markdown, output)?;
+            println!("✓ DOCX saved to: {}", output.display());
+        }
+        Commands::MarkdownToEpub { input, output } => {
+            let markdown = fs::read_to_string(input)?;
+            let chapters = convert_markdown_to_epub(&markdown, output)?;
+            println!("✓ EPUB with {} chapter(s) saved to: {}", chapters, output.display());
+        }
+        Commands::ProcessMarkdown { input, output, clean, keep_coordinates, clean_level, dehyphenate: rejoin_hyphens, reflow, only_tables, format, trim_whitespace_columns, running } => {
+            let format = if *only_tables {
+                match MarkdownFormat::resolve(*format, output.as_deref())? {
+                    MarkdownFormat::Tsv => MarkdownFormat::Tsv,
+                    _ => MarkdownFormat::Csv,
+                }
+            } else {
+                MarkdownFormat::resolve(*format, output.as_deref())?
+            };
+            if *keep_coordinates {
+                eprintln!("⚠ --keep-coordinates is deprecated and has no effect: <|det|> coordinates are kept by default");
+            }
+            let mut markdown = fs::read_to_string(input)?;
+            // Runs before cleaning, which drops the page break markers
+            if let Some(min_pages) = running.dedup_running {
+                let re_page_break = Regex::new(r"(?m)^---PAGE_BREAK---[ \t]*$").unwrap();
+                let mut pages: Vec<String> = re_page_break.split(&markdown).map(str::to_string).collect();
+                let removed = dedup_running_lines(&mut pages, min_pages as usize, running.keep_first_running);
+                markdown = pages.join("---PAGE_BREAK---");
+                eprintln!("🧹 Removed {} running header/footer line(s)", removed);
+            }
+
+            if matches!(format, MarkdownFormat::Csv | MarkdownFormat::Tsv) {
+                let tables = extract_tables(&markdown);
+                if tables.is_empty() {
+                    println!("⚠ No tables found in {}", input.display());
+                }
+                let delimiter = if format == MarkdownFormat::Tsv { b'\t' } else { b',' };
+                write_tables(&tables, output.as_deref(), delimiter)?;
+                return Ok(());
+            }
+
+            // Blocks come from the <|det|> tags, which most cleaning levels remove
+            if format == MarkdownFormat::BlocksJson {
+                let output_path = output.as_ref().context("blocks-json output needs --output")?;
+                let (_, body) = split_front_matter(&markdown);
+                let export = BlocksExport { markdown: clean_markdown_for_plain(body), blocks: parse_ocr_blocks(body, true) };
+                fs::write(output_path, serde_json::to_string_pretty(&export)?)
+                    .with_context(|| format!("Failed to write {}", output_path.display()))?;
+                println!("✓ {} block(s) saved to: {}", export.blocks.len(), output_path.display());
+                return Ok(());
+            }
+
+            // Keep front matter intact; only the body goes through cleaning
+            let (front_matter, body) = split_front_matter(&markdown);
+            let mut body = if let Some(level) = clean_level {
+                clean_markdown_level(body, *level)
+            } else if *clean {
+                clean_markdown_for_plain(body)
+            } else {
+                // Light clean by default: <|det|> coordinates survive for later rendering
+                clean_markdown(body)
+            };
+            if *rejoin_hyphens {
+                body = dehyphenate(&body);
+            }
+            if *reflow {
+                body = reflow_paragraphs(&body);
+            }
+            let processed = match front_matter {
+                Some(front_matter) => format!("{}{}", front_matter.raw, body),
+                None => body,
+            };
+
+            match (format, output) {
+                (MarkdownFormat::Markdown, Some(output_path)) => {
+                    cli.encoding.write(output_path, &processed)?;
+                    println!("✓ Processed markdown saved to: {}", output_path.display());
+                }
+                (MarkdownFormat::Markdown, None) => println!("{}", processed),
+                (MarkdownFormat::Text, Some(output_path)) => {
+                    cli.encoding.write(output_path, &markdown_to_text(&processed, trim_whitespace_columns.unwrap_or(true)))?;
+                    println!("✓ Text saved to: {}", output_path.display());
+                }
+                (MarkdownFormat::Text, None) => print!("{}", markdown_to_text(&processed, trim_whitespace_columns.unwrap_or(true))),
+                (MarkdownFormat::Pdf, Some(output_path)) => {
+                    // Coordinates kept by the light clean drive the layout when present
+                    let use_coordinates = processed.contains("<|det|>");
+                    let pdf_options = PdfOptions { trim_whitespace_columns: *trim_whitespace_columns, ..PdfOptions::default() };
+                    convert_markdown_to_pdf(&processed, output_path, use_coordinates, &pdf_options)?;
+                }
+                (MarkdownFormat::Docx, Some(output_path)) => {
+                    convert_markdown_to_docx(&processed, output_path)?;
+                    println!("✓ DOCX saved to: {}", output_path.display());
+                }
+                (MarkdownFormat::Epub, Some(output_path)) => {
+                    let chapters = convert_markdown_to_epub(&processed, output_path)?;
+                    println!("✓ EPUB with {} chapter(s) saved to: {}", chapters, output_path.display());
+                }
+                (format, None) => anyhow::bail!("{} output needs --output", format!("{:?}", format).to_lowercase()),
+                (MarkdownFormat::Csv | MarkdownFormat::Tsv | MarkdownFormat::BlocksJson, Some(_)) => unreachable!(),
+            }
+        }
+        Commands::SplitPdf { input, output, pages } => {
+            println!("Splitting PDF: {} -> {}", input.display(), output.display());
+            println!("Page order: {}", pages);
+            
+            split_pdf(input, output, pages)?;
+            println!("✓ PDF split successfully: {}", output.display());
+        }
+        Commands::Serve { host, port, model, custom_prompt, use_coordinates, disable_grounding_mode, ocr_options } => {
+            let mut options = OcrOptions::clone(ocr_options);
+            options.client = Some(ocr_options.http_client()?);
+            let state = ServeState {
+                model: model.clone(),
+                custom_prompt: custom_prompt.clone(),
+                use_coordinates: *use_coordinates,
+                use_grounding_mode: !disable_grounding_mode,
+                options,
+            };
+            serve(host, *port, state).await?;
+        }
+        Commands::DiffMarkdown { old, new, words } => {
+            use std::io::IsTerminal;
+
+            let old_text = clean_markdown_for_plain(&fs::read_to_string(old)?);
+            let new_text = clean_markdown_for_plain(&fs::read_to_string(new)?);
+            let color = std::io::stdout().is_terminal();
+            print!("{}", diff_markdown(&old_text, &new_text, *words, color));
+        }
+        Commands::Contacts { input, output, columns, thumb_size, temp_dir } => {
+            let pages = if input.extension().is_some_and(|e| e.eq_ignore_ascii_case("pdf")) {
+                render_pdf_thumbnail_pages(input, temp_dir)?
+            } else {
+                collect_image_files(input)?
+            };
+            if pages.is_empty() {
+                anyhow::bail!("No images found in {}", input.display());
+            }
+            let sheet = build_contact_sheet(&pages, *columns, *thumb_size)?;
+            sheet.save_with_format(output, image::ImageFormat::Png)
+                .with_context(|| format!("Failed to save {}", output.display()))?;
+            println!("✓ Contact sheet with {} page(s) saved to: {}", pages.len(), output.display());
+        }
+        Commands::Selftest { model, disable_grounding_mode, ocr_options } => {
+            selftest(model, !disable_grounding_mode, ocr_options).await?;
+        }
+    }
+
+    if incomplete_pages > 0 {
+        eprintln!("⚠ Completed with {} skipped or failed image(s)", incomplete_pages);
+        std::process::exit(EXIT_PARTIAL_FAILURE);
+    }
+
+    Ok(())
+}
+
+/// Sample page for `selftest`; it reads "PRIVACY OCR".
+const SELFTEST_IMAGE: &[u8] = include_bytes!("../assets/selftest.png");
+const SELFTEST_EXPECTED_WORD: &str = "PRIVACY";
+
+/// OCR the bundled sample image end to end and check that the expected word comes back.
+async fn selftest(model: &str, use_grounding_mode: bool, options: &OcrOptions) -> Result<()> {
+    let image_path = std::env::temp_dir().join(format!("ocr_selftest_{}.png", std::process::id()));
+    fs::write(&image_path, SELFTEST_IMAGE).context("Failed to write the self-test image")?;
+
+    println!("🧪 Running OCR self-test with model {}", model);
+    let started = std::time::Instant::now();
+    let result = process_image(&image_path, model, None, false, use_grounding_mode, options).await;
+    let elapsed = started.elapsed();
+    let _ = fs::remove_file(&image_path);
+    let (markdown, _) = result.context("Self-test OCR request failed")?;
+
+    let text = clean_markdown_for_plain(&markdown);
+    if !text.to_uppercase().contains(SELFTEST_EXPECTED_WORD) {
+        anyhow::bail!(
+            "Self-test failed after {:.2}s: expected \"{}\" in the OCR output, got: {:?}",
+            elapsed.as_secs_f64(),
+            SELFTEST_EXPECTED_WORD,
+            text
+        );
+    }
+    println!("✓ Self-test passed in {:.2}s (found \"{}\")", elapsed.as_secs_f64(), SELFTEST_EXPECTED_WORD);
+    Ok(())
+}
+
+/// Render a line- or word-level diff. Without color, word changes are marked
+/// `[-removed-]{+added+}` and line changes with a leading `-`/`+`.
+fn diff_markdown(old: &str, new: &str, words: bool, color: bool) -> String {
+    use similar::{ChangeTag, TextDiff};
+
+    const RED: &str = "\x1b[31m";
+    const GREEN: &str = "\x1b[32m";
+    const RESET: &str = "\x1b[0m";
+
+    let diff = if words {
+        TextDiff::from_words(old, new)
+    } else {
+        TextDiff::from_lines(old, new)
+    };
+
+    let mut out = String::new();
+    let (mut removed, mut added) = (0usize, 0usize);
+    for change in diff.iter_all_changes() {
+        let value = change.value();
+        match (change.tag(), words, color) {
+            (ChangeTag::Equal, true, _) => out.push_str(value),
+            (ChangeTag::Equal, false, _) => out.push_str(&format!("  {}", value)),
+            (ChangeTag::Delete, true, true) => out.push_str(&format!("{}{}{}", RED, value, RESET)),
+            (ChangeTag::Delete, true, false) => out.push_str(&format!("[-{}-]", value)),
+            (ChangeTag::Delete, false, true) => out.push_str(&format!("{}- {}{}", RED, value, RESET)),
+            (ChangeTag::Delete, false, false) => out.push_str(&format!("- {}", value)),
+            (ChangeTag::Insert, true, true) => out.push_str(&format!("{}{}{}", GREEN, value, RESET)),
+            (ChangeTag::Insert, true, false) => out.push_str(&format!("{{+{}+}}", value)),
+            (ChangeTag::Insert, false, true) => out.push_str(&format!("{}+ {}{}", GREEN, value, RESET)),
+            (ChangeTag::Insert, false, false) => out.push_str(&format!("+ {}", value)),
+        }
+        if !words && change.missing_newline() {
+            out.push('\n');
+        }
+        // Whitespace tokens are part of the word diff but not worth counting
+        if value.trim().is_empty() {
+            continue;
+        }
+        match change.tag() {
+            ChangeTag::Delete => removed += 1,
+            ChangeTag::Insert => added += 1,
+            ChangeTag::Equal => {}
+        }
+    }
+
+    if words && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    let unit = if words { "words" } else { "lines" };
+    out.push_str(&format!(
+        "\n{} {} removed, {} {} added (similarity {:.1}%)\n",
+        removed,
+        unit,
+        added,
+        unit,
+        diff.ratio() * 100.0
+    ));
+    out
+}
+
+fn split_pdf(input: &Path, output: &Path, pages_str: &str) -> Result<()> {
+    use std::process::Command;
+    
+    // Parse page numbers
+    let page_numbers: Vec<u32> = pages_str
+        .split(',')
+        .map(|s| s.trim())
         .filter(|s| !s.is_empty())
         .map(|s| s.parse::<u32>())
         .collect::<Result<Vec<u32>, _>>()
         .context("Failed to parse page numbers")?;
     
-    if page_numbers.is_empty() {
-        anyhow::bail!("No page numbers provided");
+    if page_numbers.is_empty() {
+        anyhow::bail!("No page numbers provided");
+    }
+    
+    println!("Splitting PDF: {} pages selected", page_numbers.len());
+    
+    // Try qpdf first (better quality preservation)
+    let qpdf_result = Command::new("qpdf")
+        .arg("--empty")
+        .arg("--pages")
+        .arg(input)
+        .arg(pages_str)
+        .arg("--")
+        .arg(output)
+        .output();
+    
+    if let Ok(output_result) = qpdf_result {
+        if output_result.status.success() {
+            println!("✓ PDF split successfully with qpdf");
+            return Ok(());
+        }
+    }
+    
+    // Fallback to pdftk
+    // Build pdftk command: pdftk input.pdf cat 1 3 2 output output.pdf
+    let pdftk_result = Command::new("pdftk")
+        .arg(input)
+        .arg("cat")
+        .args(page_numbers.iter().map(|n| n.to_string()))
+        .arg("output")
+        .arg(output)
+        .output();
+    
+    if let Ok(output_result) = pdftk_result {
+        if output_result.status.success() {
+            println!("✓ PDF split successfully with pdftk");
+            return Ok(());
+        }
+    }
+    
+    anyhow::bail!("PDF split requires qpdf or pdftk to be installed. Install with: brew install qpdf or brew install pdftk-java")
+}
+
+/// OCR one image file, page by page when it has frames or spread halves.
+/// Returns the markdown and the model(s) of the `--model` chain that answered.
+async fn process_image(image_path: &Path, model: &str, custom_prompt: Option<&str>, use_coordinates: bool, use_grounding_mode: bool, options: &OcrOptions) -> Result<(String, String), OcrError> {
+    let (frames, parts) = image_parts(image_path, options)?;
+    if frames > 1 {
+        println!("🎞 {} frames found; OCR'ing each as a page", frames);
+    }
+    if parts.len() <= 1 {
+        let (result, answered) = process_image_with_fallback(image_path, model, custom_prompt, use_grounding_mode, use_coordinates, options).await?;
+        return Ok((result.markdown, answered.to_string()));
+    }
+
+    let mut pages = Vec::with_capacity(parts.len());
+    for (index, part) in parts.into_iter().enumerate() {
+        let label = part.label();
+        println!("  {}", label);
+        let (result, answered) = process_image_with_fallback(image_path, model, custom_prompt, use_grounding_mode, use_coordinates, &part.options(options))
+            .await
+            .with_context(|| format!("{} failed", label))?;
+        pages.push(PageOutput { index, source: image_path.to_path_buf(), part, markdown: result.markdown, dimensions: result.size, language: None, model: Some(answered.to_string()) });
+    }
+    Ok((combine_pages(&pages), answering_models(&pages)))
+}
+
+/// One page of an input image: a frame of an animated GIF / multi-page TIFF and/or
+/// one half of a `--split-spreads` spread. Both `None` for a plain image.
+#[derive(Clone, Copy, Debug, Default)]
+struct ImagePart {
+    frame: Option<usize>,
+    /// Columns (left, width) of a spread half
+    half: Option<(u32, u32)>,
+}
+
+impl ImagePart {
+    /// "frame 2, left page"; empty for a plain image.
+    fn label(&self) -> String {
+        let frame = self.frame.map(|frame| format!("frame {}", frame + 1));
+        let half = self.side().map(|side| format!("{} page", side));
+        frame.into_iter().chain(half).collect::<Vec<_>>().join(", ")
+    }
+
+    /// "left" or "right" for a spread half.
+    fn side(&self) -> Option<&'static str> {
+        self.half.map(|(left, _)| if left == 0 { "left" } else { "right" })
+    }
+
+    /// Pixel size of this part of the image: a spread half is only its own columns wide.
+    fn dimensions(&self, image_path: &Path) -> Option<(u32, u32)> {
+        let (width, height) = image::image_dimensions(image_path).ok()?;
+        Some(self.half.map_or((width, height), |(_, half_width)| (half_width, height)))
+    }
+
+    /// `options` narrowed to this part.
+    fn options(&self, options: &OcrOptions) -> OcrOptions {
+        let mut part_options = options.clone();
+        part_options.frame = self.frame;
+        part_options.spread_half = self.half;
+        part_options
+    }
+}
+
+/// The pages an image is OCR'd as, with its frame count: every frame, or every half
+/// of a split spread, is a page of its own.
+fn image_parts(image_path: &Path, options: &OcrOptions) -> Result<(usize, Vec<ImagePart>)> {
+    let image_data = match image_url(image_path) {
+        Some(_) => None,
+        None => read_image_bytes(image_path).ok(),
+    };
+    let frames = image_data.as_deref().map_or(1, frame_count);
+
+    let mut parts = Vec::new();
+    for index in 0..frames {
+        let frame = (frames > 1).then_some(index);
+        let halves = match &image_data {
+            Some(data) if options.split_spreads => spread_halves(data, frame, options.spread_split)?,
+            _ => Vec::new(),
+        };
+        if halves.is_empty() {
+            parts.push(ImagePart { frame, half: None });
+        } else {
+            parts.extend(halves.into_iter().map(|half| ImagePart { frame, half: Some(half) }));
+        }
+    }
+    Ok((frames, parts))
+}
+
+/// Columns (left, width) of the two facing pages of a landscape `--split-spreads`
+/// image, split at `split_percent` of the width or at the detected gutter. Empty for
+/// portrait images, which hold a single page.
+fn spread_halves(image_data: &[u8], frame: Option<usize>, split_percent: Option<f32>) -> Result<Vec<(u32, u32)>> {
+    let img = match frame {
+        Some(index) => load_frame(image_data, index)?,
+        None => image::load_from_memory(image_data)?,
+    };
+    let (width, height) = (img.width(), img.height());
+    if width <= height {
+        return Ok(Vec::new());
+    }
+    let split = match split_percent {
+        Some(percent) => (width as f32 * percent / 100.0).round() as u32,
+        None => detect_gutter(&img),
+    };
+    if split == 0 || split >= width {
+        anyhow::bail!("--spread-split leaves nothing on one side of the {}px wide image", width);
+    }
+    println!("📖 Splitting spread at column {} of {}{}", split, width, if split_percent.is_none() { " (detected gutter)" } else { "" });
+    Ok(vec![(0, split), (split, width - split)])
+}
+
+/// Gutter column of a two-page spread: the column of the middle fifth of the image
+/// crossing the fewest light/dark edges, i.e. the one with the least text on it.
+fn detect_gutter(img: &image::DynamicImage) -> u32 {
+    let gray = img.to_luma8();
+    let (width, height) = gray.dimensions();
+    let edges: Vec<u32> = (0..width)
+        .map(|x| {
+            (1..height)
+                .filter(|&y| gray.get_pixel(x, y)[0].abs_diff(gray.get_pixel(x, y - 1)[0]) > 48)
+                .count() as u32
+        })
+        .collect();
+    // Averaged over a few columns so a single gap between letters doesn't win
+    let window = (width / 100).max(1) as usize;
+    let (from, to) = ((width * 2 / 5) as usize, (width * 3 / 5) as usize);
+    let scores: Vec<u32> = (from..to)
+        .map(|x| edges[x.saturating_sub(window)..(x + window + 1).min(edges.len())].iter().sum())
+        .collect();
+    // A blank gutter is wider than the window: split in the middle of it
+    let Some(&best) = scores.iter().min() else {
+        return width / 2;
+    };
+    let start = scores.iter().position(|&score| score == best).unwrap_or(0);
+    let run = scores[start..].iter().take_while(|&&score| score == best).count();
+    (from + start + run / 2) as u32
+}
+
+/// Models of a `--model` fallback chain such as "deepseek-ocr,NexaAI/DeepSeek-OCR-GGUF", in priority order.
+fn model_chain(model: &str) -> Vec<&str> {
+    model.split(',').map(str::trim).filter(|m| !m.is_empty()).collect()
+}
+
+/// OCR with each model of the `--model` chain in turn until one succeeds.
+/// Returns the result and the model that produced it.
+async fn process_image_with_fallback<'m>(image_path: &Path, model: &'m str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool, options: &OcrOptions) -> Result<(ImageOcr, &'m str)> {
+    let models = model_chain(model);
+    let Some((last, earlier)) = models.split_last() else {
+        anyhow::bail!("--model must name at least one model");
+    };
+    if earlier.is_empty() {
+        // Pass the grounding mode flag correctly
+        let markdown = process_image_with_mode(image_path, last, custom_prompt, use_grounding_mode, use_coordinates, options).await?;
+        return Ok((markdown, last));
+    }
+
+    for (i, candidate) in earlier.iter().enumerate() {
+        match process_image_with_mode(image_path, candidate, custom_prompt, use_grounding_mode, use_coordinates, options).await {
+            Ok(markdown) => {
+                println!("✓ Result produced by model {}", candidate);
+                return Ok((markdown, candidate));
+            }
+            Err(e) => eprintln!("⚠ Model {} failed: {:#}; falling back to {}", candidate, e, models[i + 1]),
+        }
+    }
+    let markdown = process_image_with_mode(image_path, last, custom_prompt, use_grounding_mode, use_coordinates, options)
+        .await
+        .with_context(|| format!("all {} models failed", models.len()))?;
+    println!("✓ Result produced by model {}", last);
+    Ok((markdown, last))
+}
+
+/// Preflight measurements of an image for `--min-quality`.
+struct ImageQuality {
+    width: u32,
+    height: u32,
+    /// Variance of the Laplacian of the gray image; low for blurry scans
+    sharpness: f64,
+    /// Gray levels between the 1st and 99th percentile
+    contrast: u8,
+}
+
+fn assess_image_quality(img: &image::DynamicImage) -> ImageQuality {
+    let gray = img.to_luma8();
+    let (width, height) = gray.dimensions();
+
+    let mut sum = 0.0;
+    let mut sum_sq = 0.0;
+    let mut count = 0.0;
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let at = |x: u32, y: u32| gray.get_pixel(x, y)[0] as f64;
+            let laplacian = at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y);
+            sum += laplacian;
+            sum_sq += laplacian * laplacian;
+            count += 1.0;
+        }
+    }
+    let sharpness = if count > 0.0 { sum_sq / count - (sum / count).powi(2) } else { 0.0 };
+
+    let mut histogram = [0usize; 256];
+    for pixel in gray.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+    // Gray level below which `share` of the pixels lie
+    let percentile = |share: f64| {
+        let target = (gray.pixels().len() as f64 * share) as usize;
+        let mut seen = 0;
+        histogram.iter().position(|&n| {
+            seen += n;
+            seen > target
+        })
+        .unwrap_or(255) as u8
+    };
+    let contrast = percentile(0.99).saturating_sub(percentile(0.01));
+
+    ImageQuality { width, height, sharpness, contrast }
+}
+
+/// `--min-quality` preflight: report an image about to be sent (after preprocessing,
+/// so the frame, spread half, crop region or tile being read) that falls below the
+/// thresholds. Returns false when it should be skipped.
+fn check_image_quality(image_data: &[u8], filename: &str, options: &OcrOptions) -> Result<bool> {
+    let Some(action) = options.min_quality else {
+        return Ok(true);
+    };
+    let quality = assess_image_quality(&image::load_from_memory(image_data)?);
+
+    let mut problems = Vec::new();
+    if quality.width.min(quality.height) < options.min_resolution {
+        problems.push(format!("too small ({}x{} px)", quality.width, quality.height));
+    }
+    if quality.sharpness < options.min_sharpness {
+        problems.push(format!("blurry (sharpness {:.0})", quality.sharpness));
+    }
+    if quality.contrast < options.min_contrast {
+        problems.push(format!("low contrast ({} gray levels)", quality.contrast));
+    }
+    if problems.is_empty() {
+        return Ok(true);
+    }
+
+    let part = ImagePart { frame: options.frame, half: options.spread_half }.label();
+    let tile = options.tile.map(|(top, rows)| format!("rows {}-{}", top, top + rows));
+    let part = match (part.is_empty(), tile) {
+        (true, None) => String::new(),
+        (true, Some(tile)) => format!(" ({})", tile),
+        (false, None) => format!(" ({})", part),
+        (false, Some(tile)) => format!(" ({}, {})", part, tile),
+    };
+    match action {
+        QualityAction::Warn => eprintln!("⚠ Poor OCR input {}{}: {}", filename, part, problems.join(", ")),
+        QualityAction::Skip => eprintln!("⏭ Skipping poor OCR input {}{}: {}", filename, part, problems.join(", ")),
+    }
+    Ok(action == QualityAction::Warn)
+}
+
+/// Number of frames of an animated GIF or pages of a multi-page TIFF; 1 for anything else.
+fn frame_count(image_data: &[u8]) -> usize {
+    let frames = match image::guess_format(image_data) {
+        Ok(image::ImageFormat::Gif) => image::codecs::gif::GifDecoder::new(std::io::Cursor::new(image_data))
+            .map_or(1, |decoder| image::AnimationDecoder::into_frames(decoder).count()),
+        Ok(image::ImageFormat::Tiff) => tiff_directories(image_data).map_or(1, |(_, offsets)| offsets.len()),
+        _ => 1,
+    };
+    frames.max(1)
+}
+
+/// Decode one frame of an animated GIF or one page of a multi-page TIFF.
+fn load_frame(image_data: &[u8], index: usize) -> Result<image::DynamicImage, OcrError> {
+    let missing = || {
+        image::ImageError::Parameter(image::error::ParameterError::from_kind(image::error::ParameterErrorKind::NoMoreData))
+    };
+    match image::guess_format(image_data)? {
+        image::ImageFormat::Gif => {
+            let decoder = image::codecs::gif::GifDecoder::new(std::io::Cursor::new(image_data))?;
+            let frame = image::AnimationDecoder::into_frames(decoder).nth(index).ok_or_else(missing)??;
+            Ok(image::DynamicImage::ImageRgba8(frame.into_buffer()))
+        }
+        image::ImageFormat::Tiff => {
+            // The image crate only decodes the first page, so point the header at the wanted one
+            let (layout, offsets) = tiff_directories(image_data).ok_or_else(missing)?;
+            let offset = *offsets.get(index).ok_or_else(missing)?;
+            let mut page = image_data.to_vec();
+            layout.write_offset(&mut page, layout.first_offset_at, offset);
+            Ok(image::load_from_memory_with_format(&page, image::ImageFormat::Tiff)?)
+        }
+        _ if index == 0 => Ok(image::load_from_memory(image_data)?),
+        _ => Err(missing().into()),
+    }
+}
+
+/// Byte layout of a classic or BigTIFF file.
+struct TiffLayout {
+    little_endian: bool,
+    /// Where the header stores the offset of the first image directory
+    first_offset_at: usize,
+    offset_len: usize,
+    count_len: usize,
+    entry_len: usize,
+}
+
+impl TiffLayout {
+    fn read(&self, data: &[u8], at: usize, len: usize) -> Option<usize> {
+        let bytes = data.get(at..at.checked_add(len)?)?;
+        let fold = |value: u64, &byte: &u8| (value << 8) | u64::from(byte);
+        let value = if self.little_endian {
+            bytes.iter().rev().fold(0, fold)
+        } else {
+            bytes.iter().fold(0, fold)
+        };
+        usize::try_from(value).ok()
+    }
+
+    fn write_offset(&self, data: &mut [u8], at: usize, offset: usize) {
+        let bytes = &mut data[at..at + self.offset_len];
+        let mut value = offset as u64;
+        for i in 0..bytes.len() {
+            let slot = if self.little_endian { i } else { bytes.len() - 1 - i };
+            bytes[slot] = value as u8;
+            value >>= 8;
+        }
+    }
+}
+
+/// Layout of a TIFF file and the offsets of its image directories (one per page), in order.
+fn tiff_directories(data: &[u8]) -> Option<(TiffLayout, Vec<usize>)> {
+    let little_endian = match data.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let mut layout = TiffLayout { little_endian, first_offset_at: 4, offset_len: 4, count_len: 2, entry_len: 12 };
+    if layout.read(data, 2, 2)? == 43 {
+        layout = TiffLayout { little_endian, first_offset_at: 8, offset_len: 8, count_len: 8, entry_len: 20 };
+    }
+
+    let mut offsets = Vec::new();
+    let mut next = layout.read(data, layout.first_offset_at, layout.offset_len)?;
+    // A directory pointing back at an earlier one would loop forever
+    while next != 0 && !offsets.contains(&next) {
+        let Some(count) = layout.read(data, next, layout.count_len) else { break };
+        offsets.push(next);
+        let next_at = count
+            .checked_mul(layout.entry_len)
+            .and_then(|entries| entries.checked_add(next + layout.count_len));
+        match next_at.and_then(|at| layout.read(data, at, layout.offset_len)) {
+            Some(offset) => next = offset,
+            None => break,
+        }
+    }
+    Some((layout, offsets))
+}
+
+async fn process_image_with_mode(image_path: &Path, model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool, options: &OcrOptions) -> Result<ImageOcr> {
+    if let Some(tile_height) = options.tile_height {
+        if let Some(result) = ocr_tiles(image_path, model, custom_prompt, use_grounding_mode, use_coordinates, tile_height, options).await? {
+            return Ok(result);
+        }
+    }
+    ocr_image_oriented(image_path, model, custom_prompt, use_grounding_mode, use_coordinates, options).await
+}
+
+/// OCR an image taller than `tile_height` as overlapping horizontal tiles and join the
+/// results, with boxes on the grid of the whole (cropped) image. Returns `None` when
+/// the image is short enough to send whole.
+async fn ocr_tiles(image_path: &Path, model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool, tile_height: u32, options: &OcrOptions) -> Result<Option<ImageOcr>> {
+    if options.tile_overlap >= tile_height {
+        anyhow::bail!("--tile-overlap ({}) must be smaller than --tile-height ({})", options.tile_overlap, tile_height);
+    }
+    if image_url(image_path).is_some() {
+        println!("⚠ --tile-height is not supported for URL inputs; sending the image whole");
+        return Ok(None);
+    }
+    let image_data = read_image_bytes(image_path)?;
+    let (width, height) = image::ImageReader::new(std::io::Cursor::new(&image_data))
+        .with_guessed_format()?
+        .into_dimensions()
+        .with_context(|| format!("Failed to read image size of {}", image_path.display()))?;
+    // Tiles are cut from the --crop-region, which is applied first
+    let (width, height) = options.crop_region.map_or((width, height), |[_, _, w, h]| (w, h));
+    if height <= tile_height {
+        return Ok(None);
+    }
+
+    let tiles = tile_ranges(height, tile_height, options.tile_overlap);
+    println!("🧩 Splitting {}px tall image into {} tiles", height, tiles.len());
+    let mut markdown = String::new();
+    for (i, &(top, rows)) in tiles.iter().enumerate() {
+        println!("  Tile {}/{}: rows {}-{}", i + 1, tiles.len(), top, top + rows);
+        let mut tile_options = options.clone();
+        tile_options.tile = Some((top, rows));
+        let tile = ocr_image_oriented(image_path, model, custom_prompt, use_grounding_mode, use_coordinates, &tile_options)
+            .await
+            .with_context(|| format!("tile {}/{} failed", i + 1, tiles.len()))?;
+        let tile_markdown = offset_tile_coordinates(&tile.markdown, top, rows, height);
+        markdown = join_tiles(markdown, &tile_markdown);
+    }
+    Ok(Some(ImageOcr { markdown, size: Some((width, height)), skipped: false }))
+}
+
+/// Row ranges (top, height) covering `height` rows in tiles of `tile_height` that
+/// share `overlap` rows with the previous tile.
+fn tile_ranges(height: u32, tile_height: u32, overlap: u32) -> Vec<(u32, u32)> {
+    let mut tiles = Vec::new();
+    let mut top = 0;
+    loop {
+        let rows = tile_height.min(height - top);
+        tiles.push((top, rows));
+        if top + rows >= height {
+            return tiles;
+        }
+        top += tile_height - overlap;
+    }
+}
+
+/// Map `<|det|>` boxes from a tile's coordinate grid onto the full image.
+fn offset_tile_coordinates(markdown: &str, top: u32, rows: u32, height: u32) -> String {
+    let re_det = Regex::new(r"<\|det\|>(.*?)<\|/det\|>").unwrap();
+    let re_box = Regex::new(r"\[\s*([\d.]+)\s*,\s*([\d.]+)\s*,\s*([\d.]+)\s*,\s*([\d.]+)\s*\]").unwrap();
+    let to_full = |y: &str| {
+        let y: f32 = y.parse().unwrap_or(0.0);
+        ((y / COORDINATE_GRID * rows as f32 + top as f32) / height as f32 * COORDINATE_GRID).round()
+    };
+    re_det
+        .replace_all(markdown, |det: &regex::Captures| {
+            let boxes = re_box.replace_all(&det[1], |b: &regex::Captures| {
+                format!("[{}, {}, {}, {}]", &b[1], to_full(&b[2]), &b[3], to_full(&b[4]))
+            });
+            format!("<|det|>{}<|/det|>", boxes)
+        })
+        .into_owned()
+}
+
+/// Most lines a tile overlap is expected to repeat.
+const MAX_TILE_OVERLAP_LINES: usize = 20;
+
+/// Append a tile's text, dropping its leading lines when they repeat the end of the
+/// previous tile (the overlap region was read twice).
+fn join_tiles(previous: String, next: &str) -> String {
+    let key = |line: &str| {
+        clean_markdown_for_plain(line)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+    };
+    let previous_keys: Vec<String> = previous
+        .lines()
+        .map(key)
+        .filter(|k| !k.is_empty())
+        .collect();
+    let next_lines: Vec<&str> = next.lines().collect();
+    let next_keys: Vec<(usize, String)> = next_lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| (i, key(line)))
+        .filter(|(_, k)| !k.is_empty())
+        .collect();
+
+    let max_overlap = MAX_TILE_OVERLAP_LINES.min(previous_keys.len()).min(next_keys.len());
+    let skip = (1..=max_overlap)
+        .rev()
+        .find(|&k| {
+            previous_keys[previous_keys.len() - k..]
+                .iter()
+                .zip(&next_keys[..k])
+                .all(|(a, (_, b))| a == b)
+        })
+        .map_or(0, |k| next_keys[k - 1].0 + 1);
+
+    let rest = next_lines[skip..].join("\n");
+    let rest = rest.trim();
+    match (previous.trim().is_empty(), rest.is_empty()) {
+        (true, _) => rest.to_string(),
+        (false, true) => previous,
+        (false, false) => format!("{}\n\n{}", previous.trim_end(), rest),
+    }
+}
+
+/// OCR one image, retrying rotated copies when `--auto-orient-ocr` finds the result poor.
+async fn ocr_image_oriented(image_path: &Path, model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool, options: &OcrOptions) -> Result<ImageOcr> {
+    let result = ocr_image(image_path, model, custom_prompt, use_grounding_mode, use_coordinates, options).await?;
+    if !options.auto_orient_ocr || result.skipped {
+        return Ok(result);
+    }
+
+    let score = ocr_quality_score(&result.markdown);
+    if score >= ORIENTATION_SCORE_THRESHOLD {
+        return Ok(result);
+    }
+    println!("🔄 Low-quality OCR result (score {:.2}), retrying with rotated image", score);
+
+    let mut best = (score, 0, result);
+    for degrees in [90, 180, 270] {
+        let mut rotated = options.clone();
+        rotated.rotation = degrees;
+        // Rotating doesn't change what --min-quality measures; it was reported already
+        rotated.min_quality = None;
+        match ocr_image(image_path, model, custom_prompt, use_grounding_mode, use_coordinates, &rotated).await {
+            Ok(result) => {
+                let score = ocr_quality_score(&result.markdown);
+                println!("  {}°: score {:.2}", degrees, score);
+                if score > best.0 {
+                    best = (score, degrees, result);
+                }
+            }
+            Err(e) => eprintln!("  {}°: OCR failed: {:#}", degrees, e),
+        }
+        if best.0 >= ORIENTATION_SCORE_THRESHOLD {
+            break;
+        }
+    }
+
+    if best.1 != 0 {
+        println!("✓ Using OCR result rotated {}°", best.1);
+    }
+    Ok(best.2)
+}
+
+/// The input as a URL when it is an http(s) address rather than a file path.
+fn image_url(input: &Path) -> Option<&str> {
+    input
+        .to_str()
+        .filter(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+/// File name for a downloaded image, taken from the last segment of the URL path.
+fn url_filename(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.path_segments()?.next_back().map(str::to_string))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "image".to_string())
+}
+
+async fn fetch_image(url: &str, options: &OcrOptions) -> Result<Vec<u8>, OcrError> {
+    if options.offline {
+        return Err(OcrError::Offline { url: url.to_string() });
+    }
+    println!("🌐 Downloading {}", url);
+    let network = |source| OcrError::Network { url: url.to_string(), source };
+    let response = options.http_client()?.get(url).send().await.map_err(network)?;
+    let bytes = ensure_success(response).await?.bytes().await.map_err(network)?;
+    Ok(bytes.to_vec())
+}
+
+/// Results scoring below this trigger the `--auto-orient-ocr` retries.
+const ORIENTATION_SCORE_THRESHOLD: f32 = 0.6;
+/// Results with fewer letters than this are scored down proportionally.
+const ORIENTATION_MIN_CHARS: usize = 40;
+
+/// Rough OCR quality in 0..=1: the share of alphabetic characters, scaled down for
+/// very short results. Text read from an upside-down page is mostly symbols and digits.
+fn ocr_quality_score(markdown: &str) -> f32 {
+    let text = clean_markdown_for_plain(markdown);
+    let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if chars.is_empty() {
+        return 0.0;
+    }
+    let alphabetic = chars.iter().filter(|c| c.is_alphabetic()).count();
+    let ratio = alphabetic as f32 / chars.len() as f32;
+    ratio * (alphabetic as f32 / ORIENTATION_MIN_CHARS as f32).min(1.0)
+}
+
+/// Markdown read from one image, with the pixel size of the image the model saw.
+struct ImageOcr {
+    markdown: String,
+    /// After cropping, rotation, spread splitting and `--max-image-bytes` downscaling;
+    /// pixel boxes in the markdown are relative to this size
+    size: Option<(u32, u32)>,
+    /// Left out by `--min-quality skip` instead of being sent
+    skipped: bool,
+}
+
+/// OCR one image once, with the preprocessing from `options`.
+async fn ocr_image(image_path: &Path, model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool, options: &OcrOptions) -> Result<ImageOcr, OcrError> {
+    let url = image_url(image_path);
+    let filename = match url {
+        Some(url) => url_filename(url),
+        None => image_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("image")
+            .to_string(),
+    };
+
+    println!("Processing: {}", filename);
+
+    // Read (or download) and encode image to base64
+    let mut image_data = match url {
+        Some(url) => fetch_image(url, options).await.with_context(|| format!("{}: failed to download image", filename))?,
+        None => read_image_bytes(image_path).with_context(|| format!("{}: failed to read image {}", filename, image_path.display()))?,
+    };
+    // Downloaded images and zip entries only exist in memory, like preprocessed ones
+    let mut preprocessed = options.needs_preprocessing() || !image_path.is_file();
+    if options.needs_preprocessing() {
+        image_data = preprocess_image(&image_data, options)
+            .with_context(|| format!("{}: failed to preprocess image", filename))?;
+    }
+    // Judged before --max-image-bytes, whose downscaling is a deliberate trade-off
+    if !check_image_quality(&image_data, &filename, options).with_context(|| format!("{}: failed to check image quality", filename))? {
+        return Ok(ImageOcr { markdown: String::new(), size: None, skipped: true });
+    }
+    if let Some(limit) = options.max_image_bytes {
+        if let Some(recompressed) = fit_image_to_limit(&image_data, limit)
+            .with_context(|| format!("{}: image too large", filename))?
+        {
+            image_data = recompressed;
+            preprocessed = true;
+        }
+    }
+    let mut image_format = image::guess_format(&image_data).unwrap_or(image::ImageFormat::Png);
+    // Vision APIs take PNG, JPEG and WebP; anything else (e.g. TIFF pages) goes as PNG
+    if !matches!(image_format, image::ImageFormat::Png | image::ImageFormat::Jpeg | image::ImageFormat::WebP) {
+        let img = image::load_from_memory(&image_data).with_context(|| format!("{}: failed to decode image", filename))?;
+        let mut buffer = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut buffer), image::ImageFormat::Png)
+            .with_context(|| format!("{}: failed to convert image to PNG", filename))?;
+        image_data = buffer;
+        image_format = image::ImageFormat::Png;
+        preprocessed = true;
+    }
+    let size = image::ImageReader::new(std::io::Cursor::new(&image_data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    let base64_image = general_purpose::STANDARD.encode(&image_data);
+
+    // Detect if this is an Ollama model (doesn't contain "NexaAI" or "GGUF")
+    let is_ollama = !model.contains("NexaAI") && !model.contains("GGUF");
+    
+    // Detect if this is DeepSeek-OCR model (works best without extra instructions)
+    let is_deepseek = model.to_lowercase().contains("deepseek-ocr");
+    
+    // For DeepSeek models, ignore custom prompts
+    let effective_custom_prompt = if is_deepseek { None } else { custom_prompt };
+
+    // Build the base prompt text based on model type and grounding mode
+    let grounding = &options.grounding_token;
+    let base_prompt = if let Some(custom) = effective_custom_prompt {
+        // For custom prompts, include grounding tag only for NexaAI with grounding mode enabled
+        if is_ollama {
+            format!("{} {}", filename, custom)
+        } else if use_grounding_mode {
+            format!("{}\n{}{}", filename, grounding, custom)
+        } else {
+            format!("{} {}", filename, custom)
+        }
+    } else {
+        // Default prompts based on model type and grounding mode
+        if is_ollama {
+            if use_grounding_mode {
+                // Check if it's deepseek-ocr which supports grounding
+                if is_deepseek {
+                    format!("{}\n{}Convert the document to markdown.", filename, grounding)
+                } else {
+                    format!("{}\nConvert the document to markdown.", filename)
+                }
+            } else {
+                format!("{}\nExtract the text in the image.", filename)
+            }
+        } else if use_grounding_mode {
+            format!("{}\n{}Convert the document to markdown.", filename, grounding)
+        } else {
+            format!("{}\nExtract the text in the image.", filename)
+        }
+    };
+
+    // Add automatic instructions for Ollama models (BUT NOT DeepSeek)
+    let prompt_text = if is_ollama && !is_deepseek {
+
+        let mut enhanced = base_prompt;
+        enhanced.push_str("\n\nIMPORTANT INSTRUCTIONS:");
+        enhanced.push_str("\n- Return ONLY the OCR result. No thinking or explanations. Do not wrap the output in markdown code fences (```).");
+        enhanced.push_str("\n- Fix grammar mistakes when confident.");
+        // Coordinate instructions are not added for DeepSeek models, as they handle coordinates differently.
+        if use_coordinates {
+            enhanced.push_str("\n- Include coordinate information using the format: <|det|>[[x1,y1,x2,y2]]</|det|> followed by the text.");
+        }
+        enhanced
+    } else {
+        base_prompt
+    };
+
+    // --schema replaces the markdown instructions with the field description
+    let schema = options.schema.as_ref();
+    let prompt_text = match schema {
+        Some(schema) => schema_prompt(&filename, schema, effective_custom_prompt),
+        None => prompt_text,
+    };
+
+    // Debug: Print the full prompt
+    println!("=== OCR PROMPT ===");
+    println!("Model: {}", model);
+    println!("Use Coordinates: {}", use_coordinates);
+    println!("Prompt Text:");
+    println!("{}", prompt_text);
+    println!("==================");
+
+    // For DeepSeek-OCR on Ollama, use the CLI directly to ensure correct behavior
+    if is_deepseek && is_ollama {
+        println!("Using Ollama CLI for DeepSeek-OCR");
+        if options.temperature.is_some() || options.top_p.is_some() {
+            println!("⚠ --temperature/--top-p are not supported by the Ollama CLI path and will be ignored");
+        }
+        if options.refine {
+            println!("⚠ --refine is not supported by the Ollama CLI path and will be ignored");
+        }
+        if schema.is_some() {
+            return Err(anyhow::anyhow!("{}: --schema needs an API model; the Ollama CLI path only returns markdown", filename).into());
+        }
+        
+        // Construct the prompt exactly as requested: "/path/to/image\n<|grounding|>Convert..."
+        // Note: prompt_text already contains the filename/path at the start
+        // But we need to make sure we pass the absolute path to the image
+        // The CLI reads the image from disk, so hand it the preprocessed copy
+        let cli_image_path = if preprocessed {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            // Unique per call, so concurrent runs (serve, parallel batches) never share a file
+            static PREPROCESSED_COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let stem = Path::new(&filename).file_stem().and_then(|n| n.to_str()).unwrap_or("image");
+            let extension = image_format.extensions_str().first().copied().unwrap_or("png");
+            let temp_path = std::env::temp_dir().join(format!(
+                "ocr_preprocessed_{}_{}_{}.{}",
+                std::process::id(),
+                PREPROCESSED_COUNTER.fetch_add(1, Ordering::Relaxed),
+                stem,
+                extension
+            ));
+            fs::write(&temp_path, &image_data)
+                .with_context(|| format!("{}: failed to write preprocessed image", filename))?;
+            temp_path
+        } else {
+            image_path.to_path_buf()
+        };
+        let run_cli = || -> Result<std::process::Output> {
+            let abs_image_path = std::fs::canonicalize(&cli_image_path)
+                .with_context(|| format!("{}: failed to resolve image path", filename))?;
+            let cli_prompt = if use_grounding_mode {
+                 format!("{}\n{}Convert the document to markdown.", abs_image_path.display(), grounding)
+            } else {
+                 format!("{}\nExtract the text in the image.", abs_image_path.display())
+            };
+
+            println!("CLI Prompt: {}", cli_prompt);
+
+            std::process::Command::new("ollama")
+                .arg("run")
+                .arg(model)
+                .arg(&cli_prompt)
+                .output()
+                .with_context(|| format!("{}: failed to execute ollama run", filename))
+        };
+        let output = run_cli();
+        // Remove the preprocessed copy before any error is returned
+        if preprocessed {
+            let _ = fs::remove_file(&cli_image_path);
+        }
+        let output = output?;
+            
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!("{}: Ollama CLI error: {}", filename, stderr).into());
+        }
+        
+        let markdown = String::from_utf8_lossy(&output.stdout).to_string();
+        
+        // Save raw response to file for debugging
+        let raw_output_path = "/tmp/deepseek_raw_output.txt";
+        std::fs::write(raw_output_path, &markdown)
+            .with_context(|| format!("{}: failed to save raw OCR output", filename))?;
+        println!("=== RAW OCR OUTPUT SAVED ===");
+        println!("Saved to: {}", raw_output_path);
+        println!("Content length: {} chars", markdown.len());
+        println!("============================");
+
+        let markdown = options.postprocess(clean_markdown(&markdown))?;
+        return Ok(ImageOcr { markdown, size, skipped: false });
+    }
+
+    // Prepare OCR request for other models (API)
+    let request = OcrRequest {
+        model: model.to_string(),
+        messages: vec![Message {
+            role: "user".to_string(),
+            content: vec![
+                Content::Text {
+                    text: prompt_text,
+                },
+                Content::ImageUrl {
+                    image_url: ImageUrl {
+                        url: format!("data:{};base64,{}", image_format.to_mime_type(), base64_image),
+                    },
+                },
+            ],
+        }],
+        max_tokens: options.max_tokens,
+        stream: false,
+        temperature: options.temperature,
+        top_p: options.top_p,
+        response_format: schema.map(schema_response_format),
+    };
+
+    // Send request to OCR API
+    let api_url = options.api_url(model);
+    println!("Using API: {} with model: {}", api_url, model);
+    
+    if let Some(schema) = schema {
+        let markdown = extract_with_schema(api_url, &request, schema, options)
+            .await
+            .with_context(|| format!("{}: schema extraction failed", filename))?;
+        return Ok(ImageOcr { markdown, size, skipped: false });
+    }
+
+    let response = options
+        .send_ocr_request(api_url, &request)
+        .await
+        .with_context(|| format!("{}: failed to send OCR request to {}", filename, api_url))?;
+    let response = ensure_success(response).await.with_context(|| format!("{}: OCR request failed", filename))?;
+
+    let markdown = options
+        .read_ocr_response(response)
+        .await
+        .with_context(|| format!("{}: failed to parse OCR JSON response", filename))?;
+    let markdown = if options.refine {
+        refine_ocr(api_url, request, markdown, options)
+            .await
+            .with_context(|| format!("{}: refinement pass failed", filename))?
+    } else {
+        markdown
+    };
+        
+    // Save raw response to file for debugging
+    let raw_output_path = "/tmp/deepseek_raw_output.txt";
+    std::fs::write(raw_output_path, &markdown)
+        .with_context(|| format!("{}: failed to save raw OCR output", filename))?;
+    println!("=== RAW OCR OUTPUT SAVED ===");
+    println!("Saved to: {}", raw_output_path);
+    println!("Content length: {} chars", markdown.len());
+    println!("============================");
+    
+    let markdown = options.postprocess(clean_markdown(&markdown))?;
+    Ok(ImageOcr { markdown, size, skipped: false })
+}
+
+const REFINE_PROMPT: &str = "Review your transcription above against the image and correct obvious OCR mistakes: misread characters, split or merged words, wrong punctuation. Keep the layout, markup and any <|ref|>/<|det|> tags exactly as they are and do not add content. Reply with the corrected transcription only.";
+
+/// Second pass for `--refine`: continue the conversation with the first result as the
+/// assistant turn and ask the model to correct it. An empty reply keeps the first result.
+async fn refine_ocr(api_url: &str, mut request: OcrRequest, markdown: String, options: &OcrOptions) -> Result<String, OcrError> {
+    println!("🔁 Refining OCR result ({} chars)", markdown.len());
+    request.messages.push(Message {
+        role: "assistant".to_string(),
+        content: vec![Content::Text { text: markdown.clone() }],
+    });
+    request.messages.push(Message {
+        role: "user".to_string(),
+        content: vec![Content::Text { text: REFINE_PROMPT.to_string() }],
+    });
+
+    let response = ensure_success(options.send_ocr_request(api_url, &request).await?).await?;
+    let refined = options.read_ocr_response(response).await?;
+    if refined.trim().is_empty() {
+        println!("⚠ Refinement returned nothing, keeping the first result");
+        return Ok(markdown);
+    }
+    Ok(refined)
+}
+
+/// Read the `--schema` file while the arguments are parsed, so it is loaded once per run.
+fn parse_json_schema(path: &str) -> Result<serde_json::Value, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("failed to read schema {}: {}", path, e))?;
+    serde_json::from_str(&text).map_err(|e| format!("schema {} is not valid JSON: {}", path, e))
+}
+
+/// How often a reply that doesn't match the `--schema` is requested again.
+const SCHEMA_RETRIES: u32 = 2;
+
+fn schema_prompt(filename: &str, schema: &serde_json::Value, custom_prompt: Option<&str>) -> String {
+    let mut prompt = format!(
+        "{}\nExtract the information in the document as a JSON object that matches this JSON schema:\n{}\n\nReply with the JSON object only. Use null for fields that are not in the document.",
+        filename,
+        serde_json::to_string_pretty(schema).unwrap_or_default()
+    );
+    if let Some(custom) = custom_prompt {
+        prompt.push('\n');
+        prompt.push_str(custom);
+    }
+    prompt
+}
+
+fn schema_response_format(schema: &serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "type": "json_schema",
+        "json_schema": { "name": "extraction", "schema": schema },
+    })
+}
+
+/// Send a `--schema` request until the reply is JSON matching the schema. Backends
+/// without structured output may wrap the JSON in prose or code fences, so the
+/// outermost object is taken from the reply. Returns the pretty-printed JSON.
+async fn extract_with_schema(api_url: &str, request: &OcrRequest, schema: &serde_json::Value, options: &OcrOptions) -> Result<String> {
+    let mut attempt = 0;
+    loop {
+        let response = ensure_success(options.send_ocr_request(api_url, request).await?).await?;
+        let reply = options.read_ocr_response(response).await?;
+        let start = reply.find('{').unwrap_or(0);
+        let end = reply.rfind('}').map_or(reply.len(), |i| i + 1);
+        let problem = match serde_json::from_str::<serde_json::Value>(reply.get(start..end).unwrap_or_default()) {
+            Ok(value) => match validate_json(&value, schema, "$") {
+                Ok(()) => return Ok(serde_json::to_string_pretty(&value)?),
+                Err(problem) => problem,
+            },
+            Err(e) => format!("reply is not JSON: {}", e),
+        };
+        if attempt >= SCHEMA_RETRIES {
+            anyhow::bail!("{} (after {} attempts)", problem, attempt + 1);
+        }
+        attempt += 1;
+        println!("⚠ Reply doesn't match the schema ({}), retrying ({}/{})", problem, attempt, SCHEMA_RETRIES);
     }
-    
-    println!("Splitting PDF: {} pages selected", page_numbers.len());
-    
-    // Try qpdf first (better quality preservation)
-    let qpdf_result = Command::new("qpdf")
-        .arg("--empty")
-        .arg("--pages")
-        .arg(input)
-        .arg(pages_str)
-        .arg("--")
-        .arg(output)
-        .output();
-    
-    if let Ok(output_result) = qpdf_result {
-        if output_result.status.success() {
-            println!("✓ PDF split successfully with qpdf");
-            return Ok(());
+}
+
+/// Check `value` against the commonly used part of JSON Schema: `type`, `enum`,
+/// `properties`, `required`, `additionalProperties: false` and `items`. A field may
+/// be null whatever its type, as the prompt asks for fields missing from the document.
+fn validate_json(value: &serde_json::Value, schema: &serde_json::Value, path: &str) -> Result<(), String> {
+    use serde_json::Value;
+
+    let type_matches = |name: &str| match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    };
+    match schema.get("type") {
+        Some(Value::String(name)) if !type_matches(name) => {
+            return Err(format!("{} should be of type {}", path, name));
         }
+        Some(Value::Array(names)) if !names.iter().filter_map(Value::as_str).any(type_matches) => {
+            return Err(format!("{} has none of the allowed types", path));
+        }
+        _ => {}
     }
-    
-    // Fallback to pdftk
-    // Build pdftk command: pdftk input.pdf cat 1 3 2 output output.pdf
-    let pdftk_result = Command::new("pdftk")
-        .arg(input)
-        .arg("cat")
-        .args(page_numbers.iter().map(|n| n.to_string()))
-        .arg("output")
-        .arg(output)
-        .output();
-    
-    if let Ok(output_result) = pdftk_result {
-        if output_result.status.success() {
-            println!("✓ PDF split successfully with pdftk");
-            return Ok(());
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            return Err(format!("{} is not one of the allowed values", path));
         }
     }
-    
-    anyhow::bail!("PDF split requires qpdf or pdftk to be installed. Install with: brew install qpdf or brew install pdftk-java")
+
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for name in schema.get("required").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                return Err(format!("{} is missing required field \"{}\"", path, name));
+            }
+        }
+        for (name, field) in object {
+            match properties.and_then(|p| p.get(name)) {
+                Some(_) if field.is_null() => {}
+                Some(field_schema) => validate_json(field, field_schema, &format!("{}.{}", path, name))?,
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    return Err(format!("{} has unexpected field \"{}\"", path, name));
+                }
+                None => {}
+            }
+        }
+    }
+    if let (Some(items), Some(item_schema)) = (value.as_array(), schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            validate_json(item, item_schema, &format!("{}[{}]", path, i))?;
+        }
+    }
+    Ok(())
 }
 
-async fn process_image(image_path: &Path, model: &str, custom_prompt: Option<&str>, use_coordinates: bool, use_grounding_mode: bool) -> Result<String> {
-    // Pass the grounding mode flag correctly
-    process_image_with_mode(image_path, model, custom_prompt, use_grounding_mode, use_coordinates).await
+/// Word list for `--spellfix`, loaded once per run.
+static SPELL_DICTIONARY: std::sync::OnceLock<HashSet<String>> = std::sync::OnceLock::new();
+
+fn load_spell_dictionary(path: &Path) -> Result<&'static HashSet<String>> {
+    if let Some(words) = SPELL_DICTIONARY.get() {
+        return Ok(words);
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read spellfix dictionary {}", path.display()))?;
+    let words = content
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|word| word.to_lowercase())
+        .collect();
+    Ok(SPELL_DICTIONARY.get_or_init(|| words))
 }
 
-async fn process_image_with_mode(image_path: &Path, model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool) -> Result<String> {
-    let filename = image_path
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("image");
+/// Character sequences OCR commonly confuses, tried before general single edits.
+const OCR_CONFUSIONS: &[(&str, &str)] = &[
+    ("rn", "m"), ("m", "rn"), ("cl", "d"), ("d", "cl"), ("vv", "w"), ("w", "vv"),
+    ("li", "h"), ("ii", "u"), ("c", "e"), ("e", "c"), ("l", "i"), ("i", "l"),
+];
+
+/// Shortest word `--spellfix` will try to correct.
+const SPELLFIX_MIN_LEN: usize = 3;
+
+/// Correct lowercase words missing from `dictionary` when exactly one OCR confusion,
+/// or failing that exactly one single-character edit, turns them into a known word.
+/// Capitalized words (likely proper nouns), words touching digits and markup inside
+/// `<...>` are left alone. Returns the text and the number of corrections.
+fn spellfix(text: &str, dictionary: &HashSet<String>) -> (String, usize) {
+    let re_token = Regex::new(r"<[^>]*>|\b[a-z]+\b").unwrap();
+    let mut corrections = 0;
+    let fixed = re_token.replace_all(text, |cap: &regex::Captures| {
+        let token = &cap[0];
+        if token.starts_with('<') || token.len() < SPELLFIX_MIN_LEN || dictionary.contains(token) {
+            return token.to_string();
+        }
+        match spell_correction(token, dictionary) {
+            Some(correction) => {
+                corrections += 1;
+                correction
+            }
+            None => token.to_string(),
+        }
+    });
+    (fixed.into_owned(), corrections)
+}
 
-    println!("Processing: {}", filename);
+fn spell_correction(word: &str, dictionary: &HashSet<String>) -> Option<String> {
+    let unique = |candidates: HashSet<String>| -> Option<String> {
+        let mut known = candidates.into_iter().filter(|c| dictionary.contains(c));
+        match (known.next(), known.next()) {
+            (Some(word), None) => Some(word),
+            _ => None,
+        }
+    };
 
-    // Read and encode image to base64
-    let image_data =
-        fs::read(image_path).context(format!("Failed to read image: {}", image_path.display()))?;
-    let base64_image = general_purpose::STANDARD.encode(&image_data);
+    let mut confusions = HashSet::new();
+    for (from, to) in OCR_CONFUSIONS {
+        for (index, _) in word.match_indices(from) {
+            confusions.insert(format!("{}{}{}", &word[..index], to, &word[index + from.len()..]));
+        }
+    }
+    if let Some(correction) = unique(confusions) {
+        return Some(correction);
+    }
 
-    // Detect if this is an Ollama model (doesn't contain "NexaAI" or "GGUF")
-    let is_ollama = !model.contains("NexaAI") && !model.contains("GGUF");
-    
-    // Detect if this is DeepSeek-OCR model (works best without extra instructions)
-    let is_deepseek = model.to_lowercase().contains("deepseek-ocr");
-    
-    // For DeepSeek models, ignore custom prompts
-    let effective_custom_prompt = if is_deepseek { None } else { custom_prompt };
+    let chars: Vec<char> = word.chars().collect();
+    let mut edits = HashSet::new();
+    for i in 0..=chars.len() {
+        for letter in 'a'..='z' {
+            let mut inserted = chars.clone();
+            inserted.insert(i, letter);
+            edits.insert(inserted.iter().collect());
+            if i < chars.len() {
+                let mut substituted = chars.clone();
+                substituted[i] = letter;
+                edits.insert(substituted.iter().collect());
+            }
+        }
+        if i < chars.len() {
+            let mut deleted = chars.clone();
+            deleted.remove(i);
+            edits.insert(deleted.iter().collect());
+        }
+        if i + 1 < chars.len() {
+            let mut swapped = chars.clone();
+            swapped.swap(i, i + 1);
+            edits.insert(swapped.iter().collect());
+        }
+    }
+    edits.remove(word);
+    unique(edits)
+}
 
-    // Build the base prompt text based on model type and grounding mode
-    let base_prompt = if let Some(custom) = effective_custom_prompt {
-        // For custom prompts, include grounding tag only for NexaAI with grounding mode enabled
-        if is_ollama {
-            format!("{} {}", filename, custom)
-        } else if use_grounding_mode {
-            format!("{}\n<|grounding|>{}", filename, custom)
+/// Apply the requested image transformations and return the result encoded as PNG.
+fn preprocess_image(image_data: &[u8], options: &OcrOptions) -> Result<Vec<u8>, OcrError> {
+    let mut img = match options.frame {
+        Some(index) => load_frame(image_data, index)?,
+        None => image::load_from_memory(image_data)?,
+    };
+
+    if let Some((left, width)) = options.spread_half {
+        img = img.crop_imm(left, 0, width, img.height());
+    }
+
+    if let Some([x, y, w, h]) = options.crop_region {
+        let (width, height) = (img.width(), img.height());
+        if x.checked_add(w).is_none_or(|right| right > width) || y.checked_add(h).is_none_or(|bottom| bottom > height) {
+            return Err(OcrError::RegionOutOfBounds { x, y, w, h, width, height });
+        }
+        img = img.crop_imm(x, y, w, h);
+        println!("✂ Cropped to region {},{} {}x{}", x, y, w, h);
+    }
+
+    if let Some((top, height)) = options.tile {
+        img = img.crop_imm(0, top, img.width(), height);
+    }
+
+    img = match options.rotation {
+        90 => img.rotate90(),
+        180 => img.rotate180(),
+        270 => img.rotate270(),
+        _ => img,
+    };
+
+    if options.auto_crop {
+        let (width, height) = (img.width(), img.height());
+        img = auto_crop_borders(img, options.crop_background, options.crop_tolerance);
+        println!("✂ Auto-crop: {}x{} -> {}x{}", width, height, img.width(), img.height());
+    }
+
+    if options.binarize {
+        img = image::DynamicImage::ImageLuma8(binarize_sauvola(&img.to_luma8()));
+        println!("◐ Binarized image");
+    }
+
+    if options.dither {
+        let mut gray = img.to_luma8();
+        image::imageops::dither(&mut gray, &image::imageops::colorops::BiLevel);
+        img = image::DynamicImage::ImageLuma8(gray);
+        println!("◐ Dithered image to black and white");
+    }
+
+    let mut buffer = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut buffer), image::ImageFormat::Png)?;
+    Ok(buffer)
+}
+
+/// Length of `len` bytes once base64 encoded.
+fn base64_len(len: usize) -> usize {
+    len.div_ceil(3) * 4
+}
+
+/// Shrink an image until its base64 payload is at most `limit` bytes by
+/// recompressing as JPEG and downscaling step by step.
+/// Returns `None` when the image already fits.
+fn fit_image_to_limit(image_data: &[u8], limit: usize) -> Result<Option<Vec<u8>>> {
+    const MAX_ATTEMPTS: usize = 10;
+
+    let size = base64_len(image_data.len());
+    if size <= limit {
+        return Ok(None);
+    }
+    println!("🗜 Image payload is {} bytes, over the {} byte limit", size, limit);
+
+    // JPEG has no alpha channel
+    let img = image::DynamicImage::ImageRgb8(
+        image::load_from_memory(image_data).context("Failed to decode image")?.to_rgb8(),
+    );
+    let mut scale = 1.0f32;
+    let mut quality = 85u8;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let width = ((img.width() as f32 * scale) as u32).max(1);
+        let height = ((img.height() as f32 * scale) as u32).max(1);
+        let resized = if scale < 1.0 {
+            img.resize_exact(width, height, image::imageops::FilterType::Triangle)
         } else {
-            format!("{} {}", filename, custom)
+            img.clone()
+        };
+
+        let mut buffer = Vec::new();
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
+        resized.write_with_encoder(encoder).context("Failed to encode image as JPEG")?;
+        let size = base64_len(buffer.len());
+        println!("  attempt {}/{}: {}x{} JPEG q{} -> {} bytes", attempt, MAX_ATTEMPTS, width, height, quality, size);
+        if size <= limit {
+            return Ok(Some(buffer));
+        }
+
+        // Lower the quality first, then keep shrinking the image
+        if quality > 60 {
+            quality -= 15;
+        } else {
+            scale *= 0.75;
+        }
+    }
+    anyhow::bail!(
+        "could not get the image under --max-image-bytes {} after {} attempts",
+        limit,
+        MAX_ATTEMPTS
+    )
+}
+
+/// Crop away a uniform border. The border color is `background`, or the most
+/// common of the four corner colors when not given. A small margin is kept
+/// around the detected content.
+fn auto_crop_borders(img: image::DynamicImage, background: Option<[u8; 3]>, tolerance: u8) -> image::DynamicImage {
+    let rgb = img.to_rgb8();
+    let (width, height) = rgb.dimensions();
+    if width == 0 || height == 0 {
+        return img;
+    }
+
+    let background = background.unwrap_or_else(|| {
+        let corners = [
+            rgb.get_pixel(0, 0).0,
+            rgb.get_pixel(width - 1, 0).0,
+            rgb.get_pixel(0, height - 1).0,
+            rgb.get_pixel(width - 1, height - 1).0,
+        ];
+        *corners
+            .iter()
+            .max_by_key(|c| corners.iter().filter(|o| *o == *c).count())
+            .unwrap_or(&corners[0])
+    });
+
+    let is_content = |x: u32, y: u32| {
+        let p = rgb.get_pixel(x, y).0;
+        p.iter()
+            .zip(background.iter())
+            .any(|(a, b)| a.abs_diff(*b) > tolerance)
+    };
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (width, height, 0u32, 0u32);
+    for y in 0..height {
+        for x in 0..width {
+            if is_content(x, y) {
+                min_x = min_x.min(x);
+                max_x = max_x.max(x);
+                min_y = min_y.min(y);
+                max_y = max_y.max(y);
+            }
+        }
+    }
+
+    if min_x > max_x || min_y > max_y {
+        // Nothing but background: leave the image alone
+        return img;
+    }
+
+    let margin = (width.min(height) / 100).max(2);
+    let left = min_x.saturating_sub(margin);
+    let top = min_y.saturating_sub(margin);
+    let right = (max_x + margin).min(width - 1);
+    let bottom = (max_y + margin).min(height - 1);
+    img.crop_imm(left, top, right - left + 1, bottom - top + 1)
+}
+
+/// Sauvola adaptive threshold: each pixel is compared against a threshold
+/// derived from the mean and standard deviation of its neighbourhood, so
+/// uneven lighting and faint strokes still come out as clean black text.
+fn binarize_sauvola(gray: &image::GrayImage) -> image::GrayImage {
+    const K: f64 = 0.2;
+    const R: f64 = 128.0;
+
+    let (width, height) = gray.dimensions();
+    let (w, h) = (width as usize, height as usize);
+    let radius = ((width.min(height) / 40).max(7)) as usize;
+
+    // Integral images of values and squared values, padded with a zero row/column
+    let mut sum = vec![0f64; (w + 1) * (h + 1)];
+    let mut sq_sum = vec![0f64; (w + 1) * (h + 1)];
+    for y in 0..h {
+        let mut row = 0f64;
+        let mut row_sq = 0f64;
+        for x in 0..w {
+            let v = gray.get_pixel(x as u32, y as u32).0[0] as f64;
+            row += v;
+            row_sq += v * v;
+            let i = (y + 1) * (w + 1) + x + 1;
+            sum[i] = sum[i - (w + 1)] + row;
+            sq_sum[i] = sq_sum[i - (w + 1)] + row_sq;
+        }
+    }
+    let area = |table: &[f64], x0: usize, y0: usize, x1: usize, y1: usize| {
+        table[y1 * (w + 1) + x1] - table[y0 * (w + 1) + x1] - table[y1 * (w + 1) + x0] + table[y0 * (w + 1) + x0]
+    };
+
+    image::GrayImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as usize, y as usize);
+        let x0 = x.saturating_sub(radius);
+        let y0 = y.saturating_sub(radius);
+        let x1 = (x + radius + 1).min(w);
+        let y1 = (y + radius + 1).min(h);
+        let n = ((x1 - x0) * (y1 - y0)) as f64;
+
+        let mean = area(&sum, x0, y0, x1, y1) / n;
+        let variance = (area(&sq_sum, x0, y0, x1, y1) / n - mean * mean).max(0.0);
+        let threshold = mean * (1.0 + K * (variance.sqrt() / R - 1.0));
+
+        let v = gray.get_pixel(x as u32, y as u32).0[0] as f64;
+        image::Luma([if v > threshold { 255 } else { 0 }])
+    })
+}
+
+/// OCR result for a single image of a directory batch.
+struct PageOutput {
+    /// Position of the page in the sorted input list
+    index: usize,
+    source: PathBuf,
+    /// Frame and/or spread half of the source this page was read from
+    part: ImagePart,
+    markdown: String,
+    /// Pixel size of the image as sent to the model (crop, rotation, spread half and
+    /// downscaling applied); pixel boxes are relative to it and it picks the PDF page orientation
+    dimensions: Option<(u32, u32)>,
+    /// ISO 639-1 code from `--detect-language`
+    language: Option<String>,
+    /// Model of the `--model` chain that answered; `None` for failed or non-OCR pages
+    model: Option<String>,
+}
+
+/// Distinct models that answered the pages, in order of first use, for the front matter.
+fn answering_models(pages: &[PageOutput]) -> String {
+    let mut models: Vec<&str> = Vec::new();
+    for model in pages.iter().filter_map(|page| page.model.as_deref()) {
+        if !models.contains(&model) {
+            models.push(model);
+        }
+    }
+    models.join(", ")
+}
+
+/// Pages with fewer words than this are too short to guess the language from.
+const MIN_LANGUAGE_WORDS: usize = 5;
+
+/// Frequent short words of the Latin-script languages `detect_language` tells apart.
+const LANGUAGE_STOPWORDS: &[(&str, &[&str])] = &[
+    ("en", &["the", "and", "of", "to", "is", "in", "that", "for", "with", "this", "are", "was", "be", "on", "not"]),
+    ("de", &["der", "die", "und", "das", "ist", "nicht", "mit", "den", "ein", "eine", "zu", "auf", "für", "sich", "auch"]),
+    ("fr", &["le", "la", "les", "et", "des", "est", "une", "du", "dans", "pour", "que", "pas", "sur", "avec", "qui"]),
+    ("es", &["el", "la", "los", "las", "y", "que", "del", "en", "es", "por", "una", "para", "con", "se", "su"]),
+    ("it", &["il", "di", "che", "e", "la", "per", "non", "una", "della", "sono", "con", "del", "gli", "anche", "nel"]),
+    ("pt", &["o", "os", "as", "e", "que", "do", "da", "em", "um", "uma", "para", "com", "não", "no", "dos"]),
+    ("nl", &["de", "het", "een", "en", "van", "is", "dat", "niet", "op", "te", "zijn", "met", "voor", "ook", "wordt"]),
+    ("sv", &["och", "att", "det", "som", "en", "är", "av", "för", "med", "till", "den", "inte", "har", "på", "om"]),
+    ("pl", &["i", "w", "nie", "na", "się", "jest", "że", "do", "z", "to", "od", "jak", "dla", "oraz", "przez"]),
+];
+
+/// Guess the language of a page as an ISO 639-1 code: by script for non-Latin
+/// text, by counting frequent words for Latin text. `None` when unsure.
+fn detect_language(text: &str) -> Option<&'static str> {
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.is_empty() {
+        return None;
+    }
+    let share = |range: &dyn Fn(char) -> bool| letters.iter().filter(|&&c| range(c)).count() as f32 / letters.len() as f32;
+    let scripts: [(&str, &dyn Fn(char) -> bool); 9] = [
+        ("ja", &|c| ('\u{3040}'..='\u{30ff}').contains(&c)),
+        ("ko", &|c| ('\u{ac00}'..='\u{d7af}').contains(&c) || ('\u{1100}'..='\u{11ff}').contains(&c)),
+        ("zh", &|c| ('\u{4e00}'..='\u{9fff}').contains(&c)),
+        ("ru", &|c| ('\u{0400}'..='\u{04ff}').contains(&c)),
+        ("el", &|c| ('\u{0370}'..='\u{03ff}').contains(&c)),
+        ("ar", &|c| ('\u{0600}'..='\u{06ff}').contains(&c)),
+        ("he", &|c| ('\u{0590}'..='\u{05ff}').contains(&c)),
+        ("th", &|c| ('\u{0e00}'..='\u{0e7f}').contains(&c)),
+        ("hi", &|c| ('\u{0900}'..='\u{097f}').contains(&c)),
+    ];
+    for (code, in_script) in scripts {
+        // Japanese mixes kana with Han characters, so any real share of kana decides it
+        let threshold = if code == "ja" { 0.1 } else { 0.5 };
+        if share(in_script) >= threshold {
+            // Ukrainian letters absent from Russian
+            if code == "ru" && text.contains(['і', 'ї', 'є', 'ґ']) {
+                return Some("uk");
+            }
+            return Some(code);
         }
+    }
+
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.len() < MIN_LANGUAGE_WORDS {
+        return None;
+    }
+    let mut scores: Vec<(&str, usize)> = LANGUAGE_STOPWORDS
+        .iter()
+        .map(|(code, stopwords)| (*code, words.iter().filter(|w| stopwords.contains(&w.as_str())).count()))
+        .collect();
+    scores.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+    match scores.as_slice() {
+        [(code, best), (_, second), ..] if *best >= 2 && *best > *second => Some(code),
+        _ => None,
+    }
+}
+
+fn is_supported_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|s| s.to_str())
+        .map(|ext| matches!(ext.to_lowercase().as_str(), "png" | "jpg" | "jpeg" | "webp" | "gif" | "tif" | "tiff"))
+        .unwrap_or(false)
+}
+
+fn is_glob_pattern(input: &Path) -> bool {
+    input
+        .to_str()
+        .map(|s| s.contains(['*', '?', '[']))
+        .unwrap_or(false)
+}
+
+/// Resolve the `--input` of a directory batch into a sorted list of image files.
+/// Accepts a directory (non-recursive) or a glob pattern such as `scans/page-*.png`;
+/// either way only files with a supported image extension are kept.
+fn collect_image_files(input: &Path) -> Result<Vec<PathBuf>> {
+    let mut image_files: Vec<PathBuf> = if is_zip_archive(input) {
+        zip_image_entries(input)?
+    } else if !input.is_dir() && is_glob_pattern(input) {
+        let pattern = input.to_str().unwrap_or_default();
+        glob::glob(pattern)
+            .with_context(|| format!("Invalid glob pattern: {}", pattern))?
+            .filter_map(|entry| entry.ok())
+            .filter(|path| path.is_file() && is_supported_image(path))
+            .collect()
     } else {
-        // Default prompts based on model type and grounding mode
-        if is_ollama {
-            if use_grounding_mode {
-                // Check if it's deepseek-ocr which supports grounding
-                if is_deepseek {
-                    format!("{}\n<|grounding|>Convert the document to markdown.", filename)
-                } else {
-                    format!("{}\nConvert the document to markdown.", filename)
+        WalkDir::new(input)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter(|e| is_supported_image(e.path()))
+            .map(|e| e.path().to_path_buf())
+            .collect()
+    };
+
+    image_files.sort();
+    Ok(image_files)
+}
+
+fn is_zip_archive(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .and_then(|s| s.to_str())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Image entries of a zip archive as `<archive>/<entry name>` paths, which
+/// `read_image_bytes` resolves back into the archive.
+fn zip_image_entries(archive_path: &Path) -> Result<Vec<PathBuf>> {
+    let file = fs::File::open(archive_path)
+        .with_context(|| format!("Failed to open archive: {}", archive_path.display()))?;
+    let archive = zip::ZipArchive::new(file)
+        .with_context(|| format!("Invalid zip archive: {}", archive_path.display()))?;
+    Ok(archive
+        .file_names()
+        // Skip macOS resource forks that ride along in archives made by Finder
+        .filter(|name| !name.starts_with("__MACOSX/") && !name.ends_with('/'))
+        .filter(|name| is_supported_image(Path::new(name)))
+        .map(|name| archive_path.join(name))
+        .collect())
+}
+
+/// Read an image file, or an entry inside a zip archive addressed as `<archive>/<entry name>`.
+fn read_image_bytes(path: &Path) -> Result<Vec<u8>> {
+    if path.is_file() {
+        return fs::read(path).with_context(|| format!("Failed to read {}", path.display()));
+    }
+    let Some(archive_path) = path.ancestors().skip(1).find(|p| is_zip_archive(p)) else {
+        return fs::read(path).with_context(|| format!("Failed to read {}", path.display()));
+    };
+    let entry_name = path
+        .strip_prefix(archive_path)?
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let file = fs::File::open(archive_path)
+        .with_context(|| format!("Failed to open archive: {}", archive_path.display()))?;
+    let mut archive = zip::ZipArchive::new(file)?;
+    let mut entry = archive
+        .by_name(&entry_name)
+        .with_context(|| format!("{} not found in {}", entry_name, archive_path.display()))?;
+    let mut bytes = Vec::new();
+    std::io::Read::read_to_end(&mut entry, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Read a `--file-list` manifest: one image path per line, kept in the given order;
+/// relative paths are resolved against the manifest's directory. Blank lines and
+/// lines starting with `#` are ignored.
+fn read_file_list(list_path: &Path) -> Result<Vec<PathBuf>> {
+    let text = fs::read_to_string(list_path)
+        .with_context(|| format!("Failed to read file list: {}", list_path.display()))?;
+    let mut image_files = Vec::new();
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Relative entries are relative to the list file, not the working directory
+        let path = list_path.parent().unwrap_or(Path::new("")).join(line);
+        if !path.is_file() {
+            anyhow::bail!("{}:{}: image not found: {}", list_path.display(), line_no + 1, path.display());
+        }
+        image_files.push(path);
+    }
+    Ok(image_files)
+}
+
+/// OCR a directory batch. The `--report` is written however the batch ends, with the
+/// error that stopped it if it did not finish.
+#[allow(clippy::too_many_arguments)]
+async fn process_directory(image_files: &[PathBuf], model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool, batch_options: &BatchOptions, options: &OcrOptions, stream: Option<&mut PdfStream<'_>>) -> Result<BatchResult, OcrError> {
+    let started = std::time::Instant::now();
+    let tokens_at_start = tokens_used();
+    let mut report = BatchReport::default();
+    let result = process_directory_pages(image_files, model, custom_prompt, use_grounding_mode, use_coordinates, batch_options, options, stream, &mut report).await;
+
+    if let Some(path) = &batch_options.report {
+        let count = |status| report.pages.iter().filter(|page| page.status == status).count();
+        report.succeeded = count(PageStatus::Ok);
+        report.failed = count(PageStatus::Failed);
+        report.skipped = count(PageStatus::Skipped) + count(PageStatus::Blank);
+        report.total_tokens = tokens_used() - tokens_at_start;
+        report.elapsed_seconds = started.elapsed().as_secs_f64();
+        report.error = result.as_ref().err().map(|e| format!("{:#}", e));
+        let json = serde_json::to_string_pretty(&report).context("Failed to serialize report")?;
+        fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))?;
+        println!("📋 Run report saved to: {}", path.display());
+    }
+    Ok(result?)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_directory_pages(image_files: &[PathBuf], model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool, batch_options: &BatchOptions, options: &OcrOptions, mut stream: Option<&mut PdfStream<'_>>, report: &mut BatchReport) -> Result<BatchResult> {
+    let sampled: Vec<(usize, &PathBuf)> = image_files.iter().enumerate().step_by(batch_options.sample as usize).collect();
+    let total = sampled.len();
+    let mut pages = Vec::with_capacity(total);
+    let mut skipped = Vec::new();
+    let mut failed = Vec::new();
+    let mut failed_pages = Vec::new();
+    let mut blank = Vec::new();
+    let mut last_language = batch_options.language.clone();
+
+    if batch_options.sample > 1 {
+        println!("🎯 Sampling every {} of {} images", ordinal(batch_options.sample), image_files.len());
+    }
+    println!("📊 Processing {} images", total);
+    println!("─────────────────────────────────────────");
+
+    // `i` stays the position in the full page list so sampled pages keep their real index;
+    // images left out by --sample are never opened and count as one page each
+    let (mut next_page, mut next_image) = (0, 0);
+    for (current, (image_index, image_path)) in sampled.into_iter().enumerate() {
+        let current = current + 1;
+        let percentage = (current as f32 / total as f32 * 100.0) as u32;
+        next_page += image_index - next_image;
+        next_image = image_index + 1;
+
+        // Frames and spread halves are found as each image comes up; an unreadable
+        // image stays one page and its OCR reports the error
+        let (frames, parts) = image_parts(image_path, options).unwrap_or_else(|_| (1, vec![ImagePart::default()]));
+        if frames > 1 {
+            println!("🎞 {}: {} frames, each OCR'd as a page", image_path.display(), frames);
+        }
+        report.total_pages += parts.len();
+        let first_page = next_page;
+        next_page += parts.len();
+
+        for (i, part) in (first_page..).zip(parts) {
+            // Simple per-image progress log (no animation)
+            let part_label = match part.label() {
+                label if label.is_empty() => label,
+                label => format!(" ({})", label),
+            };
+            println!("[{}/{}] {}% | Processing: {}{}", current, total, percentage, image_path.display(), part_label);
+
+            let page_started = std::time::Instant::now();
+            let page_tokens = tokens_used();
+            let result = process_image_with_fallback(image_path, model, custom_prompt, use_grounding_mode, use_coordinates, &part.options(options)).await;
+            let mut report_page = |status| {
+                report.pages.push(PageReport {
+                    page: i + 1,
+                    source: image_path.display().to_string(),
+                    frame: part.frame.map(|index| index + 1),
+                    half: part.side(),
+                    status,
+                    seconds: page_started.elapsed().as_secs_f64(),
+                    tokens: tokens_used() - page_tokens,
+                })
+            };
+            let (ImageOcr { markdown, size, .. }, answered) = match result {
+                Ok(result) => result,
+                Err(e) if batch_options.continue_on_error => {
+                    report_page(PageStatus::Failed);
+                    let filename = image_path
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .unwrap_or("image");
+                    eprintln!("✗ OCR failed for {}{}: {:#}", image_path.display(), part_label, e);
+                    failed.push(image_path.clone());
+                    failed_pages.push(i);
+                    pages.push(PageOutput {
+                        index: i,
+                        source: image_path.clone(),
+                        part,
+                        markdown: format!("<!-- OCR FAILED: {} -->", filename),
+                        dimensions: part.dimensions(image_path),
+                        language: None,
+                        model: None,
+                    });
+                    if let Some(stream) = stream.as_deref_mut() {
+                        stream.add_page(&pages[pages.len() - 1])?;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    report_page(PageStatus::Failed);
+                    return Err(e);
                 }
+            };
+            // With --skip-blank an empty response is a blank page, not an incomplete one
+            if batch_options.skip_blank {
+                let text_len = clean_markdown_for_plain(&markdown).chars().filter(|c| !c.is_whitespace()).count();
+                if text_len < batch_options.blank_threshold || markdown.trim().is_empty() {
+                    println!("⏭ Blank page skipped: {}{} ({} chars)", image_path.display(), part_label, text_len);
+                    report_page(PageStatus::Blank);
+                    blank.push(image_path.clone());
+                    continue;
+                }
+            }
+            if markdown.trim().is_empty() {
+                println!("⚠ No text returned for {}{}, skipping", image_path.display(), part_label);
+                report_page(PageStatus::Skipped);
+                skipped.push(image_path.clone());
+                continue;
+            }
+            report_page(PageStatus::Ok);
+            let language = if batch_options.detect_language {
+                // Undecided pages take the language of the page before, or --language
+                match detect_language(&clean_markdown_for_plain(&markdown)) {
+                    Some(language) => {
+                        println!("🌐 Language: {}", language);
+                        last_language = Some(language.to_string());
+                    }
+                    None => match &last_language {
+                        Some(language) => println!("🌐 Language: {} (undecided, carried over)", language),
+                        None => println!("🌐 Language: unknown"),
+                    },
+                }
+                last_language.clone()
             } else {
-                format!("{}\nExtract the text in the image.", filename)
+                None
+            };
+            pages.push(PageOutput {
+                index: i,
+                source: image_path.clone(),
+                part,
+                markdown,
+                dimensions: size.or_else(|| part.dimensions(image_path)),
+                language,
+                model: Some(answered.to_string()),
+            });
+            if let Some(stream) = stream.as_deref_mut() {
+                stream.add_page(&pages[pages.len() - 1])?;
             }
-        } else if use_grounding_mode {
-            format!("{}\n<|grounding|>Convert the document to markdown.", filename)
-        } else {
-            format!("{}\nExtract the text in the image.", filename)
         }
-    };
+    }
+
+    if skipped.is_empty() && failed.is_empty() {
+        println!("\n✓ All images processed successfully!");
+    } else {
+        println!(
+            "\n⚠ Processed {} of {} pages ({} skipped, {} failed)",
+            report.total_pages - skipped.len() - failed.len(),
+            report.total_pages,
+            skipped.len(),
+            failed.len()
+        );
+        for path in &failed {
+            println!("  ✗ {}", path.display());
+        }
+    }
+    if !blank.is_empty() {
+        println!("⏭ {} blank page(s) left out of the output", blank.len());
+    }
+
+    if let Some(percent) = batch_options.verify_sample {
+        let sampled: Vec<&PageOutput> = pages.iter().filter(|p| !failed_pages.contains(&p.index)).collect();
+        verify_pages(&sampled, percent, &batch_options.verify_report, model, custom_prompt, use_grounding_mode, use_coordinates, options).await?;
+    }
+
+    if let Some(min_pages) = batch_options.running.dedup_running {
+        let mut texts: Vec<String> = pages.iter_mut().map(|p| std::mem::take(&mut p.markdown)).collect();
+        let removed = dedup_running_lines(&mut texts, min_pages as usize, batch_options.running.keep_first_running);
+        for (page, text) in pages.iter_mut().zip(texts) {
+            page.markdown = text;
+        }
+        println!("🧹 Removed {} running header/footer line(s)", removed);
+    }
+
+    Ok(BatchResult { pages, skipped, failed })
+}
+
+/// Machine-readable summary of a directory batch, written by `--report`.
+#[derive(Debug, Default, Serialize)]
+struct BatchReport {
+    total_pages: usize,
+    succeeded: usize,
+    failed: usize,
+    /// Pages without text, including those dropped by --skip-blank
+    skipped: usize,
+    /// Tokens the backend reported, including --verify-sample re-runs; 0 when it reports none
+    total_tokens: u64,
+    elapsed_seconds: f64,
+    pages: Vec<PageReport>,
+    /// Why the batch stopped early; absent when it finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PageReport {
+    /// 1-based page number; every frame of a multi-frame image is a page of its own
+    page: usize,
+    source: String,
+    /// 1-based frame of a multi-frame GIF/TIFF source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame: Option<usize>,
+    /// "left" or "right" half of a --split-spreads spread
+    #[serde(skip_serializing_if = "Option::is_none")]
+    half: Option<&'static str>,
+    status: PageStatus,
+    seconds: f64,
+    tokens: u64,
+}
+
+/// Outcome of one image of a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PageStatus {
+    Ok,
+    Failed,
+    Skipped,
+    Blank,
+}
+
+/// "2nd", "3rd", "10th", ...
+fn ordinal(n: u64) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+/// Join per-image results into one document with the internal index/page-break markers.
+fn combine_pages(pages: &[PageOutput]) -> String {
+    let mut combined_markdown = String::new();
+
+    for (i, page) in pages.iter().enumerate() {
+        // Add image index marker before the content
+        combined_markdown.push_str(&format!("---IMAGE_INDEX:{}---\n", page.index));
+        if let Some(name) = page.source.file_name() {
+            combined_markdown.push_str(&format!("---IMAGE_SOURCE:{}---\n", name.to_string_lossy()));
+        }
+        if let Some((width, height)) = page.dimensions {
+            combined_markdown.push_str(&format!("---IMAGE_SIZE:{}x{}---\n", width, height));
+        }
+        if let Some(language) = &page.language {
+            combined_markdown.push_str(&format!("---IMAGE_LANGUAGE:{}---\n", language));
+        }
+        combined_markdown.push_str(&page.markdown);
+        combined_markdown.push_str("\n\n");
+
+        // Add explicit page break marker between images (except after last one)
+        if i + 1 < pages.len() {
+            combined_markdown.push_str("---PAGE_BREAK---\n\n");
+        }
+    }
 
-    // Add automatic instructions for Ollama models (BUT NOT DeepSeek)
-    let prompt_text = if is_ollama && !is_deepseek {
+    combined_markdown
+}
 
-        let mut enhanced = base_prompt;
-        enhanced.push_str("\n\nIMPORTANT INSTRUCTIONS:");
-        enhanced.push_str("\n- Return ONLY the OCR result. No thinking or explanations. Do not wrap the output in markdown code fences (```).");
-        enhanced.push_str("\n- Fix grammar mistakes when confident.");
-        // Coordinate instructions are not added for DeepSeek models, as they handle coordinates differently.
-        if use_coordinates {
-            enhanced.push_str("\n- Include coordinate information using the format: <|det|>[[x1,y1,x2,y2]]</|det|> followed by the text.");
-        }
-        enhanced
-    } else {
-        base_prompt
-    };
+/// PDF of a directory batch built page by page (`--stream-pdf`): each finished page is
+/// drawn into one open document right away, and the document is saved at the end.
+struct PdfStream<'a> {
+    target: PdfTarget,
+    use_coordinates: bool,
+    options: &'a PdfOptions,
+}
 
-    // Debug: Print the full prompt
-    println!("=== OCR PROMPT ===");
-    println!("Model: {}", model);
-    println!("Use Coordinates: {}", use_coordinates);
-    println!("Prompt Text:");
-    println!("{}", prompt_text);
-    println!("==================");
+impl<'a> PdfStream<'a> {
+    fn new(title: &str, use_coordinates: bool, options: &'a PdfOptions) -> Self {
+        Self { target: PdfTarget::new(title), use_coordinates, options }
+    }
 
-    // For DeepSeek-OCR on Ollama, use the CLI directly to ensure correct behavior
-    if is_deepseek && is_ollama {
-        println!("Using Ollama CLI for DeepSeek-OCR");
-        
-        // Construct the prompt exactly as requested: "/path/to/image\n<|grounding|>Convert..."
-        // Note: prompt_text already contains the filename/path at the start
-        // But we need to make sure we pass the absolute path to the image
-        let abs_image_path = std::fs::canonicalize(image_path)?;
-        let cli_prompt = if use_grounding_mode {
-             format!("{}\n<|grounding|>Convert the document to markdown.", abs_image_path.display())
-        } else {
-             format!("{}\nExtract the text in the image.", abs_image_path.display())
-        };
-        
-        println!("CLI Prompt: {}", cli_prompt);
-
-        let output = std::process::Command::new("ollama")
-            .arg("run")
-            .arg(model)
-            .arg(&cli_prompt)
-            .output()
-            .context("Failed to execute ollama run")?;
-            
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("Ollama CLI error: {}", stderr);
+    fn add_page(&mut self, page: &PageOutput) -> Result<()> {
+        render_markdown_pdf(&combine_pages(std::slice::from_ref(page)), &self.target, self.use_coordinates, self.options)
+            .with_context(|| format!("Failed to render {} to PDF", page.source.display()))?;
+        println!("📄 PDF page added: {}", page.source.display());
+        Ok(())
+    }
+
+    /// Save the document to `pdf_path`.
+    fn finish(self, pdf_path: &Path) -> Result<()> {
+        self.target.save(pdf_path, self.options)?;
+        if let Some(dir) = &self.options.page_images {
+            render_page_images(pdf_path, dir, self.options.page_image_dpi)?;
         }
-        
-        let markdown = String::from_utf8_lossy(&output.stdout).to_string();
-        
-        // Save raw response to file for debugging
-        let raw_output_path = "/tmp/deepseek_raw_output.txt";
-        std::fs::write(raw_output_path, &markdown)?;
-        println!("=== RAW OCR OUTPUT SAVED ===");
-        println!("Saved to: {}", raw_output_path);
-        println!("Content length: {} chars", markdown.len());
-        println!("============================");
+        Ok(())
+    }
+}
+
+/// Sampled pages whose two OCR results are less similar than this are flagged.
+const VERIFY_MIN_SIMILARITY: f32 = 0.9;
 
-        return Ok(clean_markdown(&markdown));
+/// Parse a percentage in (0, 100].
+fn parse_percent(value: &str) -> Result<f32, String> {
+    let percent: f32 = value.parse().map_err(|_| format!("expected a number, got {:?}", value))?;
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(format!("expected a percentage between 0 and 100, got {}", percent));
     }
+    Ok(percent)
+}
 
-    // Prepare OCR request for other models (API)
-    let request = OcrRequest {
-        model: model.to_string(),
-        messages: vec![Message {
-            role: "user".to_string(),
-            content: vec![
-                Content::Text {
-                    text: prompt_text,
-                },
-                Content::ImageUrl {
-                    image_url: ImageUrl {
-                        url: format!("data:image/png;base64,{}", base64_image),
-                    },
-                },
-            ],
-        }],
-        max_tokens: 16384,
-        stream: false,
+/// Word-level similarity of two OCR results in 0..=1, ignoring markup and whitespace.
+fn text_similarity(a: &str, b: &str) -> f32 {
+    let normalize = |text: &str| clean_markdown_for_plain(text).split_whitespace().collect::<Vec<_>>().join(" ");
+    similar::TextDiff::from_words(&normalize(a), &normalize(b)).ratio()
+}
+
+/// Pick `count` distinct indices below `len` at random (partial Fisher-Yates over a
+/// xorshift generator seeded from the clock), returned in ascending order.
+fn random_sample(len: usize, count: usize) -> Vec<usize> {
+    let mut state = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0x9e37_79b9_7f4a_7c15, |d| d.as_nanos() as u64)
+        | 1;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
     };
+    let mut indices: Vec<usize> = (0..len).collect();
+    let count = count.min(len);
+    for i in 0..count {
+        let j = i + (next() % (len - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    let mut sample = indices[..count].to_vec();
+    sample.sort_unstable();
+    sample
+}
 
-    // Send request to OCR API
-    let api_url = get_api_url(model);
-    println!("Using API: {} with model: {}", api_url, model);
-    
-    let client = reqwest::Client::new();
-    let response = client
-        .post(api_url)
-        .json(&request)
-        .send()
-        .await
-        .context("Failed to send OCR request")?;
+/// `--verify-sample`: OCR a random share of the batch again and write a report of how
+/// closely each second result matches the first.
+#[allow(clippy::too_many_arguments)]
+async fn verify_pages(pages: &[&PageOutput], percent: f32, report_path: &Path, model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool, options: &OcrOptions) -> Result<()> {
+    if pages.is_empty() {
+        return Ok(());
+    }
+    let count = ((pages.len() as f32 * percent / 100.0).ceil() as usize).max(1);
+    let sample = random_sample(pages.len(), count);
+    println!("\n🔍 Verifying {} of {} pages with a second OCR pass", sample.len(), pages.len());
+
+    let mut rows = Vec::new();
+    let mut flagged = 0;
+    for index in sample {
+        let page = pages[index];
+        let name = page.source.file_name().map_or_else(|| page.source.display().to_string(), |n| n.to_string_lossy().into_owned());
+        let name = match page.part.label() {
+            label if label.is_empty() => name,
+            label => format!("{} ({})", name, label),
+        };
+        let status = match process_image_with_fallback(&page.source, model, custom_prompt, use_grounding_mode, use_coordinates, &page.part.options(options)).await {
+            Ok((ImageOcr { markdown: second, .. }, _)) => {
+                let similarity = text_similarity(&page.markdown, &second);
+                let unstable = similarity < VERIFY_MIN_SIMILARITY;
+                if unstable {
+                    flagged += 1;
+                }
+                println!("  {} {}: {:.1}% similar", if unstable { "⚠" } else { "✓" }, name, similarity * 100.0);
+                format!("{:.1}% | {}", similarity * 100.0, if unstable { "unstable" } else { "ok" })
+            }
+            Err(e) => {
+                eprintln!("  ✗ {}: second OCR failed: {:#}", name, e);
+                format!("- | second OCR failed: {}", e)
+            }
+        };
+        rows.push(format!("| {} | {} | {} |", page.index + 1, name, status));
+    }
 
-    if !response.status().is_success() {
-        anyhow::bail!(
-            "OCR API error: {} - {}",
-            response.status(),
-            response.text().await?
-        );
+    let report = format!(
+        "# OCR verification report\n\n{} of {} pages OCR'd twice ({}%); {} below {:.0}% similarity.\n\n| Page | Source | Similarity | Status |\n|---|---|---|---|\n{}\n",
+        rows.len(),
+        pages.len(),
+        percent,
+        flagged,
+        VERIFY_MIN_SIMILARITY * 100.0,
+        rows.join("\n")
+    );
+    fs::write(report_path, report).with_context(|| format!("Failed to write {}", report_path.display()))?;
+    println!("📋 {} unstable page(s); report saved to: {}", flagged, report_path.display());
+    Ok(())
+}
+
+/// Outcome of a directory batch: the pages that produced output plus those that did not.
+struct BatchResult {
+    pages: Vec<PageOutput>,
+    /// Images that were processed but yielded no usable text
+    skipped: Vec<PathBuf>,
+    /// Images whose OCR failed (only populated with --continue-on-error)
+    failed: Vec<PathBuf>,
+}
+
+impl BatchResult {
+    fn incomplete_count(&self) -> usize {
+        self.skipped.len() + self.failed.len()
     }
+}
 
-    let ocr_response: OcrResponse = response.json().await?;
-    let markdown = ocr_response
-        .choices
-        .first()
-        .map(|c| c.message.content.clone())
-        .unwrap_or_default();
-        
-    // Save raw response to file for debugging
-    let raw_output_path = "/tmp/deepseek_raw_output.txt";
-    std::fs::write(raw_output_path, &markdown)?;
-    println!("=== RAW OCR OUTPUT SAVED ===");
-    println!("Saved to: {}", raw_output_path);
-    println!("Content length: {} chars", markdown.len());
-    println!("============================");
-    
-    Ok(clean_markdown(&markdown))
+/// Settings shared by every request handled by `serve`.
+struct ServeState {
+    model: String,
+    custom_prompt: Option<String>,
+    use_coordinates: bool,
+    use_grounding_mode: bool,
+    options: OcrOptions,
 }
 
-async fn process_directory(dir_path: &Path, model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool) -> Result<String> {
-    let mut image_files: Vec<PathBuf> = WalkDir::new(dir_path)
-        .max_depth(1)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .filter(|e| {
-            e.path()
-                .extension()
-                .and_then(|s| s.to_str())
-                .map(|ext| matches!(ext.to_lowercase().as_str(), "png" | "jpg" | "jpeg" | "webp"))
-                .unwrap_or(false)
-        })
-        .map(|e| e.path().to_path_buf())
-        .collect();
+const SERVE_MAX_UPLOAD_BYTES: usize = 50 * 1024 * 1024;
 
-    image_files.sort();
+async fn serve(host: &str, port: u16, state: ServeState) -> Result<()> {
+    let app = axum::Router::new()
+        .route("/ocr", axum::routing::post(handle_ocr_request))
+        .layer(axum::extract::DefaultBodyLimit::max(SERVE_MAX_UPLOAD_BYTES))
+        .with_state(std::sync::Arc::new(state));
 
-    let total = image_files.len();
-    let mut combined_markdown = String::new();
+    let listener = tokio::net::TcpListener::bind((host, port))
+        .await
+        .with_context(|| format!("Failed to bind {}:{}", host, port))?;
+    println!("🌐 Listening on http://{}/ocr", listener.local_addr()?);
+    axum::serve(listener, app).await.context("HTTP server failed")?;
+    Ok(())
+}
 
-    println!("📊 Processing {} images", total);
-    println!("─────────────────────────────────────────");
+/// `POST /ocr`: the request body is the raw image, the response the cleaned markdown.
+async fn handle_ocr_request(
+    axum::extract::State(state): axum::extract::State<std::sync::Arc<ServeState>>,
+    body: axum::body::Bytes,
+) -> Result<([(axum::http::header::HeaderName, &'static str); 1], String), (axum::http::StatusCode, String)> {
+    use axum::http::{header, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static REQUEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let extension = image::guess_format(&body)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .ok_or((StatusCode::UNSUPPORTED_MEDIA_TYPE, "Request body is not a supported image".to_string()))?;
+
+    // process_image works on files, so stage the upload in the temp dir
+    let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let image_path = std::env::temp_dir().join(format!("ocr_serve_{}_{}.{}", std::process::id(), request_id, extension));
+    fs::write(&image_path, &body).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to stage image: {}", e)))?;
+
+    let result = process_image(
+        &image_path,
+        &state.model,
+        state.custom_prompt.as_deref(),
+        state.use_coordinates,
+        state.use_grounding_mode,
+        &state.options,
+    )
+    .await;
+    let _ = fs::remove_file(&image_path);
+
+    match result {
+        Ok((markdown, _)) => Ok(([(header::CONTENT_TYPE, "text/markdown; charset=utf-8")], markdown)),
+        Err(e) => {
+            eprintln!("✗ Request failed: {:#}", e);
+            let status = match e.root() {
+                OcrError::InvalidImage(_) | OcrError::RegionOutOfBounds { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+                OcrError::Network { source, .. } if source.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::BAD_GATEWAY,
+            };
+            Err((status, format!("{:#}", e)))
+        }
+    }
+}
 
-    for (i, image_path) in image_files.iter().enumerate() {
-        let current = i + 1;
-        let percentage = (current as f32 / total as f32 * 100.0) as u32;
+/// Payload sent to `--notify-url` when a directory batch finishes.
+#[derive(Debug, Serialize)]
+struct BatchSummary {
+    files_processed: usize,
+    failures: usize,
+    /// Pages that were skipped or failed
+    incomplete: usize,
+    elapsed_seconds: f64,
+    output: String,
+}
 
-        // Simple per-image progress log (no animation)
-        println!("[{}/{}] {}% | Processing: {}", current, total, percentage, image_path.display());
+/// POST the batch summary to the webhook. A failed notification only warns,
+/// since the OCR output has already been written at this point.
+async fn notify_batch_complete(url: &str, summary: &BatchSummary, options: &OcrOptions) {
+    let result = async {
+        let response = options.http_client()?.post(url).json(summary).send().await?;
+        response.error_for_status()?;
+        anyhow::Ok(())
+    }
+    .await;
 
-        let markdown = process_image_with_mode(image_path, model, custom_prompt, use_grounding_mode, use_coordinates).await?;
-        
-        // Add image index marker before the content
-        combined_markdown.push_str(&format!("---IMAGE_INDEX:{}---\n", i));
-        combined_markdown.push_str(&markdown);
-        combined_markdown.push_str("\n\n");
-        
-        // Add explicit page break marker between images (except after last one)
-        if current < total {
-            combined_markdown.push_str("---PAGE_BREAK---\n\n");
+    match result {
+        Ok(()) => println!("📣 Notified {}", url),
+        Err(e) => eprintln!("⚠ Failed to send completion notification to {}: {}", url, e),
+    }
+}
+
+/// Append `markdown` as a new page of an existing document, using the same
+/// index/page-break markers as a directory batch.
+fn append_page(output_path: &Path, markdown: &str, encoding: &OutputEncoding) -> Result<()> {
+    use std::io::Write;
+
+    let existing = match fs::read_to_string(output_path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", output_path.display()))
+        }
+    };
+
+    let mut chunk = String::new();
+    let next_index = if existing.trim().is_empty() {
+        0
+    } else {
+        // A document written without markers still counts as one page
+        let markers = existing
+            .lines()
+            .filter(|l| l.trim_start_matches('\u{feff}').starts_with("---IMAGE_INDEX:"))
+            .count();
+        if !existing.ends_with('\n') {
+            chunk.push('\n');
         }
+        chunk.push_str("\n---PAGE_BREAK---\n\n");
+        markers.max(1)
+    };
+    // Front matter of a new document goes above the first page marker
+    let (front_matter, body) = match split_front_matter(markdown) {
+        (Some(front_matter), body) if next_index == 0 => (format!("{}\n", front_matter.raw), body.trim_start()),
+        _ => (String::new(), markdown),
+    };
+    chunk.push_str(&front_matter);
+    chunk.push_str(&format!("---IMAGE_INDEX:{}---\n", next_index));
+    chunk.push_str(body);
+    chunk.push_str("\n\n");
+
+    let mut bytes = Vec::new();
+    if existing.is_empty() && encoding.bom {
+        bytes.extend_from_slice("\u{feff}".as_bytes());
     }
+    bytes.extend_from_slice(encoding.convert_line_endings(&chunk).as_bytes());
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(output_path)
+        .with_context(|| format!("Failed to open {} for appending", output_path.display()))?;
+    file.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Write each page to `<output_dir>/<image stem>.md` (see `page_file_names` for
+/// frames and sources that share a stem).
+fn write_pages_to_dir(pages: &[PageOutput], output_dir: &Path, encoding: &OutputEncoding) -> Result<()> {
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("Failed to create output directory: {}", output_dir.display()))?;
 
-    println!("\n✓ All images processed successfully!");
+    for (page, name) in pages.iter().zip(page_file_names(pages)) {
+        let output_path = output_dir.join(name);
+        encoding.write(&output_path, &page.markdown)?;
+        println!("✓ Markdown saved to: {}", output_path.display());
+    }
 
-    Ok(combined_markdown)
+    Ok(())
 }
 
-async fn process_directory_joined(dir_path: &Path, model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool) -> Result<String> {
-    use image::{DynamicImage, ImageBuffer, Rgba};
-    
-    let mut image_files: Vec<PathBuf> = WalkDir::new(dir_path)
-        .max_depth(1)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .filter(|e| {
-            e.path()
-                .extension()
-                .and_then(|s| s.to_str())
-                .map(|ext| matches!(ext.to_lowercase().as_str(), "png" | "jpg" | "jpeg" | "webp"))
-                .unwrap_or(false)
+/// Unique `--output-dir` file names: `scan1.md`, `anim_frame2.md` for frames,
+/// `spread_left.md` for spread halves, the full file name (`scan1.png.md`) when
+/// images share a stem, and the page number on top when even that repeats (zip
+/// entries from different folders).
+fn page_file_names(pages: &[PageOutput]) -> Vec<String> {
+    let part_suffix = |page: &PageOutput| {
+        let frame = page.part.frame.map(|frame| format!("_frame{}", frame + 1)).unwrap_or_default();
+        let side = page.part.side().map(|side| format!("_{}", side)).unwrap_or_default();
+        frame + &side
+    };
+    let file_part = |page: &PageOutput, full: bool| {
+        let name = if full { page.source.file_name() } else { page.source.file_stem() };
+        name.map_or_else(|| "image".to_string(), |n| n.to_string_lossy().into_owned())
+    };
+    let repeated = |names: &[String], name: &String| names.iter().filter(|n| *n == name).count() > 1;
+
+    let stems: Vec<String> = pages.iter().map(|page| format!("{}{}", file_part(page, false), part_suffix(page))).collect();
+    let names: Vec<String> = pages
+        .iter()
+        .zip(&stems)
+        .map(|(page, stem)| {
+            if repeated(&stems, stem) {
+                format!("{}{}", file_part(page, true), part_suffix(page))
+            } else {
+                stem.clone()
+            }
         })
-        .map(|e| e.path().to_path_buf())
         .collect();
+    pages
+        .iter()
+        .zip(&names)
+        .map(|(page, name)| {
+            if repeated(&names, name) {
+                format!("{}_{}.md", name, page.index + 1)
+            } else {
+                format!("{}.md", name)
+            }
+        })
+        .collect()
+}
 
-    image_files.sort();
+async fn process_directory_joined(mut image_files: Vec<PathBuf>, model: &str, custom_prompt: Option<&str>, use_grounding_mode: bool, use_coordinates: bool, join_options: &JoinOptions, options: &OcrOptions) -> Result<String> {
+    use image::{DynamicImage, ImageBuffer, Rgba};
 
     let total = image_files.len();
     
@@ -638,8 +4242,14 @@ async fn process_directory_joined(dir_path: &Path, model: &str, custom_prompt: O
     for (i, image_path) in image_files.iter().enumerate() {
         println!("[{}/{}] Loading: {}", i + 1, total, image_path.display());
         
-        let img = image::open(image_path)
+        let mut img = read_image_bytes(image_path)
+            .and_then(|bytes| image::load_from_memory(&bytes).map_err(Into::into))
             .context(format!("Failed to open image: {}", image_path.display()))?;
+        if join_options.join_scale < 1.0 {
+            let width = ((img.width() as f32 * join_options.join_scale).round() as u32).max(1);
+            let height = ((img.height() as f32 * join_options.join_scale).round() as u32).max(1);
+            img = img.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
+        }
         
         max_width = max_width.max(img.width());
         total_height += img.height();
@@ -647,6 +4257,9 @@ async fn process_directory_joined(dir_path: &Path, model: &str, custom_prompt: O
     }
 
     println!("✓ All images loaded");
+    if join_options.join_scale < 1.0 {
+        println!("🔽 Images scaled by {} before joining", join_options.join_scale);
+    }
     println!("📐 Creating combined image: {}x{} pixels", max_width, total_height);
 
     // Create a new image that can hold all images vertically
@@ -656,19 +4269,9 @@ async fn process_directory_joined(dir_path: &Path, model: &str, custom_prompt: O
     for (i, img) in images.iter().enumerate() {
         println!("[{}/{}] Copying image to combined canvas", i + 1, total);
         
-        // Convert to RGBA if needed
-        let rgba_img = img.to_rgba8();
-        
         // Center the image horizontally if it's narrower than max_width
         let x_offset = (max_width - img.width()) / 2;
-        
-        // Copy pixels from source image to combined image
-        for y in 0..img.height() {
-            for x in 0..img.width() {
-                let pixel = rgba_img.get_pixel(x, y);
-                combined.put_pixel(x + x_offset, current_y + y, *pixel);
-            }
-        }
+        paste_image(&mut combined, img, x_offset, current_y);
         
         current_y += img.height();
     }
@@ -676,11 +4279,31 @@ async fn process_directory_joined(dir_path: &Path, model: &str, custom_prompt: O
     println!("✓ Combined image created");
     println!("📤 Encoding to base64...");
 
+    let mut combined = DynamicImage::ImageRgba8(combined);
+    if let Some(max_side) = join_options.join_max_side {
+        if combined.width().max(combined.height()) > max_side {
+            combined = combined.resize(max_side, max_side, image::imageops::FilterType::Lanczos3);
+            println!("🔽 Downscaled combined image to {}x{} pixels", combined.width(), combined.height());
+        }
+    }
+    println!("📐 Final canvas: {}x{} pixels", combined.width(), combined.height());
+
     // Save combined image to memory buffer
     let mut buffer = Vec::new();
-    let mut cursor = std::io::Cursor::new(&mut buffer);
-    combined.write_to(&mut cursor, image::ImageFormat::Png)
-        .context("Failed to encode combined image")?;
+    let mime_type = match join_options.join_format {
+        JoinFormat::Png => {
+            combined.write_to(&mut std::io::Cursor::new(&mut buffer), image::ImageFormat::Png)
+                .context("Failed to encode combined image")?;
+            "image/png"
+        }
+        JoinFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, join_options.join_quality);
+            combined.to_rgb8().write_with_encoder(encoder)
+                .context("Failed to encode combined image")?;
+            "image/jpeg"
+        }
+    };
     
     let base64_image = general_purpose::STANDARD.encode(&buffer);
 
@@ -691,12 +4314,13 @@ async fn process_directory_joined(dir_path: &Path, model: &str, custom_prompt: O
     let is_ollama = !model.contains("NexaAI") && !model.contains("GGUF");
 
     // Build the base prompt text with custom prompt if provided
+    let grounding = &options.grounding_token;
     let base_prompt = if let Some(custom) = custom_prompt {
         // For NexaAI with custom prompt, include grounding tag only if use_grounding_mode is true
         if is_ollama {
             format!("Combined document with multiple pages. {}", custom)
         } else if use_grounding_mode {
-            format!("Combined document with multiple pages. <|grounding|>{}", custom)
+            format!("Combined document with multiple pages. {}{}", grounding, custom)
         } else {
             format!("Combined document with multiple pages. {}", custom)
         }
@@ -705,7 +4329,7 @@ async fn process_directory_joined(dir_path: &Path, model: &str, custom_prompt: O
         if is_ollama {
             if use_grounding_mode {
                 if model.to_lowercase().contains("deepseek-ocr") {
-                    "Combined document with multiple pages. <|grounding|>Convert the entire document to markdown, preserving the structure and content from all pages.".to_string()
+                    format!("Combined document with multiple pages. {}Convert the entire document to markdown, preserving the structure and content from all pages.", grounding)
                 } else {
                     "Combined document with multiple pages. Convert the entire document to markdown. Preserve all headings, lists, tables, and layout structure from all pages.".to_string()
                 }
@@ -713,14 +4337,17 @@ async fn process_directory_joined(dir_path: &Path, model: &str, custom_prompt: O
                 "Combined document with multiple pages. Free OCR.".to_string()
             }
         } else if use_grounding_mode {
-            "Combined document with multiple pages. <|grounding|>Convert the entire document to markdown, preserving the structure and content from all pages.".to_string()
+            format!("Combined document with multiple pages. {}Convert the entire document to markdown, preserving the structure and content from all pages.", grounding)
         } else {
             "Combined document with multiple pages. Free OCR.".to_string()
         }
     };
 
     // Add automatic instructions for Ollama models
-    let prompt_text = if is_ollama {
+    let prompt_text = if let Some(schema) = &options.schema {
+        // --schema replaces the markdown instructions, as for single images
+        schema_prompt("Combined document with multiple pages.", schema, custom_prompt)
+    } else if is_ollama {
         let mut enhanced = base_prompt;
         enhanced.push_str("\n\nIMPORTANT INSTRUCTIONS:");
         enhanced.push_str("\n- Extract all text from this image. Present the extracted text in a structured format, preserving all line breaks and original spacing. Do not interpret or summarize the content; provide the raw text as precisely as possible.");
@@ -744,48 +4371,46 @@ async fn process_directory_joined(dir_path: &Path, model: &str, custom_prompt: O
                 },
                 Content::ImageUrl {
                     image_url: ImageUrl {
-                        url: format!("data:image/png;base64,{}", base64_image),
+                        url: format!("data:{};base64,{}", mime_type, base64_image),
                     },
                 },
             ],
         }],
-        max_tokens: 16384,
+        max_tokens: options.max_tokens,
         stream: false,
+        temperature: options.temperature,
+        top_p: options.top_p,
+        response_format: options.schema.as_ref().map(schema_response_format),
     };
 
     // Send request to OCR API
-    let api_url = get_api_url(model);
+    let api_url = options.api_url(model);
     println!("Using API: {} with model: {}", api_url, model);
+
+    if let Some(schema) = &options.schema {
+        return extract_with_schema(api_url, &request, schema, options).await.context("Schema extraction failed");
+    }
     
-    let client = reqwest::Client::new();
-    let response = client
-        .post(api_url)
-        .json(&request)
-        .send()
+    let response = options
+        .send_ocr_request(api_url, &request)
         .await
         .context("Failed to send OCR request")?;
+    let response = ensure_success(response).await?;
 
-    if !response.status().is_success() {
-        anyhow::bail!(
-            "OCR API error: {} - {}",
-            response.status(),
-            response.text().await?
-        );
-    }
-
-    let ocr_response: OcrResponse = response.json().await?;
-    let markdown = ocr_response
-        .choices
-        .first()
-        .map(|c| c.message.content.clone())
-        .unwrap_or_default();
+    let markdown = options.read_ocr_response(response).await?;
+    let markdown = if options.refine {
+        refine_ocr(api_url, request, markdown, options).await.context("Refinement pass failed")?
+    } else {
+        markdown
+    };
 
     println!("✓ OCR completed successfully!");
 
-    Ok(clean_markdown(&markdown))
+    options.postprocess(clean_markdown(&markdown))
 }
 
-async fn process_pdf(pdf_path: &Path, temp_dir: &Path, use_native: bool) -> Result<String> {
+/// Returns the combined markdown and the number of pages that were skipped.
+async fn process_pdf(pdf_path: &Path, temp_dir: &Path, use_native: bool, temp_format: TempFormat, dpi: u32, sample: u64) -> Result<(String, usize)> {
     // PDF processing uses default model
     const DEFAULT_MODEL: &str = "deepseek-ocr";
     
@@ -794,20 +4419,13 @@ async fn process_pdf(pdf_path: &Path, temp_dir: &Path, use_native: bool) -> Resu
 
     println!("📄 Extracting pages from PDF using pdftoppm...");
 
-    // Use pdftoppm to extract PDF pages as PNG images
+    // Use pdftoppm to extract PDF pages as images (PNG unless --temp-format says otherwise)
     let output_prefix = temp_dir.join("page");
     let output_prefix_str = output_prefix
         .to_str()
         .ok_or_else(|| anyhow::anyhow!("Invalid output path"))?;
 
-    // Run pdftoppm command
-    let output = std::process::Command::new("pdftoppm")
-        .arg("-png")
-        .arg("-r")
-        .arg("300") // 300 DPI for good quality
-        .arg(pdf_path)
-        .arg(output_prefix_str)
-        .output();
+    let output = run_pdftoppm(pdf_path, output_prefix_str, temp_format, dpi);
 
     match output {
         Ok(result) if result.status.success() => {
@@ -835,23 +4453,297 @@ async fn process_pdf(pdf_path: &Path, temp_dir: &Path, use_native: bool) -> Resu
     }
 
     // Process extracted images with default grounding mode enabled and coordinates disabled
-    process_directory(temp_dir, DEFAULT_MODEL, None, true, false).await
+    // Only pick up pages in the requested format, not leftovers from earlier runs
+    let page_files: Vec<PathBuf> = collect_image_files(temp_dir)?
+        .into_iter()
+        .filter(|p| p.extension().and_then(|e| e.to_str()) == Some(temp_format.extension()))
+        .filter(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("page-")))
+        .collect();
+    if page_files.is_empty() {
+        if use_native {
+            println!("⚠ pdftoppm extracted no pages. Falling back to native PDF extraction using pdf-extract crate.");
+            return process_pdf_native(pdf_path).await;
+        }
+        anyhow::bail!(
+            "pdftoppm extracted no pages from {} (no page-*.{} files in {}). Check that the PDF \
+             opens and has pages, or retry with --use-native to extract its text instead",
+            pdf_path.display(),
+            temp_format.extension(),
+            temp_dir.display()
+        );
+    }
+    println!("📄 {} page(s) extracted", page_files.len());
+    let batch_options = BatchOptions { sample, ..BatchOptions::default() };
+    let batch = process_directory(&page_files, DEFAULT_MODEL, None, true, false, &batch_options, &OcrOptions::default(), None).await?;
+    Ok((combine_pages(&batch.pages), batch.incomplete_count()))
+}
+
+fn run_pdftoppm(pdf_path: &Path, output_prefix: &str, format: TempFormat, dpi: u32) -> std::io::Result<std::process::Output> {
+    std::process::Command::new("pdftoppm")
+        .arg(format.pdftoppm_flag())
+        .arg("-r")
+        .arg(dpi.to_string())
+        .arg(pdf_path)
+        .arg(output_prefix)
+        .output()
+}
+
+/// Pages rendered from a PDF for `contacts`; a low resolution is plenty for thumbnails.
+fn render_pdf_thumbnail_pages(pdf_path: &Path, temp_dir: &Path) -> Result<Vec<PathBuf>> {
+    const THUMBNAIL_DPI: u32 = 50;
+
+    fs::create_dir_all(temp_dir)?;
+    let prefix = temp_dir.join("thumb");
+    let prefix_str = prefix.to_str().context("Invalid output path")?;
+    render_pdf_pages(pdf_path, prefix_str, THUMBNAIL_DPI)?;
+    Ok(collect_image_files(temp_dir)?
+        .into_iter()
+        .filter(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("thumb")))
+        .collect())
+}
+
+/// Run pdftoppm to write every page of `pdf_path` as `<output_prefix>-N.png`.
+fn render_pdf_pages(pdf_path: &Path, output_prefix: &str, dpi: u32) -> Result<()> {
+    match run_pdftoppm(pdf_path, output_prefix, TempFormat::Png, dpi) {
+        Ok(result) if result.status.success() => Ok(()),
+        Ok(result) => anyhow::bail!("pdftoppm failed: {}", String::from_utf8_lossy(&result.stderr)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => anyhow::bail!(
+            "pdftoppm not found. Please install poppler-utils:\n  \
+             macOS: brew install poppler\n  \
+             Ubuntu/Debian: sudo apt-get install poppler-utils"
+        ),
+        Err(e) => anyhow::bail!("Failed to run pdftoppm: {}", e),
+    }
+}
+
+/// `--page-images`: PNG previews of each page of a freshly written PDF, named after
+/// it (`report-1.png`, `report-2.png`, ...).
+fn render_page_images(pdf_path: &Path, dir: &Path, dpi: u32) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+    let stem = pdf_path.file_stem().and_then(|s| s.to_str()).unwrap_or("page");
+    let prefix = dir.join(stem);
+    let prefix_str = prefix.to_str().context("Invalid output path")?;
+    render_pdf_pages(pdf_path, prefix_str, dpi).context("Failed to render page images")?;
+    let written = collect_image_files(dir)?
+        .into_iter()
+        .filter(|p| {
+            p.file_stem()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix(stem)?.strip_prefix('-'))
+                .is_some_and(|n| n.chars().all(|c| c.is_ascii_digit()))
+        })
+        .count();
+    println!("🖼 Wrote {} page image(s) to {}", written, dir.display());
+    Ok(())
+}
+
+/// Copy `img` onto `canvas` with its top-left corner at (`x`, `y`).
+fn paste_image(canvas: &mut image::RgbaImage, img: &image::DynamicImage, x: u32, y: u32) {
+    let rgba_img = img.to_rgba8();
+    for (px, py, pixel) in rgba_img.enumerate_pixels() {
+        if x + px < canvas.width() && y + py < canvas.height() {
+            canvas.put_pixel(x + px, y + py, *pixel);
+        }
+    }
+}
+
+/// Thumbnails of `pages` laid out `columns` per row on a light gray sheet, each
+/// centered in a white cell so blank pages stand out.
+fn build_contact_sheet(pages: &[PathBuf], columns: u32, thumb_size: u32) -> Result<image::RgbaImage> {
+    use image::Rgba;
+    const PADDING: u32 = 8;
+
+    let columns = columns.min(pages.len() as u32).max(1);
+    let rows = (pages.len() as u32).div_ceil(columns);
+    let cell = thumb_size + PADDING;
+    let mut sheet = image::RgbaImage::from_pixel(columns * cell + PADDING, rows * cell + PADDING, Rgba([200, 200, 200, 255]));
+
+    for (i, page) in pages.iter().enumerate() {
+        println!("[{}/{}] Thumbnail: {}", i + 1, pages.len(), page.display());
+        let img = read_image_bytes(page)
+            .and_then(|bytes| image::load_from_memory(&bytes).map_err(Into::into))
+            .with_context(|| format!("Failed to open image: {}", page.display()))?
+            .thumbnail(thumb_size, thumb_size);
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        let (cell_x, cell_y) = (PADDING + column * cell, PADDING + row * cell);
+        let white = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(thumb_size, thumb_size, Rgba([255, 255, 255, 255])));
+        paste_image(&mut sheet, &white, cell_x, cell_y);
+        paste_image(&mut sheet, &img, cell_x + (thumb_size - img.width()) / 2, cell_y + (thumb_size - img.height()) / 2);
+    }
+    Ok(sheet)
 }
 
-async fn process_pdf_native(pdf_path: &Path) -> Result<String> {
+async fn process_pdf_native(pdf_path: &Path) -> Result<(String, usize)> {
     // Use the pdf-extract crate to extract text directly from PDF as a fallback when pdftoppm is not available.
     println!("📄 Extracting text from PDF using pdf-extract (native fallback)...");
-    let text_result = extract_text(pdf_path)
+    let path = pdf_path.to_path_buf();
+    let page_texts = tokio::task::spawn_blocking(move || extract_pdf_pages(&path))
+        .await
+        .context("Native PDF extraction task panicked")?
         .with_context(|| format!("Failed to extract PDF text for {}", pdf_path.display()))?;
-    // Return the extracted text as markdown.
-    println!("✓ Native PDF extraction successful");
-    Ok(text_result)
+
+    // Build the same page structure as the pdftoppm path so markers stay consistent
+    let mut pages = Vec::with_capacity(page_texts.len());
+    let mut skipped = 0;
+    for (i, text) in page_texts.into_iter().enumerate() {
+        if text.trim().is_empty() {
+            println!("⚠ No text found on page {}, skipping", i + 1);
+            skipped += 1;
+            continue;
+        }
+        pages.push(PageOutput {
+            index: i,
+            source: pdf_path.to_path_buf(),
+            part: ImagePart::default(),
+            markdown: text.trim().to_string(),
+            dimensions: None,
+            language: None,
+            model: None,
+        });
+    }
+    println!("✓ Native PDF extraction successful ({} pages)", pages.len() + skipped);
+    Ok((combine_pages(&pages), skipped))
+}
+
+/// Extract the text of every page of a PDF, spreading pages across worker threads.
+/// Pages that fail to extract come back empty.
+fn extract_pdf_pages(pdf_path: &Path) -> Result<Vec<String>> {
+    let mut doc = pdf_extract::Document::load(pdf_path)?;
+    if doc.is_encrypted() {
+        doc.decrypt("").context("PDF is encrypted")?;
+    }
+    let page_numbers: Vec<u32> = doc.get_pages().keys().copied().collect();
+    let workers = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let chunk_size = page_numbers.len().div_ceil(workers).max(1);
+
+    let mut texts = vec![String::new(); page_numbers.len()];
+    std::thread::scope(|scope| {
+        for (numbers, outputs) in page_numbers.chunks(chunk_size).zip(texts.chunks_mut(chunk_size)) {
+            let doc = &doc;
+            scope.spawn(move || {
+                for (&page_num, text) in numbers.iter().zip(outputs.iter_mut()) {
+                    let mut output = pdf_extract::PlainTextOutput::new(&mut *text);
+                    if let Err(e) = pdf_extract::output_doc_page(doc, &mut output, page_num) {
+                        eprintln!("✗ Failed to extract page {}: {}", page_num, e);
+                        text.clear();
+                    }
+                }
+            });
+        }
+    });
+    Ok(texts)
+}
+
+/// Front matter block for `--front-matter`. The title is the source file name
+/// without extension; the date is today's date (UTC).
+fn build_front_matter(source: &Path, model: &str, language: Option<&str>) -> String {
+    let title = source.file_stem().and_then(|n| n.to_str()).unwrap_or("OCR Document");
+    let mut front_matter = String::from("---\n");
+    front_matter.push_str(&format!("title: {}\n", yaml_quote(title)));
+    front_matter.push_str(&format!("source: {}\n", yaml_quote(&source.display().to_string())));
+    front_matter.push_str(&format!("date: {}\n", today_utc()));
+    // Empty when no page of the batch got an answer
+    if !model.is_empty() {
+        front_matter.push_str(&format!("model: {}\n", yaml_quote(model)));
+    }
+    if let Some(language) = language {
+        front_matter.push_str(&format!("language: {}\n", language));
+    }
+    front_matter.push_str("---\n\n");
+    front_matter
+}
+
+fn yaml_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Current date as YYYY-MM-DD in UTC.
+fn today_utc() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// YAML front matter found at the very start of a markdown document.
+/// Only flat `key: value` pairs are accepted; `raw` keeps the block verbatim.
+struct FrontMatter {
+    raw: String,
+    fields: Vec<(String, String)>,
+}
+
+impl FrontMatter {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A `key: value` front matter line as (key, unquoted value); None for anything else.
+fn front_matter_field(line: &str) -> Option<(String, String)> {
+    let (key, value) = line.split_once(':')?;
+    if key.is_empty() || !key.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        return None;
+    }
+    if !value.is_empty() && !value.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+    Some((key.to_string(), value.to_string()))
+}
+
+/// Split off a leading `---` ... `---` block when every non-blank line in it is a
+/// `key: value` pair, so a document that merely opens with a horizontal rule keeps it.
+fn split_front_matter(markdown: &str) -> (Option<FrontMatter>, &str) {
+    let text = markdown.strip_prefix('\u{feff}').unwrap_or(markdown);
+    let mut lines = text.split_inclusive('\n');
+    if lines.next().map(|l| l.trim_end()) != Some("---") {
+        return (None, markdown);
+    }
+
+    let mut offset = text.find('\n').map(|i| i + 1).unwrap_or(text.len());
+    let mut fields = Vec::new();
+    for line in lines {
+        let trimmed = line.trim_end();
+        if trimmed == "---" || trimmed == "..." {
+            let raw = text[..offset + line.len()].to_string();
+            return (Some(FrontMatter { raw, fields }), &text[offset + line.len()..]);
+        }
+        if !trimmed.is_empty() {
+            match front_matter_field(trimmed) {
+                Some(field) => fields.push(field),
+                None => return (None, markdown),
+            }
+        }
+        offset += line.len();
+    }
+
+    // No closing delimiter: not front matter
+    (None, markdown)
+}
+
+/// Remove `<|ref|>...<|/ref|>` labels, which may span lines, and any unpaired ref tag
+/// left by a truncated response.
+fn strip_ref_tags(text: &str) -> String {
+    let re_ref = Regex::new(r"(?s)<\|ref\|>.*?<\|/ref\|>").unwrap();
+    let re_stray = Regex::new(r"<\|/?ref\|>").unwrap();
+    re_stray.replace_all(&re_ref.replace_all(text, ""), "").to_string()
 }
 
 fn clean_markdown(text: &str) -> String {
     // Remove OCR-specific tags but KEEP <|det|> tags for coordinate-based rendering
-    // Remove all <|ref|>...<|/ref|> tags (including newlines within)
-    let re_ref = Regex::new(r"(?s)<\|ref\|>.*?<\|/ref\|>").unwrap();
     // Remove specific OCR tags line by line, but keep det tags
     // Match common OCR tags: <|grounding|>, <|think|>, <|OCR|>, etc.
     let re_grounding = Regex::new(r"<\|grounding\|>").unwrap();
@@ -865,7 +4757,7 @@ fn clean_markdown(text: &str) -> String {
     let mut cleaned = text.to_string();
 
     // Apply OCR tag removal but preserve <|det|> tags
-    cleaned = re_ref.replace_all(&cleaned, "").to_string();
+    cleaned = strip_ref_tags(&cleaned);
     cleaned = re_grounding.replace_all(&cleaned, "").to_string();
     cleaned = re_think.replace_all(&cleaned, "").to_string();
     cleaned = re_ocr.replace_all(&cleaned, "").to_string();
@@ -874,23 +4766,254 @@ fn clean_markdown(text: &str) -> String {
 
     // Remove explicit markers used internally
     let re_page_break = Regex::new(r"(?m)^---PAGE_BREAK---\s*$").unwrap();
-    let re_image_index = Regex::new(r"(?m)^---IMAGE_INDEX:.*---\s*$").unwrap();
+    let re_image_index = Regex::new(r"(?m)^---IMAGE_(?:INDEX|SOURCE|SIZE|LANGUAGE):.*---\s*$").unwrap();
     cleaned = re_page_break.replace_all(&cleaned, "").to_string();
     cleaned = re_image_index.replace_all(&cleaned, "").to_string();
 
     cleaned.trim().to_string()
 }
 
+/// Apply the regex passes selected by `level` (see `CleanLevel`).
+fn clean_markdown_level(text: &str, level: CleanLevel) -> String {
+    if level == CleanLevel::None {
+        return text.to_string();
+    }
+
+    let mut cleaned = text.to_string();
+    let mut strip = |pattern: &str| {
+        cleaned = Regex::new(pattern).unwrap().replace_all(&cleaned, "").to_string();
+    };
+
+    // Light: model control output and our own markers
+    strip(r"(?s)<\|think\|>.*?<\|/think\|>");
+    strip(r"<\|(?:grounding|OCR)\|>");
+    strip(r"(?m)^---PAGE_BREAK---\s*$");
+    strip(r"(?m)^---IMAGE_INDEX:\d+---\s*$");
+    strip(r"(?m)^---IMAGE_SOURCE:.*---\s*$");
+    strip(r"(?m)^---IMAGE_SIZE:\d+x\d+---\s*$");
+    strip(r"(?m)^---IMAGE_LANGUAGE:\w+---\s*$");
+
+    if level >= CleanLevel::Standard {
+        strip(r"<\|det\|>.*?<\|/det\|>");
+        strip(r"<\|conf\|>.*?<\|/conf\|>");
+    }
+    if level >= CleanLevel::Aggressive {
+        strip(r"(?s)<\|ref\|>.*?<\|/ref\|>");
+        strip(r"<\|[^|]+\|>");
+    }
+
+    strip(r"(?m)^[ \t]+$");
+    let re_newlines = Regex::new(r"\n{3,}").unwrap();
+    re_newlines.replace_all(&cleaned, "\n\n").trim().to_string()
+}
+
+/// Key a running header/footer line is matched by: page numbers and other digit runs
+/// become `#` and whitespace is collapsed, so "Page 3  of 10" matches "Page 4 of 10".
+fn running_line_key(line: &str) -> String {
+    let re_digits = Regex::new(r"\d+").unwrap();
+    re_digits.replace_all(line, "#").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Remove short lines that repeat at the top or bottom of at least `min_pages` pages,
+/// together with the coordinate line in front of them. Returns the number of lines removed.
+fn dedup_running_lines(pages: &mut [String], min_pages: usize, keep_first: bool) -> usize {
+    let is_text = |line: &str| {
+        let line = line.trim();
+        !line.is_empty() && !line.starts_with("<|") && !line.starts_with("---")
+    };
+    // Indices of the text lines near the top and bottom of each page
+    let edges: Vec<Vec<usize>> = pages
+        .iter()
+        .map(|page| {
+            let text_lines: Vec<usize> = page.lines().enumerate().filter(|(_, l)| is_text(l)).map(|(i, _)| i).collect();
+            let mut edge: Vec<usize> = text_lines.iter().take(RUNNING_EDGE_LINES).copied().collect();
+            edge.extend(text_lines.iter().rev().take(RUNNING_EDGE_LINES));
+            edge.sort_unstable();
+            edge.dedup();
+            edge.retain(|&i| page.lines().nth(i).is_some_and(|l| l.trim().chars().count() <= MAX_RUNNING_LINE_CHARS));
+            edge
+        })
+        .collect();
+
+    let mut page_counts: HashMap<String, usize> = HashMap::new();
+    for (page, edge) in pages.iter().zip(&edges) {
+        let lines: Vec<&str> = page.lines().collect();
+        let keys: HashSet<String> = edge.iter().map(|&i| running_line_key(lines[i])).collect();
+        for key in keys {
+            *page_counts.entry(key).or_default() += 1;
+        }
+    }
+
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut removed = 0;
+    for (page, edge) in pages.iter_mut().zip(&edges) {
+        let lines: Vec<&str> = page.lines().collect();
+        let mut drop = vec![false; lines.len()];
+        for &i in edge {
+            let key = running_line_key(lines[i]);
+            if page_counts.get(&key).copied().unwrap_or(0) < min_pages {
+                continue;
+            }
+            if keep_first && seen.insert(key) {
+                continue;
+            }
+            drop[i] = true;
+            removed += 1;
+            if i > 0 && lines[i - 1].trim_start().starts_with("<|") && lines[i - 1].contains("<|/det|>") {
+                drop[i - 1] = true;
+            }
+        }
+        if drop.contains(&true) {
+            let mut kept = lines.iter().zip(&drop).filter(|(_, &d)| !d).map(|(l, _)| *l).collect::<Vec<_>>().join("\n");
+            if page.ends_with('\n') {
+                kept.push('\n');
+            }
+            *page = kept;
+        }
+    }
+    removed
+}
+
+/// Rejoin words split across lines with a hyphen (`infor-\nmation` -> `information`).
+/// Only a lowercase letter followed by a lowercase continuation is merged. If the
+/// hyphenated spelling (`long-term`) also occurs elsewhere in the text, the hyphen
+/// is kept and only the line break is removed.
+fn dehyphenate(text: &str) -> String {
+    let re_split = Regex::new(r"(\p{L}*\p{Ll})-[ \t]*\n[ \t]*(\p{Ll}\p{L}*)").unwrap();
+    re_split
+        .replace_all(text, |caps: &regex::Captures| {
+            let head = &caps[1];
+            let tail = &caps[2];
+            let compound = format!("{}-{}", head, tail);
+            if text.contains(compound.as_str()) {
+                compound
+            } else {
+                format!("{}{}", head, tail)
+            }
+        })
+        .to_string()
+}
+
+/// Join runs of plain text lines into single-line paragraphs. Blank lines, headers,
+/// list items, internal markers and OCR tags end a paragraph; fenced code blocks,
+/// HTML tables and pipe tables are copied through with their line breaks intact.
+fn reflow_paragraphs(text: &str) -> String {
+    let mut output: Vec<String> = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut in_code = false;
+    let mut in_table = false;
+
+    let flush = |paragraph: &mut Vec<&str>, output: &mut Vec<String>| {
+        if !paragraph.is_empty() {
+            output.push(paragraph.join(" "));
+            paragraph.clear();
+        }
+    };
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+
+        if trimmed.starts_with("```") {
+            flush(&mut paragraph, &mut output);
+            in_code = !in_code;
+            output.push(line.to_string());
+            continue;
+        }
+        if in_code {
+            output.push(line.to_string());
+            continue;
+        }
+
+        let lower = trimmed.to_lowercase();
+        if lower.contains("<table") {
+            flush(&mut paragraph, &mut output);
+            in_table = !lower.contains("</table>");
+            output.push(line.to_string());
+            continue;
+        }
+        if in_table {
+            if lower.contains("</table>") {
+                in_table = false;
+            }
+            output.push(line.to_string());
+            continue;
+        }
+
+        let is_break = trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || trimmed.starts_with('|')
+            || trimmed.starts_with("---")
+            || trimmed.starts_with("<|")
+            || is_list_item(trimmed);
+        if is_break {
+            flush(&mut paragraph, &mut output);
+            output.push(line.to_string());
+        } else {
+            paragraph.push(trimmed);
+        }
+    }
+    flush(&mut paragraph, &mut output);
+
+    output.join("\n")
+}
+
+/// Where the text of one source image starts in the cleaned plain-mode document.
+struct PlainSection {
+    /// First line of the image's text
+    line: usize,
+    /// Page size the image asks for, when its size was recorded
+    page_size: Option<PageSize>,
+    /// Source file name, when recorded
+    source: Option<String>,
+}
+
+/// Clean a document for plain mode, noting where the text of each source image
+/// starts along with its recorded page size and file name.
+fn clean_plain_sections(markdown: &str) -> (String, Vec<PlainSection>) {
+    let new_section = || (PlainSection { line: 0, page_size: None, source: None }, String::new());
+    let mut sections = vec![new_section()];
+    for line in markdown.lines() {
+        if line.trim().starts_with("---IMAGE_INDEX:") {
+            sections.push(new_section());
+        } else if let Some((section, text)) = sections.last_mut() {
+            if let Some(size) = parse_image_size_marker(line) {
+                section.page_size = Some(page_size_for_image(Some(size)));
+            } else if let Some(name) = parse_image_source_marker(line) {
+                section.source = Some(name.to_string());
+            } else {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+    }
+
+    let mut cleaned = String::new();
+    let mut starts = Vec::new();
+    for (mut section, text) in sections {
+        let text = clean_markdown_for_plain(&text);
+        if text.is_empty() {
+            continue;
+        }
+        if !cleaned.is_empty() {
+            cleaned.push_str("\n\n");
+        }
+        section.line = cleaned.lines().count();
+        starts.push(section);
+        cleaned.push_str(&text);
+    }
+    (cleaned, starts)
+}
+
 fn clean_markdown_for_plain(text: &str) -> String {
     // Remove ALL OCR tags including <|det|> for plain text mode
     // Remove ALL OCR tags including <|det|> for plain text mode
     let re_all_tags = Regex::new(r"<\|[^|]+\|>").unwrap();
-    let re_det_tags = Regex::new(r"<\|det\|>.*?<\|/det\|>").unwrap();
+    let re_det_tags = Regex::new(r"<\|det\|>.*?<\|/det\|>|<\|conf\|>.*?<\|/conf\|>").unwrap();
     let re_ref = Regex::new(r"(?s)<\|ref\|>.*?<\|/ref\|>").unwrap();
     let re_newlines = Regex::new(r"\n{3,}").unwrap();
     let re_empty = Regex::new(r"(?m)^[ \t]+$").unwrap();
     let re_page_break = Regex::new(r"(?m)^---PAGE_BREAK---\s*$").unwrap();
-    let re_image_index = Regex::new(r"(?m)^---IMAGE_INDEX:\d+---\s*$").unwrap();
+    let re_image_index = Regex::new(r"(?m)^---IMAGE_(?:INDEX:\d+|SOURCE:.*|SIZE:\d+x\d+|LANGUAGE:\w+)---\s*$").unwrap();
 
     let mut cleaned = text.to_string();
 
@@ -910,8 +5033,8 @@ fn clean_markdown_for_plain(text: &str) -> String {
 fn is_list_item(text: &str) -> bool {
     let trimmed = text.trim_start();
     // Check for explicit list markers ONLY
-    // Checkbox marker
-    if trimmed.starts_with("☐ ") {
+    // Checkbox markers, including GFM task items like "[x] done"
+    if task_state(trimmed).is_some() {
         return true;
     }
     // Bullet point marker
@@ -929,41 +5052,120 @@ fn is_list_item(text: &str) -> bool {
             return true;
         }
     }
-    // Numeric list: "1. " or "1) " at start
-    if trimmed.len() > 2 {
-        if let Some(first_char) = trimmed.chars().next() {
-            if first_char.is_numeric() {
-                if let Some(second_char) = trimmed.chars().nth(1) {
-                    if (second_char == '.' || second_char == ')') {
-                        if let Some(third_char) = trimmed.chars().nth(2) {
-                            if third_char.is_whitespace() {
-                                return true;
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    // Ordered list: "1. ", "b) ", "iv. " at start
+    if ordered_list_marker(trimmed).is_some() {
+        return true;
     }
     
     false
 }
 
+/// Kind of ordered-list counter, used to keep lettered and roman lists apart when splitting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OrderedMarker {
+    Numeric,
+    Letter { upper: bool },
+    Roman { upper: bool },
+}
+
+/// Classify an ordered-list counter such as "12", "b" or "iv" (the part before "." or ")").
+/// Single letters that are also roman numerals ("i", "v", "x") count as roman.
+fn ordered_marker_kind(counter: &str) -> Option<OrderedMarker> {
+    let re_roman = Regex::new(r"^(?i)x{0,3}(?:ix|iv|v?i{0,3})$").unwrap();
+    if counter.is_empty() {
+        return None;
+    }
+    if counter.chars().all(|c| c.is_ascii_digit()) {
+        return Some(OrderedMarker::Numeric);
+    }
+    let upper = counter.chars().all(|c| c.is_ascii_uppercase());
+    if !upper && !counter.chars().all(|c| c.is_ascii_lowercase()) {
+        return None;
+    }
+    if re_roman.is_match(counter) {
+        Some(OrderedMarker::Roman { upper })
+    } else if counter.len() == 1 {
+        Some(OrderedMarker::Letter { upper })
+    } else {
+        None
+    }
+}
+
+/// Leading ordered-list marker ("1.", "a)", "iv.") of a line, without the following space.
+fn ordered_list_marker(text: &str) -> Option<(&str, OrderedMarker)> {
+    let re_marker = Regex::new(r"^([0-9A-Za-z]+)[.)]\s").unwrap();
+    let cap = re_marker.captures(text)?;
+    let kind = ordered_marker_kind(&cap[1])?;
+    Some((&text[..cap[1].len() + 1], kind))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TaskState {
+    Unchecked,
+    Checked,
+}
+
+/// Checkbox state of a list item, from GFM task syntax (`- [ ]`, `- [x]`) or
+/// the Unicode ballot boxes (☐ unchecked, ☑/☒ checked).
+fn task_state(item: &str) -> Option<TaskState> {
+    let t = item.trim_start();
+    if t.starts_with("☐ ") {
+        return Some(TaskState::Unchecked);
+    }
+    if t.starts_with("☑ ") || t.starts_with("☒ ") {
+        return Some(TaskState::Checked);
+    }
+    let re_task = Regex::new(r"^(?:[-*+•]\s+)?\[([ xX])\]\s").unwrap();
+    re_task.captures(t).map(|cap| {
+        if &cap[1] == " " {
+            TaskState::Unchecked
+        } else {
+            TaskState::Checked
+        }
+    })
+}
+
+/// Remove a GFM task box ("[ ] " / "[x] ") left after `strip_leading_marker`.
+fn strip_task_box(text: &str) -> String {
+    let re_box = Regex::new(r"^\[[ xX]\]\s+").unwrap();
+    re_box.replace(text.trim_start(), "").to_string()
+}
+
 fn get_list_indent() -> f32 {
     4.0  // mm indent for list items
 }
 
 fn split_list_items(text: &str) -> Vec<String> {
     // Split a block that may contain multiple list items into separate items.
-    // Handles markers: ☐, •, -, *, numbered like "1." or "1)".
+    // Handles markers: ☐, •, -, *, numbered like "1." or "1)", lettered "a." and roman "iv.".
     let mut items: Vec<String> = Vec::new();
     let trimmed = text.trim();
     // If the line starts with a marker, try to split by occurrences of markers
-    let markers = vec!["☐ ", "• ", "- ", "* "]; 
+    let markers = vec!["☐ ", "☑ ", "☒ ", "• ", "- ", "* "]; 
 
     // Detect numeric list pattern like "1. " or "1) " using regex
     let re_num = Regex::new(r"(?P<prefix>\d+[\.)]\s)").unwrap();
 
+    // Lettered and roman lists only split at markers of the same kind as the first one,
+    // so ordinary words followed by a period stay put
+    if let Some((_, kind)) = ordered_list_marker(trimmed).filter(|(_, kind)| *kind != OrderedMarker::Numeric) {
+        let re_alpha = Regex::new(r"\s([A-Za-z]+)[.)]\s").unwrap();
+        let mut last = 0usize;
+        for cap in re_alpha.captures_iter(trimmed) {
+            if ordered_marker_kind(&cap[1]) != Some(kind) {
+                continue;
+            }
+            let start = cap.get(1).unwrap().start();
+            items.push(trimmed[last..start].trim().to_string());
+            last = start;
+        }
+        items.push(trimmed[last..].trim().to_string());
+        if items.len() > 1 {
+            return items;
+        }
+        items.clear();
+    }
+
     // First, check numeric markers
     if re_num.is_match(trimmed) {
         // split by occurrences of the numeric marker while keeping the marker
@@ -1033,27 +5235,58 @@ fn split_list_items(text: &str) -> Vec<String> {
 fn strip_leading_marker(s: &str) -> String {
     let t = s.trim();
     // Symbol markers (single unicode char + space)
-    if t.starts_with("☐ ") || t.starts_with("• ") || t.starts_with("- ") || t.starts_with("* ") {
+    if ["☐ ", "☑ ", "☒ ", "• ", "- ", "* "].iter().any(|m| t.starts_with(m)) {
         // skip the first char and the following space
         let without = t.chars().skip(1).collect::<String>();
         return without.trim_start().to_string();
     }
-    // Numeric markers
-    let re_num = Regex::new(r"^\s*\d+[\.)]\s").unwrap();
-    if re_num.is_match(t) {
-        return re_num.replace(t, "").to_string().trim().to_string();
+    // Numeric, lettered and roman markers
+    if let Some((marker, _)) = ordered_list_marker(t) {
+        return t[marker.len()..].trim().to_string();
     }
     t.to_string()
 }
 
+/// Alt text of a line that consists only of an image, either `![alt](src)` or
+/// an HTML `<img alt="...">`.
+fn image_caption(line: &str) -> Option<String> {
+    let re_markdown = Regex::new(r"^!\[([^\]]*)\]\([^)]*\)$").unwrap();
+    let re_html = Regex::new(r#"(?i)^<img\b[^>]*>$"#).unwrap();
+    let re_alt = Regex::new(r#"(?i)\balt\s*=\s*"([^"]*)""#).unwrap();
+
+    let line = line.trim();
+    if let Some(cap) = re_markdown.captures(line) {
+        return Some(cap[1].trim().to_string());
+    }
+    if re_html.is_match(line) {
+        return Some(re_alt.captures(line).map(|c| c[1].trim().to_string()).unwrap_or_default());
+    }
+    None
+}
+
 fn parse_html_tags(text: &str) -> (String, bool) {
     // Returns (cleaned_text, is_centered)
+    let (cleaned, is_centered) = parse_block_html(text);
+    let text = parse_styled_runs(&cleaned)
+        .into_iter()
+        .map(|run| run.text)
+        .collect::<String>();
+    (text.trim().to_string(), is_centered)
+}
+
+/// Like `parse_html_tags`, but inline style tags (`<font>`, `<span>`) are left in place
+/// for `parse_styled_runs`.
+fn parse_block_html(text: &str) -> (String, bool) {
     let re_center = Regex::new(r"</?center>").unwrap();
     let re_table_tags = Regex::new(r"</?(?:table|tr|td|th|thead|tbody)>").unwrap();
+    let re_inline_image = Regex::new(r"!\[([^\]]*)\]\([^)]*\)").unwrap();
 
     let is_centered = text.contains("<center>");
     let mut cleaned = text.to_string();
 
+    // Inline images become their alt text
+    cleaned = re_inline_image.replace_all(&cleaned, "$1").to_string();
+
     // Remove center tags
     cleaned = re_center.replace_all(&cleaned, "").to_string();
     // Remove table tags but keep content
@@ -1062,15 +5295,151 @@ fn parse_html_tags(text: &str) -> (String, bool) {
     (cleaned.trim().to_string(), is_centered)
 }
 
+/// Inline formatting of a run of text.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct RunStyle {
+    color: Option<[u8; 3]>,
+    underline: bool,
+    strikethrough: bool,
+    superscript: bool,
+}
+
+/// Size of superscript text relative to the surrounding text.
+const SUPERSCRIPT_SCALE: f32 = 0.65;
+
+impl RunStyle {
+    /// Width factor of this run relative to normal text of the same font size.
+    fn width_scale(&self) -> f32 {
+        if self.superscript { SUPERSCRIPT_SCALE } else { 1.0 }
+    }
+}
+
+/// Wrap footnote references in `<sup>` so they render raised: a bracketed number right
+/// after a word (`claim[3]`, not a `[3](link)`) and Unicode superscript digits (`text¹`).
+fn mark_footnotes(text: &str) -> String {
+    let re_bracket = Regex::new(r#"([\p{L}.,;:)"'’])\[(\d{1,3})\](\(?)"#).unwrap();
+    let re_superscript = Regex::new(r"[⁰¹²³⁴⁵⁶⁷⁸⁹]+").unwrap();
+
+    let text = re_bracket.replace_all(text, |cap: &regex::Captures| {
+        if &cap[3] == "(" {
+            cap[0].to_string()
+        } else {
+            format!("{}<sup>{}</sup>", &cap[1], &cap[2])
+        }
+    });
+    re_superscript
+        .replace_all(&text, |cap: &regex::Captures| {
+            let digits: String = cap[0]
+                .chars()
+                .map(|c| match c {
+                    '¹' => '1',
+                    '²' => '2',
+                    '³' => '3',
+                    '⁰' => '0',
+                    c => char::from_u32(c as u32 - 0x2070 + '0' as u32).unwrap_or(c),
+                })
+                .collect();
+            format!("<sup>{}</sup>", digits)
+        })
+        .into_owned()
+}
+
+/// A piece of a line sharing one `RunStyle`.
+struct StyledRun {
+    text: String,
+    style: RunStyle,
+}
+
+/// Split a line into styled runs at `<font color=...>`, `<span style="color: ...">`,
+/// `<u>`, `<s>`/`<strike>`/`<del>`, `<sup>` tags and markdown `~~strikethrough~~`.
+/// The tags themselves are dropped; tags without a style only nest.
+fn parse_styled_runs(text: &str) -> Vec<StyledRun> {
+    let re_strike = Regex::new(r"~~([^~]+)~~").unwrap();
+    let re_tag = Regex::new(r"(?i)<(/?)(font|span|u|s|strike|del|sup)\b([^>]*)>").unwrap();
+    let re_font_color = Regex::new(r#"(?i)\bcolor\s*=\s*["']?([^"'\s>]+)"#).unwrap();
+    let re_style_color = Regex::new(r#"(?i)(?:^|[;"'\s])color\s*:\s*([^;"']+)"#).unwrap();
+
+    let text = re_strike.replace_all(text, "<s>$1</s>");
+    let mut runs: Vec<StyledRun> = Vec::new();
+    // Open tags with the style in effect inside them
+    let mut stack: Vec<(String, RunStyle)> = Vec::new();
+    let push_text = |runs: &mut Vec<StyledRun>, piece: &str, style: RunStyle| {
+        if piece.is_empty() {
+            return;
+        }
+        match runs.last_mut() {
+            Some(last) if last.style == style => last.text.push_str(piece),
+            _ => runs.push(StyledRun { text: piece.to_string(), style }),
+        }
+    };
+
+    let mut last_end = 0;
+    for cap in re_tag.captures_iter(&text) {
+        let whole = cap.get(0).unwrap();
+        let current = stack.last().map(|(_, style)| *style).unwrap_or_default();
+        push_text(&mut runs, &text[last_end..whole.start()], current);
+        last_end = whole.end();
+
+        let tag = cap[2].to_lowercase();
+        if &cap[1] == "/" {
+            // Closing a tag also closes anything left open inside it
+            if let Some(index) = stack.iter().rposition(|(open, _)| *open == tag) {
+                stack.truncate(index);
+            }
+            continue;
+        }
+        let attrs = &cap[3];
+        let mut style = current;
+        match tag.as_str() {
+            "u" => style.underline = true,
+            "s" | "strike" | "del" => style.strikethrough = true,
+            "sup" => style.superscript = true,
+            "font" => style.color = re_font_color.captures(attrs).and_then(|c| parse_css_color(&c[1])).or(style.color),
+            _ => style.color = re_style_color.captures(attrs).and_then(|c| parse_css_color(&c[1])).or(style.color),
+        }
+        stack.push((tag, style));
+    }
+    let current = stack.last().map(|(_, style)| *style).unwrap_or_default();
+    push_text(&mut runs, &text[last_end..], current);
+    runs
+}
+
+/// Parse a CSS/HTML color: hex (#rgb, #rrggbb), rgb(r, g, b) or a common color name.
+fn parse_css_color(value: &str) -> Option<[u8; 3]> {
+    let value = value.trim().to_lowercase();
+    if value.starts_with('#') {
+        return parse_hex_color(&value).ok();
+    }
+    if let Some(args) = value.strip_prefix("rgb(").and_then(|v| v.strip_suffix(')')) {
+        let parts: Vec<u8> = args.split(',').filter_map(|p| p.trim().parse().ok()).collect();
+        return (parts.len() == 3).then(|| [parts[0], parts[1], parts[2]]);
+    }
+    let rgb = match value.as_str() {
+        "black" => [0, 0, 0],
+        "white" => [255, 255, 255],
+        "red" => [255, 0, 0],
+        "darkred" | "maroon" => [128, 0, 0],
+        "green" => [0, 128, 0],
+        "blue" => [0, 0, 255],
+        "navy" => [0, 0, 128],
+        "orange" => [255, 165, 0],
+        "yellow" => [255, 255, 0],
+        "purple" => [128, 0, 128],
+        "teal" => [0, 128, 128],
+        "brown" => [165, 42, 42],
+        "gray" | "grey" => [128, 128, 128],
+        _ => return None,
+    };
+    Some(rgb)
+}
+
 fn parse_markdown_headers(text: &str) -> (String, u8) {
     // Returns (text_without_header_markers, header_level)
     // header_level: 0=normal, 1=h1(#), 2=h2(##), 3=h3(###), etc.
     let trimmed = text.trim();
     let mut level = 0u8;
-    let mut chars = trimmed.chars();
-    
     // Count leading # characters
-    while let Some(ch) = chars.next() {
+    for ch in trimmed.chars() {
         if ch == '#' {
             level += 1;
         } else if ch.is_whitespace() {
@@ -1090,140 +5459,254 @@ fn parse_markdown_headers(text: &str) -> (String, u8) {
     }
 }
 
+/// Rows of an HTML table, repaired for common OCR slips: closing `</td>`/`</tr>` tags
+/// may be missing (a cell ends at the next cell or row tag), `colspan` cells are
+/// followed by empty cells, and short rows are padded so every row has the same length.
+/// Whether `text` opens an HTML table, with or without attributes (`<table border="1">`).
+fn has_table_tag(text: &str) -> bool {
+    let re_table = Regex::new(r"(?i)<table[\s>]").unwrap();
+    re_table.is_match(text)
+}
+
 fn parse_table_html(table_html: &str) -> Vec<Vec<String>> {
-    // Extract <tr> and <td> contents
+    let re_row = Regex::new(r"(?i)<tr\b[^>]*>").unwrap();
+    let re_cell = Regex::new(r"(?i)<t[dh]\b([^>]*)>").unwrap();
+    let re_cell_end = Regex::new(r"(?i)</t[dh]>|</tr>|</t(?:able|body|head|foot)>").unwrap();
+    let re_colspan = Regex::new(r#"(?i)colspan\s*=\s*["']?(\d+)"#).unwrap();
+
+    let row_tags: Vec<regex::Match> = re_row.find_iter(table_html).collect();
     let mut rows: Vec<Vec<String>> = Vec::new();
-    let re_row = Regex::new(r"(?si)<tr>(.*?)</tr>").unwrap();
-    let re_cell = Regex::new(r"(?si)<t[dh]>(.*?)</t[dh]>").unwrap();
+    for (i, row_tag) in row_tags.iter().enumerate() {
+        let row_end = row_tags.get(i + 1).map_or(table_html.len(), |next| next.start());
+        let row_body = &table_html[row_tag.end()..row_end];
 
-    for row_cap in re_row.captures_iter(table_html) {
-        let row_body = row_cap.get(1).map(|m| m.as_str()).unwrap_or("");
+        let cell_tags: Vec<regex::Captures> = re_cell.captures_iter(row_body).collect();
         let mut cols: Vec<String> = Vec::new();
-        for cell_cap in re_cell.captures_iter(row_body) {
-            let cell_text = cell_cap.get(1).map(|m| m.as_str()).unwrap_or("");
-            cols.push(cell_text.trim().to_string());
+        for (j, cell_cap) in cell_tags.iter().enumerate() {
+            let tag = cell_cap.get(0).unwrap();
+            let next_cell = cell_tags.get(j + 1).map_or(row_body.len(), |next| next.get(0).unwrap().start());
+            let content = &row_body[tag.end()..next_cell];
+            let content = re_cell_end.find(content).map_or(content, |end| &content[..end.start()]);
+            cols.push(content.trim().to_string());
+
+            let span = re_colspan
+                .captures(&cell_cap[1])
+                .and_then(|c| c[1].parse::<usize>().ok())
+                .unwrap_or(1);
+            cols.extend(std::iter::repeat_n(String::new(), span.saturating_sub(1)));
         }
         if !cols.is_empty() {
             rows.push(cols);
         }
     }
+
+    let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    for row in &mut rows {
+        row.resize(width, String::new());
+    }
     rows
 }
 
-fn render_table_plain(
-    rows: Vec<Vec<String>>,
-    current_layer: &PdfLayerReference,
-    font: &IndirectFontRef,
-    font_bold: &IndirectFontRef,
-    y_position: f32,
-    margin_left: f32,
-    usable_width: f32,
-) -> f32 {
-    // Render a table with column widths based on content (ASCII-style alignment)
-    if rows.is_empty() {
-        return y_position;
-    }
+/// For each column, whether any row has text in it.
+fn non_empty_columns(rows: &[Vec<String>]) -> Vec<bool> {
+    let num_cols = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    (0..num_cols)
+        .map(|i| rows.iter().any(|r| r.get(i).is_some_and(|cell| !cell.trim().is_empty())))
+        .collect()
+}
 
-    let cols = rows.get(0).map(|r| r.len()).unwrap_or(1) as usize;
-    
-    // Calculate max content width for each column
-    let mut col_max_chars: Vec<usize> = vec![0; cols];
-    for row in &rows {
-        for (col_idx, cell) in row.iter().enumerate() {
-            if col_idx < cols {
-                col_max_chars[col_idx] = col_max_chars[col_idx].max(cell.len());
+/// Drop columns that are empty in every row.
+fn drop_empty_columns(rows: &[Vec<String>]) -> Vec<Vec<String>> {
+    let keep = non_empty_columns(rows);
+    rows.iter()
+        .map(|r| r.iter().zip(&keep).filter(|(_, &k)| k).map(|(cell, _)| cell.clone()).collect())
+        .collect()
+}
+
+/// Every table in the document, in order: HTML `<table>` blocks and markdown
+/// pipe tables. Cell text is flattened (list items become "• " lines).
+fn extract_tables(markdown: &str) -> Vec<Vec<Vec<String>>> {
+    text_segments(&clean_markdown_for_plain(markdown))
+        .into_iter()
+        .filter_map(|segment| match segment {
+            TextSegment::Table { rows, .. } => Some(rows),
+            TextSegment::Line(_) => None,
+        })
+        .collect()
+}
+
+/// A line of plain-cleaned text, or a table found among the lines.
+enum TextSegment {
+    Line(String),
+    /// `aligns` comes from a pipe table's separator row; empty for HTML tables
+    Table { rows: Vec<Vec<String>>, aligns: Vec<ColumnAlign> },
+}
+
+/// Split plain-cleaned text into lines and tables (HTML `<table>` blocks and
+/// markdown pipe tables).
+fn text_segments(cleaned: &str) -> Vec<TextSegment> {
+    let re_tags = Regex::new(r"<[^>]+>").unwrap();
+
+    let lines: Vec<&str> = cleaned.lines().collect();
+    let mut segments = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let trimmed = lines[i].trim();
+
+        if has_table_tag(trimmed) {
+            let mut table_block = String::from(trimmed);
+            while !table_block.to_lowercase().contains("</table>") && i + 1 < lines.len() {
+                i += 1;
+                table_block.push('\n');
+                table_block.push_str(lines[i]);
+            }
+            let rows: Vec<Vec<String>> = parse_table_html(&table_block)
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|cell| re_tags.replace_all(&wrap_cell_text(cell, f32::INFINITY).join("\n"), "").trim().to_string())
+                        .collect()
+                })
+                .collect();
+            if !rows.is_empty() {
+                segments.push(TextSegment::Table { rows, aligns: Vec::new() });
             }
+        } else if trimmed.starts_with('|') {
+            let mut rows = Vec::new();
+            let mut aligns = Vec::new();
+            while i < lines.len() && lines[i].trim().starts_with('|') {
+                let row = lines[i].trim();
+                // The |---|:--:| header separator only carries the alignments
+                if let Some(separator) = pipe_table_alignments(row) {
+                    aligns = separator;
+                } else {
+                    let inner = row.trim_start_matches('|').trim_end_matches('|');
+                    rows.push(inner.split('|').map(|cell| cell.trim().to_string()).collect());
+                }
+                i += 1;
+            }
+            if !rows.is_empty() {
+                segments.push(TextSegment::Table { rows, aligns });
+            }
+            continue;
+        } else {
+            segments.push(TextSegment::Line(lines[i].to_string()));
         }
+        i += 1;
     }
-    
-    // Calculate proportional widths based on content
-    let total_chars: usize = col_max_chars.iter().sum();
-    let col_widths: Vec<f32> = if total_chars > 0 {
-        col_max_chars.iter().map(|&max_chars| {
-            (max_chars as f32 / total_chars as f32) * usable_width
-        }).collect()
-    } else {
-        vec![usable_width / (cols as f32); cols]
-    };
-    
-    let mut y = y_position;
-    let table_left = margin_left;
-    let table_right = table_left + usable_width;
-    let mut drew_top_border = false;
+    segments
+}
 
-    // Draw rows
-    for (row_idx, row) in rows.iter().enumerate() {
-        // Find max number of wrapped lines in this row
-        let mut max_lines = 1usize;
-        let mut cell_lines: Vec<Vec<String>> = Vec::new();
-        for (ci, cell) in row.iter().enumerate() {
-            let col_width = if ci < col_widths.len() { col_widths[ci] } else { 50.0 };
-            let approx_chars = ((col_width) / (10.0 * 0.5)) as usize; // assume 10pt font for table
-            let mut lines = Vec::new();
-            let mut cur = String::new();
-            for word in cell.split_whitespace() {
-                if cur.len() + word.len() + 1 > approx_chars && !cur.is_empty() {
-                    lines.push(cur.clone());
-                    cur.clear();
-                }
-                if !cur.is_empty() {
-                    cur.push(' ');
-                }
-                cur.push_str(word);
+/// Plain text for `--format text`: OCR tags stripped and every table drawn as an
+/// ASCII grid that follows the pipe table's column alignment.
+fn markdown_to_text(markdown: &str, trim_empty_columns: bool) -> String {
+    let mut text = String::new();
+    for segment in text_segments(&clean_markdown_for_plain(markdown)) {
+        match segment {
+            TextSegment::Line(line) => {
+                text.push_str(&line);
+                text.push('\n');
             }
-            if !cur.is_empty() {
-                lines.push(cur);
+            TextSegment::Table { rows, aligns } => {
+                // Multi-line cells (lists) would break the grid
+                let rows: Vec<Vec<String>> = rows
+                    .into_iter()
+                    .map(|row| row.into_iter().map(|cell| cell.replace('\n', " ")).collect())
+                    .collect();
+                for line in build_ascii_table(&rows, &aligns, trim_empty_columns) {
+                    text.push_str(&line);
+                    text.push('\n');
+                }
             }
-            max_lines = max_lines.max(lines.len());
-            cell_lines.push(lines);
         }
+    }
+    text
+}
 
-        // If not enough vertical space, skip (caller will manage page breaks)
-        let row_height = 8.0 * (max_lines as f32);
-        if y - row_height < 20.0 {
-            // Caller should add page; we simply return current y so they can add a page
-            break;
-        }
+fn table_to_csv(rows: &[Vec<String>], delimiter: u8) -> Result<String> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_writer(Vec::new());
+    for row in rows {
+        writer.write_record(row)?;
+    }
+    let bytes = writer.into_inner().context("Failed to flush CSV")?;
+    Ok(String::from_utf8(bytes)?)
+}
 
-        // Print each cell line-by-line
-        let is_header = row_idx == 0;  // First row is header
-        let cell_font = if is_header { font_bold } else { font };
-        let font_size = if is_header { 9.5 } else { 9.0 };
-        
-        for line_index in 0..max_lines {
-            let mut x = margin_left;
-            for (ci, lines) in cell_lines.iter().enumerate() {
-                let text = lines.get(line_index).cloned().unwrap_or_default();
-                current_layer.use_text(&text, font_size, Mm(x), Mm(y), cell_font);
-                let col_width = if ci < col_widths.len() { col_widths[ci] } else { 50.0 };
-                x += col_width;
-            }
-            y -= 8.0;
-        }
-        y -= 4.0; // small spacing after row
-        let row_top = y + row_height + 4.0;
-        let row_bottom = y + 4.0;
+/// Write the tables as CSV/TSV: to stdout separated by blank lines, to `output`
+/// when there is one table, or to numbered files next to `output` otherwise.
+fn write_tables(tables: &[Vec<Vec<String>>], output: Option<&Path>, delimiter: u8) -> Result<()> {
+    let Some(output) = output else {
+        let csvs = tables.iter().map(|t| table_to_csv(t, delimiter)).collect::<Result<Vec<_>>>()?;
+        print!("{}", csvs.join("\n"));
+        return Ok(());
+    };
 
-        if !drew_top_border {
-            draw_horizontal_line(current_layer, table_left, table_right, row_top + 1.0);
-            drew_top_border = true;
-        }
-        draw_horizontal_line(current_layer, table_left, table_right, row_bottom - 1.0);
+    if tables.len() == 1 {
+        fs::write(output, table_to_csv(&tables[0], delimiter)?)?;
+        println!("✓ Table saved to: {}", output.display());
+        return Ok(());
+    }
 
-        // Draw vertical lines at column boundaries using actual column widths
-        let mut x_line = margin_left;
-        draw_vertical_line(current_layer, x_line, row_top + 1.0, row_bottom - 1.0);
-        for col_idx in 0..cols {
-            let col_width = if col_idx < col_widths.len() { col_widths[col_idx] } else { 50.0 };
-            x_line += col_width;
-            draw_vertical_line(current_layer, x_line, row_top + 1.0, row_bottom - 1.0);
-        }
+    let stem = output.file_stem().and_then(|s| s.to_str()).unwrap_or("table");
+    let default_extension = if delimiter == b'\t' { "tsv" } else { "csv" };
+    let extension = output.extension().and_then(|e| e.to_str()).unwrap_or(default_extension);
+    for (i, table) in tables.iter().enumerate() {
+        let path = output.with_file_name(format!("{}_{}.{}", stem, i + 1, extension));
+        fs::write(&path, table_to_csv(table, delimiter)?)?;
+        println!("✓ Table {} saved to: {}", i + 1, path.display());
     }
+    Ok(())
+}
+
+/// Cell alignment of a table column, from a pipe table's `|:--|:-:|--:|` separator.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum ColumnAlign {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
 
-    y
+/// Column alignments of a pipe table separator row, or None when `row` isn't one.
+fn pipe_table_alignments(row: &str) -> Option<Vec<ColumnAlign>> {
+    let row = row.trim();
+    if !row.starts_with('|') || !row.contains('-') || !row.chars().all(|c| matches!(c, '|' | '-' | ':' | ' ')) {
+        return None;
+    }
+    let inner = row.trim_start_matches('|').trim_end_matches('|');
+    Some(
+        inner
+            .split('|')
+            .map(|cell| match (cell.trim().starts_with(':'), cell.trim().ends_with(':')) {
+                (true, true) => ColumnAlign::Center,
+                (false, true) => ColumnAlign::Right,
+                _ => ColumnAlign::Left,
+            })
+            .collect(),
+    )
 }
 
-fn build_ascii_table(rows: &[Vec<String>]) -> Vec<String> {
+/// ASCII grid of `rows`; `aligns` gives each column's alignment (missing ones are left-aligned).
+fn build_ascii_table(rows: &[Vec<String>], aligns: &[ColumnAlign], trim_empty_columns: bool) -> Vec<String> {
+    let trimmed;
+    let kept_aligns: Vec<ColumnAlign>;
+    let (rows, aligns) = if trim_empty_columns {
+        let keep = non_empty_columns(rows);
+        trimmed = drop_empty_columns(rows);
+        // Alignments follow their columns when empty ones are dropped
+        kept_aligns = keep
+            .iter()
+            .enumerate()
+            .filter(|(_, &k)| k)
+            .map(|(i, _)| aligns.get(i).copied().unwrap_or_default())
+            .collect();
+        (trimmed.as_slice(), kept_aligns.as_slice())
+    } else {
+        (rows, aligns)
+    };
     if rows.is_empty() {
         return Vec::new();
     }
@@ -1263,7 +5746,11 @@ fn build_ascii_table(rows: &[Vec<String>]) -> Vec<String> {
         line.push('|');
         for (i, width) in col_widths.iter().enumerate() {
             let cell_text = row.get(i).map(String::as_str).unwrap_or("");
-            let padded = format!(" {:width$} ", cell_text, width = *width);
+            let padded = match aligns.get(i).copied().unwrap_or_default() {
+                ColumnAlign::Left => format!(" {:<width$} ", cell_text, width = *width),
+                ColumnAlign::Center => format!(" {:^width$} ", cell_text, width = *width),
+                ColumnAlign::Right => format!(" {:>width$} ", cell_text, width = *width),
+            };
             line.push_str(&padded);
             line.push('|');
         }
@@ -1282,6 +5769,26 @@ fn draw_horizontal_line(layer: &PdfLayerReference, start_x: f32, end_x: f32, y:
     layer.add_line(line);
 }
 
+/// Draw a checkbox sitting on the text baseline at (x, y), with a check mark when checked.
+fn draw_checkbox(layer: &PdfLayerReference, x: f32, y: f32, font_size: f32, state: TaskState) {
+    let size = font_size * 0.352778 * 0.75;
+    let bottom = y - size * 0.1;
+    let top = bottom + size;
+    draw_horizontal_line(layer, x, x + size, bottom);
+    draw_horizontal_line(layer, x, x + size, top);
+    draw_vertical_line(layer, x, top, bottom);
+    draw_vertical_line(layer, x + size, top, bottom);
+
+    if state == TaskState::Checked {
+        let check = Line::from_iter(vec![
+            (Point::new(Mm(x + size * 0.2), Mm(bottom + size * 0.5)), false),
+            (Point::new(Mm(x + size * 0.42), Mm(bottom + size * 0.2)), false),
+            (Point::new(Mm(x + size * 0.82), Mm(bottom + size * 0.85)), false),
+        ]);
+        layer.add_line(check);
+    }
+}
+
 fn draw_vertical_line(layer: &PdfLayerReference, x: f32, y_top: f32, y_bottom: f32) {
     let line = Line::from_iter(vec![
         (Point::new(Mm(x), Mm(y_top)), false),
@@ -1290,6 +5797,118 @@ fn draw_vertical_line(layer: &PdfLayerReference, x: f32, y_top: f32, y_bottom: f
     layer.add_line(line);
 }
 
+/// Helvetica advance widths (1/1000 em) for printable ASCII, from the standard AFM metrics.
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, // space to /
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, // 0 to ?
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778, // @ to O
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556, // P to _
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556, // ` to o
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584, // p to ~
+];
+
+/// Share of a cell's width that text may fill; absorbs rounding and fonts given with
+/// --font, whose metrics differ slightly from Helvetica.
+const CELL_WIDTH_FILL: f32 = 0.95;
+
+/// Rendered width of `text` in em, using Helvetica metrics. Characters outside ASCII
+/// count as an average glyph, CJK as a full em.
+fn text_width_em(text: &str) -> f32 {
+    text.chars()
+        .map(|c| match c {
+            ' '..='~' => HELVETICA_WIDTHS[c as usize - 32] as f32 / 1000.0,
+            c if c >= '\u{2E80}' => 1.0,
+            _ => 0.556,
+        })
+        .sum()
+}
+
+/// Lines of a table cell wrapped to a column `col_width` mm wide. The row-height and
+/// drawing passes both go through here so their line counts always agree.
+fn cell_lines(cell: &str, col_width: f32, font_size: f32) -> Vec<String> {
+    let em_mm = font_size * 0.352778;
+    wrap_cell_text(cell, col_width * CELL_WIDTH_FILL / em_mm)
+}
+
+/// Split a table cell into rendered lines. Lists inside the cell (`<li>` items or
+/// plain list markers) get one bulleted line per item; everything is word-wrapped
+/// to `max_width_em`, with item continuations indented under the bullet.
+fn wrap_cell_text(cell: &str, max_width_em: f32) -> Vec<String> {
+    let re_li = Regex::new(r"(?si)<li[^>]*>(.*?)(?:</li>|$)").unwrap();
+    let re_list_tags = Regex::new(r"(?i)</?(?:ul|ol|li)[^>]*>").unwrap();
+
+    let items: Vec<String> = if cell.to_lowercase().contains("<li") {
+        re_li
+            .captures_iter(cell)
+            .map(|cap| re_list_tags.replace_all(&cap[1], "").trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    } else if is_list_item(cell) {
+        split_list_items(cell)
+            .iter()
+            .map(|item| strip_leading_marker(item))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let wrap = |text: &str, first_prefix: &str, next_prefix: &str, lines: &mut Vec<String>| {
+        let mut current_line = String::from(first_prefix);
+        let mut prefix_len = first_prefix.chars().count();
+        for word in text.split_whitespace() {
+            if text_width_em(&current_line) + text_width_em(word) + text_width_em(" ") > max_width_em
+                && current_line.chars().count() > prefix_len
+            {
+                lines.push(current_line.clone());
+                current_line = String::from(next_prefix);
+                prefix_len = next_prefix.chars().count();
+            }
+            if current_line.chars().count() > prefix_len {
+                current_line.push(' ');
+            }
+            current_line.push_str(word);
+        }
+        if current_line.chars().count() > prefix_len {
+            lines.push(current_line);
+        }
+    };
+
+    let mut lines = Vec::new();
+    if items.is_empty() {
+        wrap(cell, "", "", &mut lines);
+    } else {
+        for item in &items {
+            wrap(item, "• ", "  ", &mut lines);
+        }
+    }
+    lines
+}
+
+/// A table as one list of (header, value) pairs per data row, for `--flatten-tables`.
+/// Empty cells are skipped; columns without a header are labelled by number.
+fn flatten_table(rows: &[Vec<String>]) -> Vec<Vec<(String, String)>> {
+    let Some((header, body)) = rows.split_first() else {
+        return Vec::new();
+    };
+    body.iter()
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .filter(|(_, value)| !value.trim().is_empty())
+                .map(|(i, value)| {
+                    let label = header
+                        .get(i)
+                        .map(|h| h.trim())
+                        .filter(|h| !h.is_empty())
+                        .map_or_else(|| format!("Column {}", i + 1), str::to_string);
+                    (label, value.trim().to_string())
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
 fn render_html_table(
     layer: &PdfLayerReference,
     rows: &[Vec<String>],
@@ -1298,8 +5917,16 @@ fn render_html_table(
     max_width: f32,
     font: &IndirectFontRef,
     font_size: f32,
+    options: &PdfOptions,
 ) -> f32 {
     // Returns the Y position after the table
+    let trimmed;
+    let rows = if options.trim_whitespace_columns.unwrap_or(true) {
+        trimmed = drop_empty_columns(rows);
+        &trimmed
+    } else {
+        rows
+    };
     if rows.is_empty() {
         return start_y;
     }
@@ -1319,7 +5946,6 @@ fn render_html_table(
 
     // Calculate column widths in mm
     let pt_to_mm = 0.352778;
-    let avg_char_width = (font_size * 0.5 * pt_to_mm) as f32;
     let cell_padding = 0.5; // mm padding inside cells (left and right)
     let border_width = 1.0; // mm width for vertical borders
     
@@ -1332,14 +5958,20 @@ fn render_html_table(
     let total_chars: f32 = col_widths.iter().map(|w| *w as f32).sum();
     
     // col_widths_mm = actual content width for each column (without padding or borders)
-    let mut col_widths_mm = vec![0.0; num_cols];
-    for (i, width) in col_widths.iter().enumerate() {
-        col_widths_mm[i] = if total_chars > 0.0 {
-            (*width as f32 / total_chars) * available_width
-        } else {
-            available_width / num_cols as f32
-        };
-    }
+    let col_widths_mm: Vec<f32> = if let Some(overrides) = &options.table_columns {
+        overrides.distribute(&col_widths, available_width)
+    } else {
+        col_widths
+            .iter()
+            .map(|width| {
+                if total_chars > 0.0 {
+                    (*width as f32 / total_chars) * available_width
+                } else {
+                    available_width / num_cols as f32
+                }
+            })
+            .collect()
+    };
 
     let base_line_height = 5.5; // mm per line in a cell
     // In PDF, text is anchored at baseline. To center text vertically in the cell:
@@ -1347,7 +5979,7 @@ fn render_html_table(
     let text_center_y = (base_line_height / 2.0) + (font_size * 0.1 * pt_to_mm);
 
     let mut current_y = start_y;
-    let mut current_x = start_x;
+    let current_x = start_x;
 
     // First pass: Calculate row heights based on wrapped text
     let mut row_heights = Vec::new();
@@ -1355,24 +5987,8 @@ fn render_html_table(
         let mut max_lines_in_row = 1;
         for (col_idx, cell) in row.iter().enumerate() {
             if col_idx < col_widths_mm.len() {
-                let col_width = col_widths_mm[col_idx];
                 // col_width is pure content width without padding
-                // Be conservative with character width calculation to avoid overflow
-                let safety_factor = 0.85; // Leave 15% margin for safety
-                let max_chars_per_line = ((col_width * safety_factor) / avg_char_width).max(1.0) as usize;
-                
-                // Count lines needed for this cell
-                let words: Vec<&str> = cell.split_whitespace().collect();
-                let mut lines = 1;
-                let mut current_line_len = 0;
-                for word in words {
-                    if current_line_len + word.len() + 1 > max_chars_per_line && current_line_len > 0 {
-                        lines += 1;
-                        current_line_len = word.len();
-                    } else {
-                        current_line_len += word.len() + 1;
-                    }
-                }
+                let lines = cell_lines(cell, col_widths_mm[col_idx], font_size).len().max(1);
                 max_lines_in_row = max_lines_in_row.max(lines);
             }
         }
@@ -1395,27 +6011,7 @@ fn render_html_table(
         for (col_idx, cell) in row.iter().enumerate() {
             if col_idx < col_widths_mm.len() {
                 let col_width = col_widths_mm[col_idx]; // pure content width
-                // Be conservative with character width calculation to avoid overflow
-                let safety_factor = 0.85; // Leave 15% margin for safety
-                let max_chars_per_line = ((col_width * safety_factor) / avg_char_width).max(1.0) as usize;
-                
-                // Wrap text into multiple lines if needed
-                let words: Vec<&str> = cell.split_whitespace().collect();
-                let mut text_lines = Vec::new();
-                let mut current_line = String::new();
-                for word in words {
-                    if current_line.len() + word.len() + 1 > max_chars_per_line && !current_line.is_empty() {
-                        text_lines.push(current_line.clone());
-                        current_line.clear();
-                    }
-                    if !current_line.is_empty() {
-                        current_line.push(' ');
-                    }
-                    current_line.push_str(word);
-                }
-                if !current_line.is_empty() {
-                    text_lines.push(current_line);
-                }
+                let text_lines = cell_lines(cell, col_width, font_size);
                 
                 // Draw each line of text in the cell with proper padding
                 let cell_text_x = cell_x + cell_padding;
@@ -1444,33 +6040,78 @@ fn convert_markdown_to_pdf(
     markdown: &str,
     output_path: &Path,
     use_coordinates: bool,
+    options: &PdfOptions,
+) -> Result<()> {
+    write_markdown_pdf(markdown, output_path, use_coordinates, options)?;
+    if let Some(dir) = &options.page_images {
+        render_page_images(output_path, dir, options.page_image_dpi)?;
+    }
+    Ok(())
+}
+
+/// Render markdown into a PDF at `output_path`, without `--page-images`.
+fn write_markdown_pdf(
+    markdown: &str,
+    output_path: &Path,
+    use_coordinates: bool,
+    options: &PdfOptions,
 ) -> Result<()> {
     println!(
         "convert_markdown_to_pdf: use_coordinates={} output={}",
         use_coordinates,
         output_path.display()
     );
+    // Front matter is metadata, not content: skip it and use its title for the PDF
+    let (front_matter, body) = split_front_matter(markdown);
+    let title = front_matter
+        .as_ref()
+        .and_then(|fm| fm.get("title"))
+        .filter(|t| !t.is_empty())
+        .unwrap_or("OCR Document");
+
+    let target = PdfTarget::new(title);
+    render_markdown_pdf(body, &target, use_coordinates, options)?;
+    println!("convert_markdown_to_pdf: saving PDF to {}", output_path.display());
+    target.save(output_path, options)
+}
+
+/// Draw markdown without front matter onto new pages of `target`.
+fn render_markdown_pdf(body: &str, target: &PdfTarget, use_coordinates: bool, options: &PdfOptions) -> Result<()> {
     if use_coordinates {
-        convert_with_coordinates(markdown, output_path)
+        convert_with_coordinates(body, target, options)
     } else {
-        convert_plain_text(markdown, output_path)
+        convert_plain_text(body, target, options)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 struct TextBlock {
     text: String,
     x: f32,
     y: f32,
-    _width: f32,
+    width: f32,
     height: f32,
     force_page_break: bool, // True if this block should start on a new page
     image_index: usize,     // Index of source image (for grouping before sorting)
+    confidence: Option<f32>, // Model confidence (0-1) from a <|conf|> tag, when emitted
 }
 
-fn parse_ocr_blocks(markdown: &str) -> Vec<TextBlock> {
-    let mut blocks = Vec::new();
+/// `process-markdown --format blocks-json`: the prose and the layout it came from.
+#[derive(Serialize)]
+struct BlocksExport {
+    /// Text cleaned of every OCR tag, as for plain output
+    markdown: String,
+    /// Boxes in document order, in the model's coordinates
+    blocks: Vec<TextBlock>,
+}
+
+/// Lines under one box are joined with spaces into a paragraph, or with newlines
+/// when `merge_lines` is off (poetry, addresses).
+fn parse_ocr_blocks(markdown: &str, merge_lines: bool) -> Vec<TextBlock> {
+    let mut blocks: Vec<TextBlock> = Vec::new();
     println!("parse_ocr_blocks: Processing {} bytes of markdown", markdown.len());
+    // Ref labels go first: one spanning lines would otherwise end up in the block text
+    let markdown = strip_ref_tags(markdown);
     let lines: Vec<&str> = markdown.lines().collect();
     let mut next_block_needs_page_break = false;
     let mut current_image_index = 0;
@@ -1520,15 +6161,16 @@ fn parse_ocr_blocks(markdown: &str) -> Vec<TextBlock> {
                     }
 
                     if !text_lines.is_empty() {
-                        let text = text_lines.join(" ");
+                        let text = text_lines.join(if merge_lines { " " } else { "\n" });
                         blocks.push(TextBlock {
                             text,
                             x: coords[0],
                             y: coords[1],
-                            _width: coords[2] - coords[0],
+                            width: coords[2] - coords[0],
                             height: coords[3] - coords[1],
                             force_page_break: next_block_needs_page_break,
                             image_index: current_image_index,
+                            confidence: parse_confidence(line),
                         });
                         next_block_needs_page_break = false; // Reset flag after use
                     }
@@ -1545,56 +6187,458 @@ fn parse_ocr_blocks(markdown: &str) -> Vec<TextBlock> {
     blocks
 }
 
+/// Coordinate grid used by DeepSeek-OCR boxes; `--box-units fraction` boxes are scaled onto it.
+const COORDINATE_GRID: f32 = 999.0;
+
+/// Map boxes given in pixels onto the coordinate grid, per source image, using the
+/// image sizes recorded in the markdown; the layout scale assumes grid units.
+/// `--box-units fraction` boxes are scaled up directly.
+fn normalize_pixel_boxes(blocks: &mut [TextBlock], image_sizes: &HashMap<usize, (u32, u32)>, units: BoxUnits) {
+    if units == BoxUnits::Grid {
+        return;
+    }
+    if units == BoxUnits::Fraction {
+        for block in blocks.iter_mut() {
+            block.x *= COORDINATE_GRID;
+            block.width *= COORDINATE_GRID;
+            block.y *= COORDINATE_GRID;
+            block.height *= COORDINATE_GRID;
+        }
+        return;
+    }
+    let mut images: Vec<usize> = blocks.iter().map(|block| block.image_index).collect();
+    images.sort_unstable();
+    images.dedup();
+    for image_index in images {
+        let in_pixels = units == BoxUnits::Pixels
+            || blocks
+                .iter()
+                .filter(|block| block.image_index == image_index)
+                .any(|block| block.x + block.width > COORDINATE_GRID + 1.0 || block.y + block.height > COORDINATE_GRID + 1.0);
+        if !in_pixels {
+            continue;
+        }
+        let Some(&(width, height)) = image_sizes.get(&image_index).filter(|(w, h)| *w > 0 && *h > 0) else {
+            println!("⚠ Boxes of image {} are in pixels but its size is unknown; laying them out as grid units", image_index);
+            continue;
+        };
+        let (scale_x, scale_y) = (COORDINATE_GRID / width as f32, COORDINATE_GRID / height as f32);
+        for block in blocks.iter_mut().filter(|block| block.image_index == image_index) {
+            block.x *= scale_x;
+            block.width *= scale_x;
+            block.y *= scale_y;
+            block.height *= scale_y;
+        }
+    }
+}
+
+/// `--page-break-gap`: a large empty band inside one image separates stitched pages.
+/// Runs after `normalize_pixel_boxes`, so `gap` is in grid units.
+fn mark_gap_page_breaks(blocks: &mut [TextBlock], gap: f32) {
+    for i in 1..blocks.len() {
+        let prev = &blocks[i - 1];
+        if prev.image_index == blocks[i].image_index && blocks[i].y - (prev.y + prev.height) > gap {
+            blocks[i].force_page_break = true;
+        }
+    }
+}
+
+/// Text heights closer than this ratio count as the same font size when combining blocks.
+const COMBINE_HEIGHT_RATIO: f32 = 1.3;
+
+/// Merge runs of neighbouring blocks (in document order) that continue one another:
+/// the next line below with overlapping columns, or the next box to the right on the same line.
+/// Headers, lists and tables are never merged. The merged box keeps the line height.
+fn combine_adjacent_blocks(blocks: Vec<TextBlock>, max_gap: f32) -> Vec<TextBlock> {
+    let is_structural = |text: &str| {
+        text.trim_start().starts_with('#') || text.to_lowercase().contains("<table") || is_list_item(text)
+    };
+
+    let mut combined: Vec<TextBlock> = Vec::new();
+    // Bottom edge of each combined block; `height` stays the height of one line
+    let mut bottoms: Vec<f32> = Vec::new();
+    for block in blocks {
+        let mergeable = combined.last().zip(bottoms.last()).is_some_and(|(prev, &prev_bottom)| {
+            if block.image_index != prev.image_index
+                || block.force_page_break
+                || is_structural(&prev.text)
+                || is_structural(&block.text)
+            {
+                return false;
+            }
+            let (short, tall) = (prev.height.min(block.height), prev.height.max(block.height));
+            if short <= 0.0 || tall / short > COMBINE_HEIGHT_RATIO {
+                return false;
+            }
+            let prev_right = prev.x + prev.width;
+            let block_right = block.x + block.width;
+            let columns_overlap = block.x < prev_right && block_right > prev.x;
+            let next_line = columns_overlap && (-short / 2.0..=max_gap).contains(&(block.y - prev_bottom));
+            let same_line = (block.y - prev.y).abs() < short / 2.0 && (0.0..=max_gap).contains(&(block.x - prev_right));
+            next_line || same_line
+        });
+
+        if mergeable {
+            let (prev, prev_bottom) = (combined.last_mut().unwrap(), bottoms.last_mut().unwrap());
+            let right = (prev.x + prev.width).max(block.x + block.width);
+            prev.text = format!("{} {}", prev.text, block.text);
+            prev.x = prev.x.min(block.x);
+            prev.y = prev.y.min(block.y);
+            prev.width = right - prev.x;
+            prev.height = prev.height.max(block.height);
+            // A merged block is only as reliable as its weakest part
+            prev.confidence = match (prev.confidence, block.confidence) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            *prev_bottom = prev_bottom.max(block.y + block.height);
+        } else {
+            bottoms.push(block.y + block.height);
+            combined.push(block);
+        }
+    }
+    combined
+}
+
 fn parse_coordinates(coords_str: &str) -> Option<[f32; 4]> {
-    // Parse [[x1, y1, x2, y2]] format
+    // Accepts [[x1, y1, x2, y2]], [x1, y1, x2, y2] and several boxes [[..], [..]],
+    // which are merged into their union bounding box
+    let re_box = Regex::new(r"\[([^\[\]]*)\]").unwrap();
     let coords_str = coords_str.trim();
-    if !coords_str.starts_with("[[") || !coords_str.ends_with("]]") {
+    if !coords_str.starts_with('[') || !coords_str.ends_with(']') {
         return None;
     }
 
-    let inner = &coords_str[2..coords_str.len() - 2];
-    let parts: Vec<&str> = inner.split(',').collect();
+    let mut union: Option<[f32; 4]> = None;
+    for cap in re_box.captures_iter(coords_str) {
+        let values: Vec<f32> = cap[1]
+            .split(',')
+            .map(|part| part.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .ok()?;
+        let [x1, y1, x2, y2] = <[f32; 4]>::try_from(values).ok()?;
+        let bbox = [x1.min(x2), y1.min(y2), x1.max(x2), y1.max(y2)];
+        union = Some(match union {
+            Some(u) => [u[0].min(bbox[0]), u[1].min(bbox[1]), u[2].max(bbox[2]), u[3].max(bbox[3])],
+            None => bbox,
+        });
+    }
+
+    union
+}
 
-    if parts.len() != 4 {
-        return None;
+/// Confidence from a `<|conf|>0.87<|/conf|>` tag on a box line; percentages are scaled to 0-1.
+fn parse_confidence(line: &str) -> Option<f32> {
+    let re_conf = Regex::new(r"<\|conf\|>\s*([0-9.]+)\s*%?\s*<\|/conf\|>").unwrap();
+    let value: f32 = re_conf.captures(line)?[1].parse().ok()?;
+    Some(if value > 1.0 { value / 100.0 } else { value })
+}
+
+/// `--color-by-confidence` buckets: below LOW is red, below HIGH is orange, the rest black.
+const LOW_CONFIDENCE: f32 = 0.5;
+const HIGH_CONFIDENCE: f32 = 0.8;
+
+fn confidence_color(confidence: Option<f32>) -> printpdf::Color {
+    let (r, g, b) = match confidence {
+        Some(c) if c < LOW_CONFIDENCE => (0.85, 0.0, 0.0),
+        Some(c) if c < HIGH_CONFIDENCE => (0.9, 0.5, 0.0),
+        _ => (0.0, 0.0, 0.0),
+    };
+    printpdf::Color::Rgb(printpdf::Rgb::new(r, g, b, None))
+}
+
+const PAGE_WIDTH_MM: f32 = 210.0;
+const PAGE_HEIGHT_MM: f32 = 297.0;
+
+/// Page width and height in mm.
+type PageSize = (f32, f32);
+
+/// A4 page size in mm, turned landscape for source images wider than they are tall.
+fn page_size_for_image(dimensions: Option<(u32, u32)>) -> PageSize {
+    match dimensions {
+        Some((width, height)) if width > height => (PAGE_HEIGHT_MM, PAGE_WIDTH_MM),
+        _ => (PAGE_WIDTH_MM, PAGE_HEIGHT_MM),
     }
+}
 
-    let mut coords = [0.0; 4];
-    for (i, part) in parts.iter().enumerate() {
-        if let Ok(val) = part.trim().parse::<f32>() {
-            coords[i] = val;
-        } else {
-            return None;
+/// Source image sizes recorded by `combine_pages`, keyed by image index.
+fn parse_image_sizes(markdown: &str) -> HashMap<usize, (u32, u32)> {
+    let mut sizes = HashMap::new();
+    let mut image_index = 0;
+    for line in markdown.lines() {
+        let line = line.trim();
+        if let Some(idx) = line.strip_prefix("---IMAGE_INDEX:").and_then(|l| l.strip_suffix("---")) {
+            image_index = idx.trim().parse().unwrap_or(image_index);
+        } else if let Some(size) = parse_image_size_marker(line) {
+            sizes.insert(image_index, size);
         }
     }
+    sizes
+}
 
-    Some(coords)
+/// Source file names recorded by `combine_pages`, keyed by image index.
+fn parse_image_sources(markdown: &str) -> HashMap<usize, String> {
+    let mut sources = HashMap::new();
+    let mut image_index = 0;
+    for line in markdown.lines() {
+        let line = line.trim();
+        if let Some(idx) = line.strip_prefix("---IMAGE_INDEX:").and_then(|l| l.strip_suffix("---")) {
+            image_index = idx.trim().parse().unwrap_or(image_index);
+        } else if let Some(name) = parse_image_source_marker(line) {
+            sources.insert(image_index, name.to_string());
+        }
+    }
+    sources
 }
 
-fn convert_with_coordinates(markdown: &str, output_path: &Path) -> Result<()> {
-    use printpdf::*;
-    println!(
-        "convert_with_coordinates: starting. output={}",
-        output_path.display()
-    );
-    let blocks = parse_ocr_blocks(markdown);
+fn parse_image_source_marker(line: &str) -> Option<&str> {
+    line.trim().strip_prefix("---IMAGE_SOURCE:")?.strip_suffix("---")
+}
 
-    if blocks.is_empty() {
-        return convert_plain_text(markdown, output_path);
+fn parse_image_size_marker(line: &str) -> Option<(u32, u32)> {
+    let size = line.trim().strip_prefix("---IMAGE_SIZE:")?.strip_suffix("---")?;
+    let (width, height) = size.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// The `--template` letterhead as JPEG data with its pixel size.
+#[derive(Clone, Debug)]
+struct Letterhead {
+    width: u32,
+    height: u32,
+    jpeg: std::sync::Arc<Vec<u8>>,
+}
+
+/// Resolution the first page of a PDF `--template` is rendered at.
+const TEMPLATE_DPI: u32 = 150;
+
+/// Load the `--template` while the arguments are parsed, so it is read once per run.
+fn parse_template(path: &str) -> Result<Letterhead, String> {
+    load_template(Path::new(path)).map_err(|e| format!("{:#}", e))
+}
+
+fn load_template(path: &Path) -> Result<Letterhead> {
+    let img = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pdf")) {
+        let temp_dir = std::env::temp_dir().join(format!("ocr_template_{}", std::process::id()));
+        fs::create_dir_all(&temp_dir)?;
+        let prefix = temp_dir.join("template");
+        let rendered = render_pdf_pages(path, prefix.to_str().context("Invalid temp path")?, TEMPLATE_DPI)
+            .and_then(|()| collect_image_files(&temp_dir))
+            .and_then(|pages| {
+                let first = pages.first().with_context(|| format!("{} has no pages", path.display()))?;
+                Ok(image::open(first)?)
+            });
+        let _ = fs::remove_dir_all(&temp_dir);
+        rendered.with_context(|| format!("Failed to render template {}", path.display()))?
+    } else {
+        image::open(path).with_context(|| format!("Failed to read template {}", path.display()))?
+    };
+    // Embedded as JPEG so the background stays small
+    let rgb = image::DynamicImage::ImageRgb8(img.to_rgb8());
+    let mut jpeg = Vec::new();
+    rgb.write_with_encoder(image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 90))?;
+    Ok(Letterhead { width: rgb.width(), height: rgb.height(), jpeg: std::sync::Arc::new(jpeg) })
+}
+
+/// Save a rendered document. printpdf embeds an image anew each time it is drawn, so
+/// with a `--template` the copies are folded into one XObject shared by every page.
+fn save_pdf(doc: printpdf::PdfDocumentReference, output_path: &Path, options: &PdfOptions) -> Result<()> {
+    if options.template.is_none() {
+        doc.save(&mut std::io::BufWriter::new(fs::File::create(output_path)?))?;
+        return Ok(());
     }
+    let mut pdf = lopdf::Document::load_mem(&doc.save_to_bytes()?)?;
+    let shared = share_duplicate_images(&mut pdf);
+    println!("🖼 Letterhead embedded once, shared by {} page(s)", shared + 1);
+    pdf.save(output_path).with_context(|| format!("Failed to write {}", output_path.display()))?;
+    Ok(())
+}
 
-    let page_width = Mm(210.0);
-    let page_height = Mm(297.0);
-    let margin = 5.0; // Margen muy reducido
-    let usable_width = 200.0; // Casi toda la página
-    let usable_height = 287.0;
+/// Point every reference to an image XObject identical to an earlier one at that
+/// earlier object and drop the copies. Returns the number of copies removed.
+fn share_duplicate_images(pdf: &mut lopdf::Document) -> usize {
+    use lopdf::Object;
+
+    fn redirect(object: &mut Object, replacements: &HashMap<lopdf::ObjectId, lopdf::ObjectId>) {
+        match object {
+            Object::Reference(id) => {
+                if let Some(original) = replacements.get(id) {
+                    *id = *original;
+                }
+            }
+            Object::Array(items) => items.iter_mut().for_each(|item| redirect(item, replacements)),
+            Object::Dictionary(dict) => dict.iter_mut().for_each(|(_, value)| redirect(value, replacements)),
+            Object::Stream(stream) => stream.dict.iter_mut().for_each(|(_, value)| redirect(value, replacements)),
+            _ => {}
+        }
+    }
+
+    let mut originals: HashMap<(Vec<u8>, String), lopdf::ObjectId> = HashMap::new();
+    let mut replacements = HashMap::new();
+    for (&id, object) in &pdf.objects {
+        let Object::Stream(stream) = object else {
+            continue;
+        };
+        if !matches!(stream.dict.get(b"Subtype").and_then(Object::as_name), Ok(b"Image")) {
+            continue;
+        }
+        let key = (stream.content.clone(), format!("{:?}", stream.dict));
+        match originals.get(&key) {
+            Some(&original) => {
+                replacements.insert(id, original);
+            }
+            None => {
+                originals.insert(key, id);
+            }
+        }
+    }
+    for id in replacements.keys() {
+        pdf.objects.remove(id);
+    }
+    for object in pdf.objects.values_mut() {
+        redirect(object, &replacements);
+    }
+    replacements.len()
+}
+
+/// Paint the page background, letterhead template and watermark. Called right
+/// after a page is created so that everything else is drawn on top.
+fn decorate_page(layer: &PdfLayerReference, options: &PdfOptions, font: &IndirectFontRef, page_size: PageSize) {
+    use printpdf::{path::PaintMode, Color, Rect, Rgb, TextMatrix};
+    let (page_width, page_height) = page_size;
+
+    if let Some([r, g, b]) = options.background {
+        layer.save_graphics_state();
+        layer.set_fill_color(Color::Rgb(Rgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, None)));
+        layer.add_rect(Rect::new(Mm(0.0), Mm(0.0), Mm(page_width), Mm(page_height)).with_mode(PaintMode::Fill));
+        layer.restore_graphics_state();
+    }
 
-    let (doc, page1, layer1) = PdfDocument::new("OCR Document", page_width, page_height, "Layer 1");
+    if let Some(Letterhead { width, height, jpeg }) = &options.template {
+        use printpdf::{ColorBits, ColorSpace, Image, ImageFilter, ImageTransform, ImageXObject, Px};
+        const DPI: f32 = 300.0;
+        let natural_mm = |px: u32| px as f32 / DPI * 25.4;
+        let image = Image::from(ImageXObject {
+            width: Px(*width as usize),
+            height: Px(*height as usize),
+            color_space: ColorSpace::Rgb,
+            bits_per_component: ColorBits::Bit8,
+            interpolate: true,
+            image_data: jpeg.to_vec(),
+            image_filter: Some(ImageFilter::DCT),
+            smask: None,
+            clipping_bbox: None,
+        });
+        image.add_to_layer(
+            layer.clone(),
+            ImageTransform {
+                translate_x: Some(Mm(0.0)),
+                translate_y: Some(Mm(0.0)),
+                scale_x: Some(page_width / natural_mm(*width)),
+                scale_y: Some(page_height / natural_mm(*height)),
+                dpi: Some(DPI),
+                ..Default::default()
+            },
+        );
+    }
+
+    if let Some(text) = options.watermark.as_deref().filter(|t| !t.trim().is_empty()) {
+        // Size the text to fit the page diagonal, then center it along that diagonal
+        let angle = (page_height / page_width).atan();
+        let diagonal_mm = (page_width.powi(2) + page_height.powi(2)).sqrt() * 0.7;
+        let pt_to_mm = 0.352778_f32;
+        let chars = text.chars().count() as f32;
+        let font_size = (diagonal_mm / (chars * 0.6 * pt_to_mm)).min(96.0);
+        let text_width_mm = chars * font_size * 0.6 * pt_to_mm;
+        let start_x = page_width / 2.0 - angle.cos() * text_width_mm / 2.0;
+        let start_y = page_height / 2.0 - angle.sin() * text_width_mm / 2.0;
+
+        layer.save_graphics_state();
+        layer.set_fill_color(Color::Rgb(Rgb::new(0.85, 0.85, 0.85, None)));
+        layer.begin_text_section();
+        layer.set_font(font, font_size);
+        layer.set_text_matrix(TextMatrix::TranslateRotate(Mm(start_x).into(), Mm(start_y).into(), angle.to_degrees()));
+        layer.write_text(text, font);
+        layer.end_text_section();
+        layer.restore_graphics_state();
+    }
+}
+
+/// Label the page with the name of its source image (`--annotate-sources`), in small
+/// grey type in the top margin starting at `x` mm.
+fn draw_source_label(layer: &PdfLayerReference, font: &IndirectFontRef, source: &str, x: f32, page_height: f32) {
+    use printpdf::{Color, Rgb};
+
+    layer.save_graphics_state();
+    layer.set_fill_color(Color::Rgb(Rgb::new(0.6, 0.6, 0.6, None)));
+    layer.use_text(source, 6.0, Mm(x), Mm(page_height - 4.0), font);
+    layer.restore_graphics_state();
+}
+
+const HEADER_HEIGHT_RATIO: f32 = 1.5;
+const MAX_INFERRED_HEADER_CHARS: usize = 80;
+
+/// Vertical gaps between blocks larger than this many median line heights are kept
+/// as section breaks in coordinate mode.
+const SECTION_GAP_LINES: f32 = 1.0;
+
+fn median_block_height(blocks: &[TextBlock]) -> f32 {
+    let mut heights: Vec<f32> = blocks
+        .iter()
+        .filter(|b| b.height > 0.0 && !has_table_tag(&b.text))
+        .map(|b| b.height)
+        .collect();
+    if heights.is_empty() {
+        return 0.0;
+    }
+    heights.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    heights[heights.len() / 2]
+}
 
-    let font = doc.add_builtin_font(BuiltinFont::Helvetica)?;
-    let font_bold = doc.add_builtin_font(BuiltinFont::HelveticaBold)?;
-    let mono_font = doc.add_builtin_font(BuiltinFont::Courier)?;
-    let mut current_layer = doc.get_page(page1).get_layer(layer1);
+/// Header level for a block without '#' markers, judged by how much taller it
+/// is than the median block. Long blocks are tall because they wrap, not because
+/// they are headings, so they are left alone.
+fn infer_header_level(height: f32, median_height: f32, text: &str) -> u8 {
+    if median_height <= 0.0 || text.trim().chars().count() > MAX_INFERRED_HEADER_CHARS {
+        return 0;
+    }
+    let ratio = height / median_height;
+    if ratio >= HEADER_HEIGHT_RATIO * 1.5 {
+        1
+    } else if ratio >= HEADER_HEIGHT_RATIO {
+        2
+    } else {
+        0
+    }
+}
+
+fn convert_with_coordinates(markdown: &str, target: &PdfTarget, options: &PdfOptions) -> Result<()> {
+    use printpdf::*;
+    println!("convert_with_coordinates: starting");
+    let min_font_size = options.min_font_size.unwrap_or(DEFAULT_MIN_FONT_SIZE);
+    let max_font_size = options.max_font_size.unwrap_or(DEFAULT_MAX_FONT_SIZE);
+    if !(min_font_size > 0.0 && min_font_size <= max_font_size) {
+        anyhow::bail!(
+            "--min-font-size ({}) must be positive and at most --max-font-size ({})",
+            min_font_size,
+            max_font_size
+        );
+    }
+    let mut blocks = parse_ocr_blocks(markdown, options.merge_lines_within_block.unwrap_or(true));
+    let image_sizes = parse_image_sizes(markdown);
+    normalize_pixel_boxes(&mut blocks, &image_sizes, options.box_units);
+    if let Some(gap) = options.page_break_gap {
+        mark_gap_page_breaks(&mut blocks, gap);
+    }
+    if let Some(max_gap) = options.combine_adjacent_blocks {
+        let before = blocks.len();
+        blocks = combine_adjacent_blocks(blocks, max_gap);
+        println!("convert_with_coordinates: combined {} blocks into {}", before, blocks.len());
+    }
+
+    if blocks.is_empty() {
+        return convert_plain_text(markdown, target, options);
+    }
 
     // Group blocks by image_index, then sort within each group by Y position
     let mut sorted_blocks = blocks.clone();
@@ -1609,16 +6653,43 @@ fn convert_with_coordinates(markdown: &str, output_path: &Path) -> Result<()> {
         }
     });
 
+    // Each source image gets a page in its own orientation when its size is known
+    let page_size = |image_index: usize| page_size_for_image(image_sizes.get(&image_index).copied());
+    let (mut page_width, mut page_height) = page_size(sorted_blocks[0].image_index);
+    let margin = 5.0; // Margen muy reducido
+    let mut usable_width = page_width - 2.0 * margin; // Casi toda la página
+    let mut usable_height = page_height - 2.0 * margin;
+
+    let mut current_layer = target.open_page((page_width, page_height), options)?;
+    let PdfFonts { regular: font, bold: font_bold, .. } = target.fonts().clone();
+    decorate_page(&current_layer, options, &font_bold, (page_width, page_height));
+    let image_sources = parse_image_sources(markdown);
+    let label_page = |layer: &PdfLayerReference, image_index: usize, page_height: f32| {
+        if let Some(source) = image_sources.get(&image_index).filter(|_| options.annotate_sources) {
+            draw_source_label(layer, &font, source, margin, page_height);
+        }
+    };
+    label_page(&current_layer, sorted_blocks[0].image_index, page_height);
+
+    // Typical line height, used to spot headings that carry no '#' marker
+    let median_height = median_block_height(&sorted_blocks);
+
     let mut page_start_y = 0.0;
-    let scale = 0.20; // Escala muy reducida para evitar que los bloques ocupen demasiado
     
     // Track last Y position per column to allow side-by-side layout
-    // Determine column boundaries based on actual OCR block positions, not fixed page center
-    let mut last_y_left = 0.0;
-    let mut last_y_right = 0.0;
+    let mut column_thresholds = if options.column_thresholds.is_empty() {
+        vec![DEFAULT_COLUMN_THRESHOLD]
+    } else {
+        options.column_thresholds.clone()
+    };
+    column_thresholds.sort_by(|a, b| a.total_cmp(b));
+    let mut column_last_y = vec![0.0; column_thresholds.len() + 1];
+    // Bottom edge (source coordinates) of the previous block in each column
+    let mut column_last_bottom: Vec<Option<f32>> = vec![None; column_thresholds.len() + 1];
     
     // Track previous block Y to detect new images (Y coordinate resets)
     let mut prev_block_y = 0.0;
+    let mut prev_image_index = 0;
     let mut force_new_page = false;
 
     for block in sorted_blocks {
@@ -1632,17 +6703,23 @@ fn convert_with_coordinates(markdown: &str, output_path: &Path) -> Result<()> {
         if prev_block_y > 100.0 && block.y < prev_block_y - 50.0 {
             force_new_page = true;
         }
+        // A break inside one image (e.g. --page-break-gap) keeps counting Y from the image top
+        let same_coordinates = block.image_index == prev_image_index && block.y > prev_block_y;
         prev_block_y = block.y;
+        prev_image_index = block.image_index;
         
         // Check for list item BEFORE any processing
         let is_list = is_list_item(&block.text);
         
         // Check if this is a table BEFORE cleaning HTML tags
-        let is_table = block.text.to_lowercase().contains("<table>");
+        let is_table = has_table_tag(&block.text);
         
         // Parse markdown headers FIRST, before cleaning HTML
         let cleaned_text = clean_markdown(&block.text);
-        let (text_with_header, header_level) = parse_markdown_headers(&cleaned_text);
+        let (text_with_header, mut header_level) = parse_markdown_headers(&cleaned_text);
+        if header_level == 0 && !is_table && !is_list {
+            header_level = infer_header_level(block.height, median_height, &text_with_header);
+        }
         let (text, _) = if !is_table {
             parse_html_tags(&text_with_header)
         } else {
@@ -1654,51 +6731,70 @@ fn convert_with_coordinates(markdown: &str, output_path: &Path) -> Result<()> {
             continue;
         }
 
-        let x_mm = (block.x * scale + margin).min(usable_width);
+        // Boxes are on the 0-999 grid by now: one image spans the usable height of its page
+        let (_, image_page_height) = page_size(block.image_index);
+        let scale = (image_page_height - 2.0 * margin) / COORDINATE_GRID;
         let block_y_mm = block.y * scale;
 
         // Force new page if we detected a new image (Y coordinate reset or explicit marker)
         if force_new_page {
-            let (page, layer) = doc.add_page(page_width, page_height, "Layer 1");
-            current_layer = doc.get_page(page).get_layer(layer);
-            page_start_y = 0.0;  // Reset to 0 so blocks start fresh from top with proper margin
-            last_y_left = 0.0;
-            last_y_right = 0.0;
+            (page_width, page_height) = page_size(block.image_index);
+            usable_width = page_width - 2.0 * margin;
+            usable_height = page_height - 2.0 * margin;
+            current_layer = target.add_page((page_width, page_height));
+            decorate_page(&current_layer, options, &font_bold, (page_width, page_height));
+            label_page(&current_layer, block.image_index, page_height);
+            // Reset to 0 so blocks start fresh from top with proper margin,
+            // or to this block when the page continues the same coordinate space
+            page_start_y = if same_coordinates { block_y_mm - margin } else { 0.0 };
+            column_last_y.fill(0.0);
+            column_last_bottom.fill(None);
             force_new_page = false;
         }
 
         // Check if we need a new page due to content overflow
         if block_y_mm - page_start_y > usable_height {
-            let (page, layer) = doc.add_page(page_width, page_height, "Layer 1");
-            current_layer = doc.get_page(page).get_layer(layer);
+            current_layer = target.add_page((page_width, page_height));
+            decorate_page(&current_layer, options, &font_bold, (page_width, page_height));
+            label_page(&current_layer, block.image_index, page_height);
             page_start_y = 0.0;  // Reset to 0 for clean start on new page
-            last_y_left = 0.0;
-            last_y_right = 0.0;
+            column_last_y.fill(0.0);
+            column_last_bottom.fill(None);
         }
 
+        // ... and its usable width, landscape pages spreading it over their extra width
+        let width_ratio = page_width / PAGE_WIDTH_MM;
+        let scale_x = usable_width / COORDINATE_GRID;
+        let x_mm = (block.x * scale_x + margin).min(usable_width);
+
         let relative_y = block_y_mm - page_start_y;
-        let mut y_mm = (page_height.0 - margin - relative_y).max(margin);
+        let mut y_mm = (page_height - margin - relative_y).max(margin);
 
-        // Determine column based on X position - use 95mm threshold instead of page center
-        // This better accommodates varying column widths
-        let is_left_column = x_mm < 95.0;
+        // Determine column from the X position: the number of boundaries left of the block
+        let column = column_thresholds.iter().filter(|t| x_mm >= **t * width_ratio).count();
         
         // Calculate base font size first to use for spacing
-        let base_font_size = ((block.height * scale * 0.5).max(6.0).min(10.0)) as f32;
+        let base_font_size = (block.height * scale * 0.5).clamp(min_font_size, max_font_size);
         
         // Dynamic spacing based on font size: approximately 1.5x line height in mm
         // Convert points to mm: 1 pt ≈ 0.3528 mm
         let min_spacing = (base_font_size * 0.3528 * 1.5).max(2.5);
         
-        // Ensure minimum spacing from previous text in SAME COLUMN
-        if is_left_column {
-            if last_y_left > 0.0 && last_y_left - y_mm < min_spacing {
-                y_mm = last_y_left - min_spacing;
-            }
+        // A source gap taller than a line marks a section break: keep at least that gap
+        // below the previous text even when overflowing lines pushed this column down
+        let source_gap_mm = column_last_bottom[column]
+            .map_or(0.0, |bottom| (block.y - bottom).max(0.0) * scale);
+        let spacing = if source_gap_mm > median_height * scale * SECTION_GAP_LINES {
+            min_spacing.max(source_gap_mm)
         } else {
-            if last_y_right > 0.0 && last_y_right - y_mm < min_spacing {
-                y_mm = last_y_right - min_spacing;
-            }
+            min_spacing
+        };
+        column_last_bottom[column] = Some(block.y + block.height);
+
+        // Ensure minimum spacing from previous text in SAME COLUMN
+        let last_y = column_last_y[column];
+        if last_y > 0.0 && last_y - y_mm < spacing {
+            y_mm = last_y - spacing;
         }
 
         // Determine font size based on header level
@@ -1718,35 +6814,35 @@ fn convert_with_coordinates(markdown: &str, output_path: &Path) -> Result<()> {
 
         // Text wrapping: use the block's actual OCR width, ensuring it fits on page
         // Limit column width to prevent overflow
-        let max_column_width = 95.0; // Máximo ~95mm por columna (deja espacio para 2 columnas)
-        let available_width_to_right = (page_width.0 - margin - x_mm).max(20.0);
-        let desired_block_width = (block._width * scale).max(25.0);
+        let max_column_width = 95.0 * width_ratio; // Máximo ~95mm por columna (deja espacio para 2 columnas)
+        let available_width_to_right = (page_width - margin - x_mm).max(20.0);
+        let desired_block_width = (block.width * scale_x).max(25.0);
         // Limitar al mínimo de: ancho del bloque OCR, ancho disponible, y máximo de columna
         let block_width_mm = desired_block_width.min(available_width_to_right).min(max_column_width);
         
         // Compute char width in mm - use actual font width estimation
         let pt_to_mm = 0.352778;
-        let avg_char_width_mm = (font_size as f32) * 0.50 * pt_to_mm;
+        let avg_char_width_mm = font_size * 0.50 * pt_to_mm;
         let max_chars = if avg_char_width_mm > 0.0 {
             ((block_width_mm / avg_char_width_mm) as usize).max(15)
         } else {
             60
         };
 
+        let block_color = options.color_by_confidence.then(|| confidence_color(block.confidence));
+        if let Some(color) = &block_color {
+            current_layer.set_fill_color(color.clone());
+        }
+
         // Check for tables FIRST before processing as list or regular text
-        if text.to_lowercase().contains("<table>") {
+        if has_table_tag(&text) {
             // Parse html table and render with HTML borders
             let rows = parse_table_html(&text);
             if !rows.is_empty() {
                 let table_font_size = 8.0;
-                let final_y = render_html_table(&current_layer, &rows, x_mm, y_mm, block_width_mm, &font, table_font_size);
+                let final_y = render_html_table(&current_layer, &rows, x_mm, y_mm, block_width_mm, &font, table_font_size, options);
                 
-                // Update last_y for the correct column
-                if is_left_column {
-                    last_y_left = final_y;
-                } else {
-                    last_y_right = final_y;
-                }
+                column_last_y[column] = final_y;
             }
         } else if is_list {
             // Split into list items only if we already know this is a list
@@ -1756,14 +6852,23 @@ fn convert_with_coordinates(markdown: &str, output_path: &Path) -> Result<()> {
             let body_font = &font;
             let bullet_pt = base_font_size.max(8.0);
             let pt_to_mm = 0.352778;
-            let avg_char_width_mm = (bullet_pt * 0.5 * pt_to_mm as f32) as f32;
+            let avg_char_width_mm = bullet_pt * 0.5 * pt_to_mm;
             let bullet_offset = avg_char_width_mm * 2.0;
             let mut item_y = y_mm;
             for item in items {
-                let mut item_text = strip_leading_marker(&item);
+                let item_text = strip_leading_marker(&item);
 
-                // Draw bold bullet
-                current_layer.use_text("•", bullet_pt as f32, Mm(x_mm), Mm(item_y), bullet_font);
+                // Draw bold bullet, or the item's own marker for ordered lists
+                let bullet_offset = match ordered_list_marker(item.trim()) {
+                    Some((marker, _)) => {
+                        current_layer.use_text(marker, bullet_pt, Mm(x_mm), Mm(item_y), body_font);
+                        bullet_offset.max((marker.len() + 1) as f32 * avg_char_width_mm)
+                    }
+                    None => {
+                        current_layer.use_text("•", bullet_pt, Mm(x_mm), Mm(item_y), bullet_font);
+                        bullet_offset
+                    }
+                };
 
                 // Wrap item_text similarly to normal wrapping but shifted by bullet_offset
                 let max_chars_item = max_chars; // reuse char estimation
@@ -1776,10 +6881,14 @@ fn convert_with_coordinates(markdown: &str, output_path: &Path) -> Result<()> {
                         line_y -= base_font_size * 0.35;
                         current_line.clear();
                         if line_y < margin {
-                            let (page, layer) = doc.add_page(page_width, page_height, "Layer 1");
-                            current_layer = doc.get_page(page).get_layer(layer);
+                            current_layer = target.add_page((page_width, page_height));
+                            decorate_page(&current_layer, options, &font_bold, (page_width, page_height));
+                            label_page(&current_layer, block.image_index, page_height);
+                            if let Some(color) = &block_color {
+                                current_layer.set_fill_color(color.clone());
+                            }
                             page_start_y = block_y_mm;
-                            line_y = page_height.0 - margin - 10.0;
+                            line_y = page_height - margin - 10.0;
                         }
                     }
                     if !current_line.is_empty() {
@@ -1789,17 +6898,30 @@ fn convert_with_coordinates(markdown: &str, output_path: &Path) -> Result<()> {
                 }
                 if !current_line.is_empty() {
                     current_layer.use_text(&current_line, base_font_size, Mm(x_mm + bullet_offset), Mm(line_y), body_font);
-                    // update last_y accordingly
-                    if is_left_column {
-                        last_y_left = line_y - base_font_size * 0.35;
-                    } else {
-                        last_y_right = line_y - base_font_size * 0.35;
-                    }
+                    column_last_y[column] = line_y - base_font_size * 0.35;
                     item_y = line_y;
                 }
                 // small gap after each item
                 item_y -= (base_font_size * 0.35) + 1.0;
             }
+        } else if text.contains('\n') {
+            // Lines kept by --merge-lines-within-block=false are drawn as written
+            let mut line_y = y_mm;
+            for line in text.lines().filter(|l| !l.trim().is_empty()) {
+                if line_y < margin {
+                    current_layer = target.add_page((page_width, page_height));
+                    decorate_page(&current_layer, options, &font_bold, (page_width, page_height));
+                    label_page(&current_layer, block.image_index, page_height);
+                    if let Some(color) = &block_color {
+                        current_layer.set_fill_color(color.clone());
+                    }
+                    page_start_y = block_y_mm;
+                    line_y = page_height - margin - 10.0;
+                }
+                current_layer.use_text(line.trim(), font_size, Mm(x_mm), Mm(line_y), current_font);
+                column_last_y[column] = line_y - font_size * 0.35;
+                line_y -= font_size * 0.35;
+            }
         } else if text.len() > max_chars {
             // Use pre-detected list status for indentation
             let list_indent = if is_list { get_list_indent() } else { 0.0 };
@@ -1809,89 +6931,482 @@ fn convert_with_coordinates(markdown: &str, output_path: &Path) -> Result<()> {
             let mut current_line = String::new();
             let mut line_y = y_mm;
 
-            for word in words {
-                if current_line.len() + word.len() + 1 > max_chars && !current_line.is_empty() {
-                    current_layer.use_text(&current_line, font_size, Mm(render_x), Mm(line_y), current_font);
-                    line_y -= font_size * 0.35; // Slightly tighter line spacing
-                    current_line.clear();
+            for word in words {
+                if current_line.len() + word.len() + 1 > max_chars && !current_line.is_empty() {
+                    current_layer.use_text(&current_line, font_size, Mm(render_x), Mm(line_y), current_font);
+                    line_y -= font_size * 0.35; // Slightly tighter line spacing
+                    current_line.clear();
+
+                    // Check if wrapped text goes to new page
+                    if line_y < margin {
+                        current_layer = target.add_page((page_width, page_height));
+                        decorate_page(&current_layer, options, &font_bold, (page_width, page_height));
+                        label_page(&current_layer, block.image_index, page_height);
+                        if let Some(color) = &block_color {
+                            current_layer.set_fill_color(color.clone());
+                        }
+                        page_start_y = block_y_mm;
+                        line_y = page_height - margin - 10.0;
+                    }
+                }
+                if !current_line.is_empty() {
+                    current_line.push(' ');
+                }
+                current_line.push_str(word);
+            }
+
+            if !current_line.is_empty() {
+                current_layer.use_text(&current_line, font_size, Mm(render_x), Mm(line_y), current_font);
+                column_last_y[column] = line_y - font_size * 0.35;
+            }
+        } else {
+            // Use pre-detected list status for indentation
+            let list_indent = if is_list { get_list_indent() } else { 0.0 };
+            let render_x = x_mm + list_indent;
+            
+            current_layer.use_text(&text, font_size, Mm(render_x), Mm(y_mm), current_font);
+            column_last_y[column] = y_mm - font_size * 0.35;
+        }
+        if block_color.is_some() {
+            current_layer.set_fill_color(confidence_color(None));
+        }
+    }
+
+    Ok(())
+}
+
+/// Smallest font `--no-wrap shrink` scales down to.
+const MIN_NO_WRAP_FONT_SIZE: f32 = 4.0;
+
+/// A line as drawn by `--no-wrap`: tags stripped, indentation kept (tabs as 4 spaces).
+fn no_wrap_text(line: &str) -> String {
+    let content = line.trim_start();
+    let indent = line[..line.len() - content.len()].replace('\t', "    ");
+    format!("{}{}", indent, parse_html_tags(content.trim_end()).0)
+}
+
+/// Whether a line carries on the body text of the block above it (for `--preserve-breaks`)
+/// rather than starting a heading, list, table or image.
+fn continues_block(line: &str) -> bool {
+    let trimmed = line.trim();
+    !trimmed.is_empty()
+        && !trimmed.starts_with('#')
+        && !is_list_item(trimmed)
+        && !trimmed.to_lowercase().contains("<table")
+        && image_caption(trimmed).is_none()
+}
+
+/// Fewest consecutive lines that count as a space-aligned table.
+const MIN_ALIGNED_ROWS: usize = 3;
+
+/// Replace tabs with spaces up to the next multiple-of-8 column.
+fn expand_tabs(line: &str) -> String {
+    let mut out = String::new();
+    for c in line.chars() {
+        if c == '\t' {
+            let pad = 8 - out.chars().count() % 8;
+            out.push_str(&" ".repeat(pad));
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Cells of a line separated by two or more spaces (or tabs), as (start, end) char columns.
+fn aligned_cells(line: &str) -> Vec<(usize, usize)> {
+    let chars: Vec<char> = expand_tabs(line).chars().collect();
+    let mut cells: Vec<(usize, usize)> = Vec::new();
+    let mut spaces = 0;
+    for (col, &c) in chars.iter().enumerate() {
+        if c == ' ' {
+            spaces += 1;
+            continue;
+        }
+        match cells.last_mut() {
+            Some(cell) if spaces < 2 => cell.1 = col + 1,
+            _ => cells.push((col, col + 1)),
+        }
+        spaces = 0;
+    }
+    cells
+}
+
+/// Number of leading lines that form a table laid out with spaces: at least
+/// `MIN_ALIGNED_ROWS` lines with the same number (2+) of cells, where every cell
+/// after the first starts or ends in the same column on each line. 0 otherwise, so
+/// prose with the odd double space is left alone.
+fn aligned_column_rows(lines: &[&str]) -> usize {
+    let is_candidate = |line: &str| {
+        let trimmed = line.trim();
+        !trimmed.is_empty() && !trimmed.starts_with(['#', '|', '<'])
+    };
+    let Some(first) = lines.first().filter(|l| is_candidate(l)).map(|l| aligned_cells(l)) else {
+        return 0;
+    };
+    if first.len() < 2 {
+        return 0;
+    }
+    let near = |a: usize, b: usize| a.abs_diff(b) <= 1;
+    let rows = lines
+        .iter()
+        .take_while(|line| {
+            if !is_candidate(line) {
+                return false;
+            }
+            let cells = aligned_cells(line);
+            cells.len() == first.len()
+                && cells[1..]
+                    .iter()
+                    .zip(&first[1..])
+                    .all(|(&(start, end), &(first_start, first_end))| near(start, first_start) || near(end, first_end))
+        })
+        .count();
+    if rows >= MIN_ALIGNED_ROWS {
+        rows
+    } else {
+        0
+    }
+}
+
+/// Headers and tables keep their normal rendering under `--no-wrap`.
+fn is_no_wrap_exempt(trimmed: &str) -> bool {
+    trimmed.starts_with("# ")
+        || trimmed.starts_with("## ")
+        || trimmed.starts_with("### ")
+        || trimmed.starts_with("#### ")
+        || has_table_tag(trimmed)
+}
+
+/// Plain-mode margins in mm (top, right, bottom, left) without `--content-margins`.
+const DEFAULT_PLAIN_MARGINS: [f32; 4] = [17.0, 5.0, 20.0, 5.0];
+
+/// Where plain-text rendering currently is: body pages started so far and, with
+/// `--two-up`, whether text runs in the right half of the sheet.
+struct PlainCursor {
+    pages: usize,
+    right_half: bool,
+}
+
+/// Continue plain-text rendering in the next content area: the right half of the
+/// sheet with `--two-up`, otherwise a new page. Returns the new left margin.
+fn next_plain_page(
+    target: &PdfTarget,
+    layer: &mut PdfLayerReference,
+    options: &PdfOptions,
+    font: &IndirectFontRef,
+    page_size: PageSize,
+    cursor: &mut PlainCursor,
+    source: Option<&str>,
+) -> f32 {
+    let margin_left = options.plain_margins()[3];
+    let margin_left = if options.two_up && !cursor.right_half {
+        cursor.right_half = true;
+        page_size.0 / 2.0 + margin_left
+    } else {
+        *layer = target.add_page(page_size);
+        decorate_page(layer, options, font, page_size);
+        cursor.right_half = false;
+        cursor.pages += 1;
+        margin_left
+    };
+    if let Some(source) = source.filter(|_| options.annotate_sources) {
+        draw_source_label(layer, font, source, margin_left, page_size.1);
+    }
+    margin_left
+}
+
+/// One line of the `--index-page` index: a source page's heading and where it starts.
+struct IndexEntry {
+    label: String,
+    /// Body page number, counted from 1 without the index pages
+    page: usize,
+}
 
-                    // Check if wrapped text goes to new page
-                    if line_y < margin {
-                        let (page, layer) = doc.add_page(page_width, page_height, "Layer 1");
-                        current_layer = doc.get_page(page).get_layer(layer);
-                        page_start_y = block_y_mm;
-                        line_y = page_height.0 - margin - 10.0;
-                    }
-                }
-                if !current_line.is_empty() {
-                    current_line.push(' ');
-                }
-                current_line.push_str(word);
-            }
+/// Line spacing of `--index-page` entries in mm.
+const INDEX_LINE_STEP: f32 = 6.0;
 
-            if !current_line.is_empty() {
-                current_layer.use_text(&current_line, font_size, Mm(render_x), Mm(line_y), current_font);
-                // Update last_y for the correct column
-                if is_left_column {
-                    last_y_left = line_y - font_size * 0.35;
-                } else {
-                    last_y_right = line_y - font_size * 0.35;
-                }
-            }
+/// Index label of one source page: its first heading, or its first line of text
+/// when it has none.
+fn index_label(lines: &[&str]) -> Option<String> {
+    let texts: Vec<String> = lines
+        .iter()
+        .map(|line| parse_block_html(line.trim()).0)
+        .filter(|text| !text.trim().is_empty())
+        .collect();
+    let text = texts.iter().find(|text| text.starts_with('#')).or(texts.first())?;
+    let plain: String = parse_styled_runs(text.trim_start_matches('#').trim()).into_iter().map(|run| run.text).collect();
+    Some(plain.split_whitespace().collect::<Vec<_>>().join(" ")).filter(|label| !label.is_empty())
+}
+
+/// Number of portrait pages the `--index-page` index needs for `entries` lines.
+fn index_page_count(entries: usize, options: &PdfOptions) -> usize {
+    let [margin_top, _, margin_bottom, _] = options.plain_margins();
+    // Rows run from the top margin down to the bottom one, both included
+    let rows = ((PAGE_HEIGHT_MM - margin_top - margin_bottom) / INDEX_LINE_STEP).floor() as usize + 1;
+    // The heading on the first page takes up two rows
+    (entries + 2).div_ceil(rows).max(1)
+}
+
+/// Draw the `--index-page` index starting on `layer`, adding pages as needed.
+/// Listed page numbers include the index pages themselves.
+fn draw_index_pages(
+    target: &PdfTarget,
+    mut layer: PdfLayerReference,
+    options: &PdfOptions,
+    font: &IndirectFontRef,
+    font_bold: &IndirectFontRef,
+    entries: &[IndexEntry],
+) {
+    let page_size = (PAGE_WIDTH_MM, PAGE_HEIGHT_MM);
+    let [margin_top, margin_right, margin_bottom, margin_left] = options.plain_margins();
+    let index_pages = index_page_count(entries.len(), options);
+    let font_size = 10.0;
+    let char_width = font_size * 0.5 * 0.352778;
+    // Helvetica's period is narrower than the average character
+    let dot_width = font_size * 0.278 * 0.352778;
+    let right_edge = PAGE_WIDTH_MM - margin_right;
+
+    decorate_page(&layer, options, font_bold, page_size);
+    layer.use_text("Contents", 16.0, Mm(margin_left), Mm(PAGE_HEIGHT_MM - margin_top), font_bold);
+    let mut y_position = PAGE_HEIGHT_MM - margin_top - 2.0 * INDEX_LINE_STEP;
+    for entry in entries {
+        if y_position < margin_bottom {
+            layer = target.add_page(page_size);
+            decorate_page(&layer, options, font_bold, page_size);
+            y_position = PAGE_HEIGHT_MM - margin_top;
+        }
+        let number = (entry.page + index_pages).to_string();
+        let number_width = number.len() as f32 * char_width;
+        // Long labels are cut short so the dot leader and page number still fit
+        let max_chars = ((right_edge - margin_left - number_width - 8.0 * dot_width) / char_width).max(1.0) as usize;
+        let label = if entry.label.chars().count() > max_chars {
+            format!("{}…", entry.label.chars().take(max_chars.saturating_sub(1)).collect::<String>())
         } else {
-            // Use pre-detected list status for indentation
-            let list_indent = if is_list { get_list_indent() } else { 0.0 };
-            let render_x = x_mm + list_indent;
-            
-            current_layer.use_text(&text, font_size, Mm(render_x), Mm(y_mm), current_font);
-            // Update last_y for the correct column
-            if is_left_column {
-                last_y_left = y_mm - font_size * 0.35;
-            } else {
-                last_y_right = y_mm - font_size * 0.35;
+            entry.label.clone()
+        };
+        let label_end = margin_left + label.chars().count() as f32 * char_width;
+        let leader = ".".repeat(((right_edge - number_width - label_end) / dot_width).max(0.0) as usize);
+        layer.use_text(label, font_size, Mm(margin_left), Mm(y_position), font);
+        layer.use_text(leader, font_size, Mm(label_end), Mm(y_position), font);
+        layer.use_text(number, font_size, Mm(right_edge - number_width), Mm(y_position), font);
+        y_position -= INDEX_LINE_STEP;
+    }
+}
+
+/// Body font size of `--vertical` output in points.
+const VERTICAL_FONT_SIZE: f32 = 12.0;
+/// Page margin of `--vertical` output in mm when `--content-margins` isn't given.
+const VERTICAL_MARGIN: f32 = 15.0;
+
+/// Vertical presentation form of punctuation that sits differently in vertical text.
+fn vertical_form(c: char) -> char {
+    match c {
+        '、' => '︑',
+        '。' => '︒',
+        '，' => '︐',
+        '：' => '︓',
+        '；' => '︔',
+        '！' => '︕',
+        '？' => '︖',
+        '…' => '︙',
+        '（' | '(' => '︵',
+        '）' | ')' => '︶',
+        '「' => '﹁',
+        '」' => '﹂',
+        '『' => '﹃',
+        '』' => '﹄',
+        '【' => '︻',
+        '】' => '︼',
+        '〈' => '︿',
+        '〉' => '﹀',
+        'ー' | '—' => '︱',
+        _ => c,
+    }
+}
+
+/// Render cleaned plain text in vertical columns (`--vertical`): each character is
+/// drawn on its own below the previous one, each paragraph starts a new column to the
+/// left of the last, and a full page continues on the next.
+fn convert_vertical_text(cleaned: &str, target: &PdfTarget, options: &PdfOptions) -> Result<()> {
+    use printpdf::*;
+
+    let page_size = (PAGE_WIDTH_MM, PAGE_HEIGHT_MM);
+    let mut layer = target.open_page(page_size, options)?;
+    let PdfFonts { regular: font, bold: font_bold, .. } = target.fonts().clone();
+    decorate_page(&layer, options, &font_bold, page_size);
+
+    // --content-margins applies here too; 15mm all round otherwise
+    let [margin_top, margin_right, margin_bottom, margin_left] = options.content_margins.unwrap_or([VERTICAL_MARGIN; 4]);
+    let char_mm = VERTICAL_FONT_SIZE * 0.352778;
+    let column_step = char_mm * 1.5;
+    let top = page_size.1 - margin_top;
+    let first_column = page_size.0 - margin_right - char_mm;
+    let mut x = first_column;
+    let mut y = top;
+    let mut column_used = false;
+
+    for line in cleaned.lines() {
+        let trimmed = line.trim();
+        // Blank lines and rules: paragraphs are already set apart by their columns
+        if trimmed.chars().all(|c| c == '-' || c == '*' || c == '_') {
+            continue;
+        }
+        let text = parse_html_tags(trimmed.trim_start_matches('#').trim()).0;
+        for c in text.chars() {
+            if c.is_whitespace() && !column_used {
+                continue;
+            }
+            if y - char_mm < margin_bottom {
+                x -= column_step;
+                y = top;
+            }
+            if x < margin_left {
+                layer = target.add_page(page_size);
+                decorate_page(&layer, options, &font_bold, page_size);
+                x = first_column;
+                y = top;
             }
+            y -= char_mm;
+            layer.use_text(vertical_form(c).to_string(), VERTICAL_FONT_SIZE, Mm(x), Mm(y), &font);
+            column_used = true;
+        }
+        // Each paragraph starts a column of its own
+        if column_used {
+            x -= column_step;
+            y = top;
+            column_used = false;
         }
     }
 
-    println!(
-        "convert_with_coordinates: saving PDF to {}",
-        output_path.display()
-    );
-    doc.save(&mut std::io::BufWriter::new(std::fs::File::create(
-        output_path,
-    )?))?;
-
     Ok(())
 }
 
-fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
-    use printpdf::*;
+fn convert_plain_text(markdown: &str, target: &PdfTarget, options: &PdfOptions) -> Result<()> {
+    println!("convert_plain_text: starting. markdown_len={}", markdown.len());
 
-    println!(
-        "convert_plain_text: starting. output={} markdown_len={}",
-        output_path.display(),
-        markdown.len()
-    );
+    if options.paragraph_spacing.is_some_and(|mm| mm < 0.0) {
+        anyhow::bail!("--paragraph-spacing must not be negative");
+    }
+
+    // Clean the markdown first - remove ALL tags for plain mode
+    let (cleaned, sections) = clean_plain_sections(markdown);
+    if options.vertical {
+        return convert_vertical_text(&cleaned, target, options);
+    }
 
-    let (doc, page1, layer1) = PdfDocument::new("OCR Document", Mm(210.0), Mm(297.0), "Layer 1");
+    if options.index_page {
+        // First pass only finds the page each source image starts on, on a scratch
+        // document; the second renders the text again behind an index listing those pages
+        let starts = render_plain_text(&cleaned, &sections, &PdfTarget::new(""), options, None)?;
+        let lines: Vec<&str> = cleaned.lines().collect();
+        let entries: Vec<IndexEntry> = starts
+            .into_iter()
+            .filter_map(|(line, page)| {
+                let end = sections.iter().map(|section| section.line).find(|&start| start > line).unwrap_or(lines.len());
+                index_label(&lines[line..end]).map(|label| IndexEntry { label, page })
+            })
+            .collect();
+        println!("📑 Index page lists {} source page(s)", entries.len());
+        render_plain_text(&cleaned, &sections, target, options, Some(&entries))?;
+    } else {
+        render_plain_text(&cleaned, &sections, target, options, None)?;
+    }
+    Ok(())
+}
 
-    let font = doc.add_builtin_font(BuiltinFont::Helvetica)?;
-    let font_bold = doc.add_builtin_font(BuiltinFont::HelveticaBold)?;
-    let mut current_layer = doc.get_page(page1).get_layer(layer1);
+/// Draw cleaned plain text onto new pages of `target`, behind the `--index-page`
+/// index when entries are given. Returns the first line of each section with the
+/// body page (counted from 1, index excluded) its text starts on.
+fn render_plain_text(
+    cleaned: &str,
+    sections: &[PlainSection],
+    target: &PdfTarget,
+    options: &PdfOptions,
+    index: Option<&[IndexEntry]>,
+) -> Result<Vec<(usize, usize)>> {
+    use printpdf::*;
 
-    let mut y_position = 280.0;
-    let margin_left = 5.0;
-    let margin_right = 5.0;
-    let page_width = 210.0;
-    let usable_width = page_width - margin_left - margin_right;
+    let (mut page_width, mut page_height) = if options.two_up {
+        // Two portrait columns side by side on a landscape sheet
+        (PAGE_HEIGHT_MM, PAGE_WIDTH_MM)
+    } else {
+        sections
+            .first()
+            .filter(|section| section.line == 0)
+            .and_then(|section| section.page_size)
+            .unwrap_or((PAGE_WIDTH_MM, PAGE_HEIGHT_MM))
+    };
+    // The index is set on portrait pages ahead of the body
+    let first_size = if index.is_some() { (PAGE_WIDTH_MM, PAGE_HEIGHT_MM) } else { (page_width, page_height) };
+    let mut current_layer = target.open_page(first_size, options)?;
+    let PdfFonts { regular: font, bold: font_bold, italic: font_italic, mono: mono_font } = target.fonts().clone();
+    if let Some(entries) = index {
+        draw_index_pages(target, current_layer, options, &font, &font_bold, entries);
+        current_layer = target.add_page((page_width, page_height));
+    }
+    decorate_page(&current_layer, options, &font_bold, (page_width, page_height));
+    // Source image of the text being drawn, for --annotate-sources
+    let mut source = sections.first().filter(|section| section.line == 0).and_then(|section| section.source.as_deref());
+    if let Some(source) = source.filter(|_| options.annotate_sources) {
+        draw_source_label(&current_layer, &font_bold, source, options.plain_margins()[3], page_height);
+    }
 
-    // Clean the markdown first - remove ALL tags for plain mode
-    let cleaned = clean_markdown_for_plain(markdown);
+    let [margin_top, margin_right, margin_bottom, mut margin_left] = options.plain_margins();
+    let mut page_top = page_height - margin_top;
+    let mut y_position = page_top;
+    let column_width = if options.two_up { page_width / 2.0 } else { page_width };
+    let mut usable_width = column_width - margin_left - margin_right;
+    let mut cursor = PlainCursor { pages: 1, right_half: false };
+    // Sections whose first text is still to be drawn, and where each one started
+    let mut pending_section = None;
+    let mut section_starts = Vec::new();
 
-    let re_num = Regex::new(r"^\s*\d+[\.)]\s").unwrap();
     let lines: Vec<&str> = cleaned.lines().collect();
+
+    // --no-wrap and space-aligned tables are drawn in Courier (0.6 em per character)
+    let no_wrap_font = options.no_wrap.map(|_| mono_font.clone());
+    let no_wrap_size = match options.no_wrap {
+        Some(NoWrap::Shrink) => {
+            let longest = lines
+                .iter()
+                .filter(|l| !is_no_wrap_exempt(l.trim()))
+                .map(|l| no_wrap_text(l).chars().count())
+                .max()
+                .unwrap_or(0);
+            let fit = usable_width / (longest.max(1) as f32 * 0.6 * 0.352778);
+            fit.clamp(MIN_NO_WRAP_FONT_SIZE, 10.0)
+        }
+        _ => 10.0,
+    };
     let mut i = 0;
     while i < lines.len() {
+        if sections.iter().any(|section| section.line == i) {
+            pending_section = Some(i);
+        }
+        if let Some(section) = sections.iter().find(|section| section.line == i && i > 0) {
+            let size = section.page_size.filter(|_| !options.two_up).unwrap_or((page_width, page_height));
+            let new_source = options.annotate_sources && section.source.as_deref() != source;
+            source = section.source.as_deref();
+            if size != (page_width, page_height) {
+                // A source image in the other orientation starts a page of its own
+                (page_width, page_height) = size;
+                page_top = page_height - margin_top;
+                usable_width = page_width - margin_left - margin_right;
+                current_layer = target.add_page((page_width, page_height));
+                decorate_page(&current_layer, options, &font_bold, (page_width, page_height));
+                if let Some(source) = source.filter(|_| options.annotate_sources) {
+                    draw_source_label(&current_layer, &font_bold, source, margin_left, page_height);
+                }
+                cursor.pages += 1;
+                y_position = page_top;
+            } else if new_source {
+                // So is any new source image when pages are labelled with their source
+                margin_left = next_plain_page(target, &mut current_layer, options, &font_bold, (page_width, page_height), &mut cursor, source);
+                y_position = page_top;
+            }
+        }
+
         let line = lines[i];
         let trimmed = line.trim();
         if trimmed.is_empty() {
@@ -1901,10 +7416,39 @@ fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
         }
 
         // Check if we need a new page
-        if y_position < 20.0 {
-            let (page, layer) = doc.add_page(Mm(210.0), Mm(297.0), "Layer 1");
-            current_layer = doc.get_page(page).get_layer(layer);
-            y_position = 280.0;
+        if y_position < margin_bottom {
+            margin_left = next_plain_page(target, &mut current_layer, options, &font_bold, (page_width, page_height), &mut cursor, source);
+            y_position = page_top;
+        }
+        if let Some(line) = pending_section.take() {
+            section_starts.push((line, cursor.pages));
+        }
+
+        // --no-wrap keeps the line exactly as written, indentation included
+        if let Some(no_wrap_font) = no_wrap_font.as_ref().filter(|_| !is_no_wrap_exempt(trimmed)) {
+            current_layer.use_text(no_wrap_text(line), no_wrap_size, Mm(margin_left), Mm(y_position), no_wrap_font);
+            y_position -= no_wrap_size * 0.4;
+            i += 1;
+            continue;
+        }
+
+        // Plain-text tables aligned with spaces keep their columns instead of being
+        // wrapped as prose (or taken for a list when cells start with dashes)
+        let aligned_rows = aligned_column_rows(&lines[i..]);
+        if aligned_rows > 0 {
+            let rows: Vec<String> = lines[i..i + aligned_rows].iter().map(|l| expand_tabs(l.trim_end())).collect();
+            let longest = rows.iter().map(|row| row.chars().count()).max().unwrap_or(0);
+            let size = (usable_width / (longest.max(1) as f32 * 0.6 * 0.352778)).clamp(MIN_NO_WRAP_FONT_SIZE, 10.0);
+            for row in rows {
+                if y_position < margin_bottom {
+                    margin_left = next_plain_page(target, &mut current_layer, options, &font_bold, (page_width, page_height), &mut cursor, source);
+                    y_position = page_top;
+                }
+                current_layer.use_text(row, size, Mm(margin_left), Mm(y_position), &mono_font);
+                y_position -= size * 0.45;
+            }
+            i += aligned_rows;
+            continue;
         }
 
         // Handle list items: split multiple items in the same line into separate list elements
@@ -1919,17 +7463,40 @@ fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
             let line_step = 5.0;
 
             for item in list_items {
-                if y_position < 20.0 {
-                    let (page, layer) = doc.add_page(Mm(210.0), Mm(297.0), "Layer 1");
-                    current_layer = doc.get_page(page).get_layer(layer);
-                    y_position = 280.0;
+                if y_position < margin_bottom {
+                    margin_left = next_plain_page(target, &mut current_layer, options, &font_bold, (page_width, page_height), &mut cursor, source);
+                    y_position = page_top;
                 }
                 // Determine marker stripped text
                 let stripped = item.trim();
-                let rendered_text = strip_leading_marker(stripped);
+                let rendered_text = strip_task_box(&strip_leading_marker(stripped));
+                let ordered_marker = ordered_list_marker(stripped).map(|(marker, _)| marker);
+                // Ordered markers like "iii." need more room than a bullet
+                let marker_width = ordered_marker.map_or(1.5, |m| m.len() as f32) * avg_char_width_mm;
+                let bullet_offset = match ordered_marker {
+                    Some(_) => bullet_offset.max(marker_width + avg_char_width_mm),
+                    None => bullet_offset,
+                };
+
+                // In RTL mode the marker sits at the right margin and text ends just before it
+                let right_edge = margin_left + usable_width;
+                let marker_x = if options.rtl { right_edge - marker_width } else { margin_left };
+                let draw_item_line = |layer: &PdfLayerReference, line: &str, y: f32| {
+                    if options.rtl {
+                        let width = line.chars().count() as f32 * avg_char_width_mm;
+                        let x = (right_edge - bullet_offset - width).max(margin_left);
+                        layer.use_text(bidi_visual_line(line), font_size, Mm(x), Mm(y), &font);
+                    } else {
+                        layer.use_text(line, font_size, Mm(margin_left + bullet_offset), Mm(y), &font);
+                    }
+                };
 
-                // Draw bold bullet
-                current_layer.use_text("•", font_size, Mm(margin_left), Mm(y_position), &font_bold);
+                // Task items get a drawn checkbox, ordered items keep their marker, everything else a bold bullet
+                match (task_state(stripped), ordered_marker) {
+                    (Some(state), _) => draw_checkbox(&current_layer, marker_x, y_position, font_size, state),
+                    (None, Some(marker)) => current_layer.use_text(marker, font_size, Mm(marker_x), Mm(y_position), &font),
+                    (None, None) => current_layer.use_text("•", font_size, Mm(marker_x), Mm(y_position), &font_bold),
+                }
 
                 // Wrap the rest of the text within available width
                 let max_line_width = usable_width - bullet_offset - 1.0;
@@ -1941,7 +7508,7 @@ fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
                     let extra_space = if current_line.is_empty() { 0.0 } else { space_width };
                     if current_line_width + extra_space + word_width > max_line_width && !current_line.is_empty() {
                         // flush
-                        current_layer.use_text(&current_line, font_size, Mm(margin_left + bullet_offset), Mm(y_position), &font);
+                        draw_item_line(&current_layer, &current_line, y_position);
                         y_position -= line_step;
                         current_line.clear();
                         current_line_width = 0.0;
@@ -1954,7 +7521,7 @@ fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
                     current_line_width += word_width;
                 }
                 if !current_line.is_empty() {
-                    current_layer.use_text(&current_line, font_size, Mm(margin_left + bullet_offset), Mm(y_position), &font);
+                    draw_item_line(&current_layer, &current_line, y_position);
                     y_position -= line_step;
                 }
                 y_position -= 2.0; // small gap after item
@@ -1964,13 +7531,13 @@ fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
         }
 
         // Table handling: Check for <table> BEFORE stripping HTML tags
-        if trimmed.to_lowercase().contains("<table>") {
+        if has_table_tag(trimmed) {
             let mut table_block = String::new();
             table_block.push_str(trimmed);
             i += 1;
             while i < lines.len() {
                 let l = lines[i];
-                table_block.push_str("\n");
+                table_block.push('\n');
                 table_block.push_str(l);
                 if l.trim().to_lowercase().contains("</table>") {
                     break;
@@ -1981,23 +7548,59 @@ fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
             
             if !rows.is_empty() {
                 // Check if we need a new page
-                if y_position < 50.0 {
-                    let (page, layer) = doc.add_page(Mm(210.0), Mm(297.0), "Layer 1");
-                    current_layer = doc.get_page(page).get_layer(layer);
-                    y_position = 280.0;
+                if y_position < margin_bottom + 30.0 {
+                    margin_left = next_plain_page(target, &mut current_layer, options, &font_bold, (page_width, page_height), &mut cursor, source);
+                    y_position = page_top;
                 }
                 
-                // Render HTML table with borders
-                let table_font_size = 9.0;
-                y_position = render_html_table(&current_layer, &rows, margin_left, y_position, usable_width, &font, table_font_size);
-                y_position -= 5.0; // spacing after table
+                if options.flatten_tables && rows.len() > 1 {
+                    let font_size = 10.0;
+                    let em_mm = font_size * 0.352778;
+                    for row in flatten_table(&rows) {
+                        for (label, value) in row {
+                            if y_position < margin_bottom {
+                                margin_left = next_plain_page(target, &mut current_layer, options, &font_bold, (page_width, page_height), &mut cursor, source);
+                                y_position = page_top;
+                            }
+                            // Bold label, value wrapped in the space to its right
+                            let label = format!("{}: ", label);
+                            let label_width = text_width_em(&label) * em_mm;
+                            current_layer.use_text(&label, font_size, Mm(margin_left), Mm(y_position), &font_bold);
+                            let value_width_em = ((usable_width - label_width) / em_mm).max(10.0);
+                            for line in wrap_cell_text(&value, value_width_em) {
+                                current_layer.use_text(line, font_size, Mm(margin_left + label_width), Mm(y_position), &font);
+                                y_position -= 5.0;
+                            }
+                        }
+                        y_position -= 3.0; // gap between rows
+                    }
+                    y_position -= 2.0; // spacing after table
+                } else {
+                    // Render HTML table with borders
+                    let table_font_size = 9.0;
+                    y_position = render_html_table(&current_layer, &rows, margin_left, y_position, usable_width, &font, table_font_size, options);
+                    y_position -= 5.0; // spacing after table
+                }
+            }
+            i += 1;
+            continue;
+        }
+
+        // A standalone image can't be embedded, so keep its alt text as an italic caption
+        if let Some(caption) = image_caption(trimmed) {
+            if !caption.is_empty() {
+                let caption_size = 9.0;
+                let width = caption.chars().count() as f32 * caption_size * 0.5 * 0.352778;
+                let x = margin_left + ((usable_width - width) / 2.0).max(0.0);
+                current_layer.use_text(&caption, caption_size, Mm(x), Mm(y_position), &font_italic);
+                y_position -= 6.0;
             }
             i += 1;
             continue;
         }
 
-        // Parse HTML tags
-        let (text_without_html, is_centered) = parse_html_tags(trimmed);
+        // Parse HTML tags; <font>/<span> colors are resolved per word below
+        let (text_without_html, is_centered) = parse_block_html(trimmed);
 
         // Determine font size and style based on markdown formatting
         let (text, font_size, line_spacing, use_bold) = if text_without_html.starts_with("# ") {
@@ -2027,39 +7630,129 @@ fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
         let max_line_width = (usable_width - 1.0_f32).max(avg_char_width_mm);
         let space_width = avg_char_width_mm;
         let line_step = line_spacing * 0.8_f32;
-        let mut current_line = String::new();
+        let mut current_line: Vec<Vec<(String, RunStyle)>> = Vec::new();
         let mut current_line_width = 0.0;
 
-        let mut flush_line = |line: &str, line_width_mm: f32| -> Result<()> {
+        let mut flush_line = |line: &[Vec<(String, RunStyle)>], line_width_mm: f32| -> Result<()> {
             if line.is_empty() {
                 return Ok(());
             }
-
-            let approx_line_width = line_width_mm.max(avg_char_width_mm);
+            let plain = line
+                .iter()
+                .map(|word| word.iter().map(|(piece, _)| piece.as_str()).collect::<String>())
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            let approx_line_width = if options.rtl {
+                plain.chars().count() as f32 * avg_char_width_mm
+            } else {
+                line_width_mm
+            }
+            .max(avg_char_width_mm);
             let x_pos = if is_centered {
                 margin_left + ((usable_width - approx_line_width) / 2.0).max(0.0)
+            } else if options.rtl {
+                margin_left + (usable_width - approx_line_width).max(0.0)
             } else {
                 margin_left
             };
 
             let selected_font = if use_bold { &font_bold } else { &font };
-            current_layer.use_text(line, font_size, Mm(x_pos), Mm(y_position), selected_font);
+            let styled = line.iter().flatten().any(|(_, style)| *style != RunStyle::default());
+            if options.rtl {
+                // Bidi reordering moves runs around, so RTL lines are drawn unstyled
+                current_layer.use_text(bidi_visual_line(&plain), font_size, Mm(x_pos), Mm(y_position), selected_font);
+            } else if !styled {
+                current_layer.use_text(plain, font_size, Mm(x_pos), Mm(y_position), selected_font);
+            } else {
+                // Merge neighbouring pieces of the same style into segments with a start x
+                let mut segments: Vec<(String, RunStyle, f32)> = Vec::new();
+                let mut x = x_pos;
+                for (index, word) in line.iter().enumerate() {
+                    let mut gap = if index > 0 { space_width } else { 0.0 };
+                    for (piece, style) in word {
+                        match segments.last_mut() {
+                            Some((text, last_style, _)) if last_style == style => {
+                                if gap > 0.0 {
+                                    text.push(' ');
+                                }
+                                text.push_str(piece);
+                            }
+                            _ => segments.push((piece.clone(), *style, x + gap)),
+                        }
+                        x += gap + piece.len() as f32 * style.width_scale() * avg_char_width_mm;
+                        gap = 0.0;
+                    }
+                }
+                for (text, style, x) in segments {
+                    let [r, g, b] = style.color.unwrap_or([0, 0, 0]);
+                    let color = Color::Rgb(Rgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, None));
+                    let end_x = x + text.len() as f32 * style.width_scale() * avg_char_width_mm;
+                    // Superscripts (footnote markers) are smaller and raised
+                    let (size, baseline) = if style.superscript {
+                        (font_size * SUPERSCRIPT_SCALE, y_position + font_size * pt_to_mm * 0.35)
+                    } else {
+                        (font_size, y_position)
+                    };
+                    current_layer.save_graphics_state();
+                    current_layer.set_fill_color(color.clone());
+                    current_layer.use_text(text, size, Mm(x), Mm(baseline), selected_font);
+                    // Decorations are drawn in the text color, just below the baseline / at mid x-height
+                    current_layer.set_outline_color(color);
+                    current_layer.set_outline_thickness(font_size * 0.05);
+                    if style.underline {
+                        draw_horizontal_line(&current_layer, x, end_x, y_position - font_size * pt_to_mm * 0.15);
+                    }
+                    if style.strikethrough {
+                        draw_horizontal_line(&current_layer, x, end_x, y_position + font_size * pt_to_mm * 0.3);
+                    }
+                    current_layer.restore_graphics_state();
+                }
+            }
             y_position -= line_step;
 
-            if y_position < 20.0 {
-                let (page, layer) = doc.add_page(Mm(210.0), Mm(297.0), "Layer 1");
-                current_layer = doc.get_page(page).get_layer(layer);
-                y_position = 280.0;
+            if y_position < margin_bottom {
+                margin_left = next_plain_page(target, &mut current_layer, options, &font_bold, (page_width, page_height), &mut cursor, source);
+                y_position = page_top;
             }
 
             Ok(())
         };
 
-        // Word wrapping using width-based accumulation
-        let words: Vec<&str> = text.split_whitespace().collect();
+        // Split the styled runs into words; a word may span several runs
+        let mut words: Vec<Vec<(String, RunStyle)>> = Vec::new();
+        let mut in_word = false;
+        // The RTL path draws each line as one plain string, which would turn claim[3]
+        // into claim3, so footnote markers stay as written there
+        let marked = if options.rtl { text.to_string() } else { mark_footnotes(text) };
+        for run in parse_styled_runs(&marked) {
+            let mut rest = run.text.as_str();
+            while !rest.is_empty() {
+                let start = rest.len() - rest.trim_start().len();
+                if start > 0 {
+                    in_word = false;
+                    rest = &rest[start..];
+                    continue;
+                }
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                if !in_word {
+                    words.push(Vec::new());
+                }
+                if let Some(word) = words.last_mut() {
+                    word.push((rest[..end].to_string(), run.style));
+                }
+                in_word = true;
+                rest = &rest[end..];
+            }
+        }
 
+        // Word wrapping using width-based accumulation
         for word in words {
-            let word_width = word.len() as f32 * avg_char_width_mm;
+            let word_width = word
+                .iter()
+                .map(|(piece, style)| piece.len() as f32 * style.width_scale())
+                .sum::<f32>()
+                * avg_char_width_mm;
             let extra_space = if current_line.is_empty() {
                 0.0
             } else {
@@ -2075,11 +7768,10 @@ fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
             }
 
             if !current_line.is_empty() {
-                current_line.push(' ');
                 current_line_width += space_width;
             }
 
-            current_line.push_str(word);
+            current_line.push(word);
             current_line_width += word_width;
         }
 
@@ -2087,17 +7779,459 @@ fn convert_plain_text(markdown: &str, output_path: &Path) -> Result<()> {
             flush_line(&current_line, current_line_width)?;
         }
 
-        y_position -= line_spacing;
+        // With --preserve-breaks a body line followed directly by more text ends a line, not a paragraph
+        let line_break = options.preserve_breaks && !use_bold && lines.get(i + 1).is_some_and(|next| continues_block(next));
+        if !line_break {
+            y_position -= options.paragraph_spacing.unwrap_or(line_spacing);
+        }
         i += 1;
     }
 
-    println!(
-        "convert_plain_text: saving PDF to {}",
-        output_path.display()
+    Ok(section_starts)
+}
+
+/// Structural element of cleaned markdown, shared by the non-PDF exporters.
+enum DocBlock {
+    Heading(u8, String),
+    /// Alt text of an image that can't be embedded
+    Caption(String),
+    ListItem(String),
+    Table(Vec<Vec<String>>),
+    Paragraph { text: String, centered: bool },
+}
+
+/// Split cleaned (tag-free) markdown into headings, list items, tables and paragraphs.
+fn parse_document_blocks(cleaned: &str) -> Vec<DocBlock> {
+    let lines: Vec<&str> = cleaned.lines().collect();
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let trimmed = lines[i].trim();
+        if trimmed.is_empty() {
+            i += 1;
+            continue;
+        }
+
+        if is_list_item(trimmed) {
+            for item in split_list_items(trimmed) {
+                let text = strip_task_box(&strip_leading_marker(&item));
+                let text = match task_state(&item) {
+                    Some(TaskState::Checked) => format!("☑ {}", text),
+                    Some(TaskState::Unchecked) => format!("☐ {}", text),
+                    None => text,
+                };
+                blocks.push(DocBlock::ListItem(text));
+            }
+            i += 1;
+            continue;
+        }
+
+        if has_table_tag(trimmed) {
+            let mut table_block = String::from(trimmed);
+            let closed_on_first_line = trimmed.to_lowercase().contains("</table>");
+            i += 1;
+            while i < lines.len() && !closed_on_first_line {
+                table_block.push('\n');
+                table_block.push_str(lines[i]);
+                i += 1;
+                if lines[i - 1].trim().to_lowercase().contains("</table>") {
+                    break;
+                }
+            }
+            let rows = parse_table_html(&table_block);
+            if !rows.is_empty() {
+                blocks.push(DocBlock::Table(rows));
+            }
+            continue;
+        }
+
+        if let Some(caption) = image_caption(trimmed) {
+            if !caption.is_empty() {
+                blocks.push(DocBlock::Caption(caption));
+            }
+            i += 1;
+            continue;
+        }
+
+        let (text, centered) = parse_html_tags(trimmed);
+        let (text, level) = parse_markdown_headers(&text);
+        let text = text.trim().to_string();
+        if level > 0 {
+            blocks.push(DocBlock::Heading(level, text));
+        } else {
+            blocks.push(DocBlock::Paragraph { text, centered });
+        }
+        i += 1;
+    }
+    blocks
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A WordprocessingML paragraph. `style` is one of the ids defined in `DOCX_STYLES`.
+fn docx_paragraph(text: &str, style: Option<&str>, centered: bool, bullet: bool) -> String {
+    let mut props = String::new();
+    if let Some(style) = style {
+        props.push_str(&format!(r#"<w:pStyle w:val="{}"/>"#, style));
+    }
+    if bullet {
+        props.push_str(r#"<w:numPr><w:ilvl w:val="0"/><w:numId w:val="1"/></w:numPr>"#);
+    }
+    if centered {
+        props.push_str(r#"<w:jc w:val="center"/>"#);
+    }
+    let props = if props.is_empty() { String::new() } else { format!("<w:pPr>{}</w:pPr>", props) };
+    format!(
+        r#"<w:p>{}<w:r><w:t xml:space="preserve">{}</w:t></w:r></w:p>"#,
+        props,
+        xml_escape(text)
+    )
+}
+
+fn docx_table(rows: &[Vec<String>]) -> String {
+    let mut xml = String::from(
+        r#"<w:tbl><w:tblPr><w:tblStyle w:val="TableGrid"/><w:tblW w:w="5000" w:type="pct"/></w:tblPr>"#,
+    );
+    for row in rows {
+        xml.push_str("<w:tr>");
+        for cell in row {
+            xml.push_str("<w:tc>");
+            // Reuse the PDF cell wrapping to turn <li> items into bullet lines, without a width limit
+            let lines = wrap_cell_text(cell, f32::INFINITY);
+            if lines.is_empty() {
+                xml.push_str("<w:p/>");
+            }
+            for line in lines {
+                xml.push_str(&docx_paragraph(&line, None, false, false));
+            }
+            xml.push_str("</w:tc>");
+        }
+        xml.push_str("</w:tr>");
+    }
+    xml.push_str("</w:tbl>");
+    xml
+}
+
+const DOCX_CONTENT_TYPES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/word/document.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml"/><Override PartName="/word/styles.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.styles+xml"/><Override PartName="/word/numbering.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.numbering+xml"/><Override PartName="/docProps/core.xml" ContentType="application/vnd.openxmlformats-package.core-properties+xml"/></Types>"#;
+
+const DOCX_PACKAGE_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="word/document.xml"/><Relationship Id="rId2" Type="http://schemas.openxmlformats.org/package/2006/relationships/metadata/core-properties" Target="docProps/core.xml"/></Relationships>"#;
+
+const DOCX_DOCUMENT_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/styles" Target="styles.xml"/><Relationship Id="rId2" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/numbering" Target="numbering.xml"/></Relationships>"#;
+
+const DOCX_STYLES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:styles xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:style w:type="paragraph" w:default="1" w:styleId="Normal"><w:name w:val="Normal"/><w:pPr><w:spacing w:after="120"/></w:pPr><w:rPr><w:sz w:val="22"/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Heading1"><w:name w:val="heading 1"/><w:basedOn w:val="Normal"/><w:next w:val="Normal"/><w:pPr><w:keepNext/><w:spacing w:before="240"/><w:outlineLvl w:val="0"/></w:pPr><w:rPr><w:b/><w:sz w:val="36"/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Heading2"><w:name w:val="heading 2"/><w:basedOn w:val="Normal"/><w:next w:val="Normal"/><w:pPr><w:keepNext/><w:spacing w:before="200"/><w:outlineLvl w:val="1"/></w:pPr><w:rPr><w:b/><w:sz w:val="32"/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Heading3"><w:name w:val="heading 3"/><w:basedOn w:val="Normal"/><w:next w:val="Normal"/><w:pPr><w:keepNext/><w:spacing w:before="160"/><w:outlineLvl w:val="2"/></w:pPr><w:rPr><w:b/><w:sz w:val="28"/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Heading4"><w:name w:val="heading 4"/><w:basedOn w:val="Normal"/><w:next w:val="Normal"/><w:pPr><w:keepNext/><w:outlineLvl w:val="3"/></w:pPr><w:rPr><w:b/><w:sz w:val="24"/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Caption"><w:name w:val="caption"/><w:basedOn w:val="Normal"/><w:rPr><w:i/><w:sz w:val="18"/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="ListParagraph"><w:name w:val="List Paragraph"/><w:basedOn w:val="Normal"/><w:pPr><w:spacing w:after="60"/><w:ind w:left="720"/></w:pPr></w:style><w:style w:type="table" w:styleId="TableGrid"><w:name w:val="Table Grid"/><w:tblPr><w:tblBorders><w:top w:val="single" w:sz="4" w:space="0" w:color="auto"/><w:left w:val="single" w:sz="4" w:space="0" w:color="auto"/><w:bottom w:val="single" w:sz="4" w:space="0" w:color="auto"/><w:right w:val="single" w:sz="4" w:space="0" w:color="auto"/><w:insideH w:val="single" w:sz="4" w:space="0" w:color="auto"/><w:insideV w:val="single" w:sz="4" w:space="0" w:color="auto"/></w:tblBorders></w:tblPr></w:style></w:styles>"#;
+
+const DOCX_NUMBERING: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:numbering xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:abstractNum w:abstractNumId="0"><w:lvl w:ilvl="0"><w:start w:val="1"/><w:numFmt w:val="bullet"/><w:lvlText w:val="•"/><w:lvlJc w:val="left"/><w:pPr><w:ind w:left="720" w:hanging="360"/></w:pPr></w:lvl></w:abstractNum><w:num w:numId="1"><w:abstractNumId w:val="0"/></w:num></w:numbering>"#;
+
+/// Convert OCR markdown into a .docx: headers become Word headings, list items
+/// bulleted paragraphs and `<table>` blocks real Word tables.
+fn convert_markdown_to_docx(markdown: &str, output_path: &Path) -> Result<()> {
+    let (front_matter, body) = split_front_matter(markdown);
+    let title = front_matter
+        .as_ref()
+        .and_then(|fm| fm.get("title"))
+        .filter(|t| !t.is_empty())
+        .unwrap_or("OCR Document");
+
+    let mut content = String::new();
+    for block in parse_document_blocks(&clean_markdown_for_plain(body)) {
+        match block {
+            DocBlock::Heading(level, text) => {
+                let style = match level {
+                    1 => "Heading1",
+                    2 => "Heading2",
+                    3 => "Heading3",
+                    _ => "Heading4",
+                };
+                content.push_str(&docx_paragraph(&text, Some(style), false, false));
+            }
+            DocBlock::ListItem(text) => content.push_str(&docx_paragraph(&text, Some("ListParagraph"), false, true)),
+            DocBlock::Caption(text) => content.push_str(&docx_paragraph(&text, Some("Caption"), true, false)),
+            DocBlock::Table(rows) => {
+                content.push_str(&docx_table(&rows));
+                // Word needs a paragraph between consecutive tables
+                content.push_str("<w:p/>");
+            }
+            DocBlock::Paragraph { text, centered } => content.push_str(&docx_paragraph(&text, None, centered, false)),
+        }
+    }
+
+    let document = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:body>{}<w:sectPr><w:pgSz w:w="11906" w:h="16838"/><w:pgMar w:top="1134" w:right="1134" w:bottom="1134" w:left="1134" w:header="709" w:footer="709" w:gutter="0"/></w:sectPr></w:body></w:document>"#,
+        content
     );
-    doc.save(&mut std::io::BufWriter::new(std::fs::File::create(
+    let core = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<cp:coreProperties xmlns:cp="http://schemas.openxmlformats.org/package/2006/metadata/core-properties" xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>{}</dc:title></cp:coreProperties>"#,
+        xml_escape(title)
+    );
+
+    write_zip_archive(
         output_path,
-    )?))?;
+        &[
+            ("[Content_Types].xml", DOCX_CONTENT_TYPES),
+            ("_rels/.rels", DOCX_PACKAGE_RELS),
+            ("docProps/core.xml", &core),
+            ("word/_rels/document.xml.rels", DOCX_DOCUMENT_RELS),
+            ("word/document.xml", &document),
+            ("word/styles.xml", DOCX_STYLES),
+            ("word/numbering.xml", DOCX_NUMBERING),
+        ],
+    )
+}
+
+/// 64-bit FNV-1a hash; unlike `DefaultHasher` its output never changes between Rust releases.
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3))
+}
 
+/// Write a zip-based document package. A `mimetype` entry is stored
+/// uncompressed, as EPUB readers require.
+fn write_zip_archive(output_path: &Path, parts: &[(&str, &str)]) -> Result<()> {
+    use std::io::Write;
+
+    let file = fs::File::create(output_path)
+        .with_context(|| format!("Failed to create {}", output_path.display()))?;
+    let mut zip = zip::ZipWriter::new(file);
+    let deflated = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    let stored = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, data) in parts {
+        zip.start_file(*name, if *name == "mimetype" { stored } else { deflated })?;
+        zip.write_all(data.as_bytes())?;
+    }
+    zip.finish()
+        .with_context(|| format!("Failed to write {}", output_path.display()))?;
     Ok(())
 }
+
+/// A reflowable chapter of an EPUB export.
+struct EpubChapter {
+    title: String,
+    blocks: Vec<DocBlock>,
+}
+
+/// Split the document into chapters at h1/h2 headings, or at page breaks when
+/// the OCR output has no such headings.
+fn split_epub_chapters(body: &str) -> Vec<EpubChapter> {
+    let re_page_break = Regex::new(r"(?m)^---PAGE_BREAK---\s*$").unwrap();
+    let pages: Vec<Vec<DocBlock>> = re_page_break
+        .split(body)
+        .map(|page| parse_document_blocks(&clean_markdown_for_plain(page)))
+        .filter(|blocks| !blocks.is_empty())
+        .collect();
+
+    let has_headings = pages
+        .iter()
+        .flatten()
+        .any(|block| matches!(block, DocBlock::Heading(level, _) if *level <= 2));
+
+    if !has_headings {
+        return pages
+            .into_iter()
+            .enumerate()
+            .map(|(i, blocks)| EpubChapter { title: format!("Page {}", i + 1), blocks })
+            .collect();
+    }
+
+    let mut chapters: Vec<EpubChapter> = Vec::new();
+    let mut current: Vec<DocBlock> = Vec::new();
+    let mut current_title: Option<String> = None;
+    for block in pages.into_iter().flatten() {
+        if let DocBlock::Heading(level, text) = &block {
+            if *level <= 2 {
+                if !current.is_empty() {
+                    let title = current_title.take().unwrap_or_else(|| format!("Chapter {}", chapters.len() + 1));
+                    chapters.push(EpubChapter { title, blocks: std::mem::take(&mut current) });
+                }
+                current_title = Some(text.clone());
+            }
+        }
+        current.push(block);
+    }
+    if !current.is_empty() {
+        let title = current_title.unwrap_or_else(|| format!("Chapter {}", chapters.len() + 1));
+        chapters.push(EpubChapter { title, blocks: current });
+    }
+    chapters
+}
+
+fn epub_chapter_xhtml(chapter: &EpubChapter) -> String {
+    let mut html = String::new();
+    let mut in_list = false;
+    for block in &chapter.blocks {
+        let is_list_item = matches!(block, DocBlock::ListItem(_));
+        if in_list && !is_list_item {
+            html.push_str("</ul>\n");
+            in_list = false;
+        }
+        match block {
+            DocBlock::Heading(level, text) => {
+                let level = (*level).min(6);
+                html.push_str(&format!("<h{0}>{1}</h{0}>\n", level, xml_escape(text)));
+            }
+            DocBlock::ListItem(text) => {
+                if !in_list {
+                    html.push_str("<ul>\n");
+                    in_list = true;
+                }
+                html.push_str(&format!("<li>{}</li>\n", xml_escape(text)));
+            }
+            DocBlock::Table(rows) => {
+                html.push_str("<table>\n");
+                for row in rows {
+                    html.push_str("<tr>");
+                    for cell in row {
+                        let lines: Vec<String> = wrap_cell_text(cell, f32::INFINITY).iter().map(|l| xml_escape(l)).collect();
+                        html.push_str(&format!("<td>{}</td>", lines.join("<br/>")));
+                    }
+                    html.push_str("</tr>\n");
+                }
+                html.push_str("</table>\n");
+            }
+            DocBlock::Caption(text) => {
+                html.push_str(&format!("<p class=\"caption\">{}</p>\n", xml_escape(text)));
+            }
+            DocBlock::Paragraph { text, centered } => {
+                let class = if *centered { r#" class="center""# } else { "" };
+                html.push_str(&format!("<p{}>{}</p>\n", class, xml_escape(text)));
+            }
+        }
+    }
+    if in_list {
+        html.push_str("</ul>\n");
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>{}</title><link rel="stylesheet" type="text/css" href="style.css"/></head>
+<body>
+{}</body>
+</html>
+"#,
+        xml_escape(&chapter.title),
+        html
+    )
+}
+
+const EPUB_CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container"><rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>"#;
+
+const EPUB_STYLE: &str = "body { font-family: serif; line-height: 1.4; }
+.center { text-align: center; }
+.caption { text-align: center; font-style: italic; font-size: 0.9em; }
+table { border-collapse: collapse; margin: 1em 0; }
+td { border: 1px solid #888; padding: 0.2em 0.4em; vertical-align: top; }
+";
+
+/// Convert OCR markdown into a reflowable EPUB 2 book.
+fn convert_markdown_to_epub(markdown: &str, output_path: &Path) -> Result<usize> {
+    let (front_matter, body) = split_front_matter(markdown);
+    let field = |key: &str| front_matter.as_ref().and_then(|fm| fm.get(key)).filter(|v| !v.is_empty());
+    let title = field("title").unwrap_or("OCR Document");
+    let language = field("language").unwrap_or("en");
+
+    let chapters = split_epub_chapters(body);
+    if chapters.is_empty() {
+        anyhow::bail!("No content to export");
+    }
+
+    // Stable identifier derived from the content, so re-exports keep the same id
+    let identifier = format!("urn:iloveprivacypdf:{:016x}", fnv1a_64(markdown.as_bytes()));
+
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    let mut nav_points = String::new();
+    let mut chapter_files = Vec::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let id = format!("chapter{}", i + 1);
+        manifest.push_str(&format!(r#"<item id="{0}" href="{0}.xhtml" media-type="application/xhtml+xml"/>"#, id));
+        spine.push_str(&format!(r#"<itemref idref="{}"/>"#, id));
+        nav_points.push_str(&format!(
+            r#"<navPoint id="nav{0}" playOrder="{0}"><navLabel><text>{1}</text></navLabel><content src="{2}.xhtml"/></navPoint>"#,
+            i + 1,
+            xml_escape(&chapter.title),
+            id
+        ));
+        chapter_files.push((format!("OEBPS/{}.xhtml", id), epub_chapter_xhtml(chapter)));
+    }
+
+    let opf = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="BookId" version="2.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>{}</dc:title><dc:language>{}</dc:language><dc:identifier id="BookId">{}</dc:identifier></metadata><manifest><item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/><item id="style" href="style.css" media-type="text/css"/>{}</manifest><spine toc="ncx">{}</spine></package>"#,
+        xml_escape(title),
+        xml_escape(language),
+        identifier,
+        manifest,
+        spine
+    );
+    let ncx = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1"><head><meta name="dtb:uid" content="{}"/></head><docTitle><text>{}</text></docTitle><navMap>{}</navMap></ncx>"#,
+        identifier,
+        xml_escape(title),
+        nav_points
+    );
+
+    let mut parts: Vec<(&str, &str)> = vec![
+        ("mimetype", "application/epub+zip"),
+        ("META-INF/container.xml", EPUB_CONTAINER),
+        ("OEBPS/content.opf", &opf),
+        ("OEBPS/toc.ncx", &ncx),
+        ("OEBPS/style.css", EPUB_STYLE),
+    ];
+    parts.extend(chapter_files.iter().map(|(name, data)| (name.as_str(), data.as_str())));
+    write_zip_archive(output_path, &parts)?;
+    Ok(chapters.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_coordinates_double_brackets() {
+        assert_eq!(parse_coordinates("[[10, 20, 110, 220]]"), Some([10.0, 20.0, 110.0, 220.0]));
+    }
+
+    #[test]
+    fn parse_coordinates_single_brackets() {
+        assert_eq!(parse_coordinates(" [10,20,110,220] "), Some([10.0, 20.0, 110.0, 220.0]));
+    }
+
+    #[test]
+    fn parse_coordinates_swapped_corners() {
+        assert_eq!(parse_coordinates("[[110, 220, 10, 20]]"), Some([10.0, 20.0, 110.0, 220.0]));
+    }
+
+    #[test]
+    fn parse_coordinates_merges_boxes_into_their_union() {
+        assert_eq!(parse_coordinates("[[10, 20, 110, 60], [50, 80, 300, 120]]"), Some([10.0, 20.0, 300.0, 120.0]));
+    }
+
+    #[test]
+    fn parse_coordinates_keeps_small_values_as_given() {
+        assert_eq!(parse_coordinates("[[0, 0, 1, 1]]"), Some([0.0, 0.0, 1.0, 1.0]));
+    }
+
+    #[test]
+    fn parse_coordinates_rejects_unparseable_input() {
+        assert_eq!(parse_coordinates(""), None);
+        assert_eq!(parse_coordinates("[]"), None);
+        assert_eq!(parse_coordinates("10, 20, 110, 220"), None);
+        assert_eq!(parse_coordinates("[[10, 20, 110]]"), None);
+        assert_eq!(parse_coordinates("[[10, 20, abc, 220]]"), None);
+        assert_eq!(parse_coordinates("[[10, 20, 110, 220], [1, 2]]"), None);
+    }
+}